@@ -1,6 +1,6 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 
-use marble::open;
+use marble::{open, Config, SyncMode};
 
 pub fn measure_latency(c: &mut Criterion) {
     let marble = open("measure_latency").unwrap();
@@ -22,6 +22,26 @@ pub fn measure_throughput(c: &mut Criterion) {
     });
 }
 
+pub fn measure_sync_modes(c: &mut Criterion) {
+    for (name, sync_mode) in [("sync_all", SyncMode::Full), ("sync_data", SyncMode::Data)] {
+        let marble = Config {
+            path: format!("measure_sync_modes_{name}").into(),
+            fsync_each_batch: true,
+            sync_mode,
+            ..Default::default()
+        }
+        .open()
+        .unwrap();
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                let batch = [(0, Some(vec![0_u8; 4096]))];
+                marble.write_batch(batch.into_iter()).unwrap();
+            })
+        });
+    }
+}
+
 criterion_group!(latency, measure_latency);
 criterion_group!(throughput, measure_throughput);
-criterion_main!(latency, throughput);
+criterion_group!(sync_modes, measure_sync_modes);
+criterion_main!(latency, throughput, sync_modes);
@@ -115,7 +115,7 @@ fn main() {
     }
 
     while threads.iter().any(|t| !t.is_finished()) {
-        let cleaned_up = marble.maintenance().unwrap();
+        let cleaned_up = marble.maintenance().unwrap().objects_rewritten;
         if cleaned_up > 0 {
             let stats = marble.stats();
             println!("defragmented {cleaned_up} objects. stats: {stats:?}",);
@@ -128,11 +128,11 @@ fn main() {
 
     let mut cleaned_up = 1;
     while cleaned_up != 0 {
-        cleaned_up = marble.maintenance().unwrap();
+        cleaned_up = marble.maintenance().unwrap().objects_rewritten;
         let stats = marble.stats();
         println!("defragmented {cleaned_up} objects. stats: {stats:?}",);
     }
-    let cleaned_up_2 = marble.maintenance().unwrap();
+    let cleaned_up_2 = marble.maintenance().unwrap().objects_rewritten;
     assert_eq!(cleaned_up_2, 0);
 
     let total_ops = concurrency * BATCH_SZ * BATCHES_PER_THREAD;
@@ -3,11 +3,9 @@ use std::collections::{BTreeMap, HashMap};
 use std::io;
 
 use bincode::{deserialize, serialize};
-use marble::Marble;
+use marble::{Marble, ObjectId};
 use serde::{Deserialize, Serialize};
 
-type ObjectId = u64;
-
 const INDEX_OBJECT_ID: ObjectId = 1;
 
 #[derive(Serialize, Deserialize, Debug)]
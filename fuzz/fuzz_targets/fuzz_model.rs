@@ -9,7 +9,7 @@ use std::collections::HashMap;
 
 use arbitrary::Arbitrary;
 
-use marble::Config as MarbleConfig;
+use marble::{Config as MarbleConfig, ObjectId};
 
 const TEST_DIR: &str = "testing_data_directories";
 
@@ -19,8 +19,6 @@ const BATCH_MAX_SZ: u8 = 16;
 const VALUE_MAX_SZ: u8 = 16;
 const OPS: usize = 6;
 
-type ObjectId = u64;
-
 #[derive(Debug)]
 struct Config(MarbleConfig);
 
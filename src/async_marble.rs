@@ -0,0 +1,86 @@
+use std::io;
+use std::sync::Arc;
+
+use crate::{MaintenanceReport, Marble, MarbleError, ObjectId, Stats};
+
+/// A wrapper around `Marble` for use from async code, which
+/// runs blocking operations on the blocking thread pool of
+/// the current tokio runtime using `spawn_blocking`, so that
+/// they do not stall the threads that drive futures.
+///
+/// No locks are held while waiting on the returned futures,
+/// so dropping one before it completes does not block other
+/// operations, although the spawned operation still runs to
+/// completion. `AsyncMarble` is cheap to clone, and clones
+/// share the same underlying store.
+#[derive(Debug, Clone)]
+pub struct AsyncMarble {
+    marble: Marble,
+}
+
+impl From<Marble> for AsyncMarble {
+    fn from(marble: Marble) -> AsyncMarble {
+        AsyncMarble { marble }
+    }
+}
+
+impl AsyncMarble {
+    /// The wrapped `Marble`, for calling non-blocking methods
+    /// like `stats`, or for calling blocking methods from a
+    /// thread that is not driving futures.
+    pub fn marble(&self) -> &Marble {
+        &self.marble
+    }
+
+    /// Like `Marble::read`.
+    pub async fn read(&self, object_id: ObjectId) -> Result<Option<Box<[u8]>>, MarbleError> {
+        self.spawn(move |marble| marble.read(object_id)).await
+    }
+
+    /// Like `Marble::read_cached`.
+    pub async fn read_cached(&self, object_id: ObjectId) -> Result<Option<Arc<[u8]>>, MarbleError> {
+        self.spawn(move |marble| marble.read_cached(object_id))
+            .await
+    }
+
+    /// Like `Marble::write_batch`. The batch is moved to the
+    /// blocking thread pool, so it must be `Send` and
+    /// `'static`.
+    pub async fn write_batch<B, I>(&self, write_batch: I) -> Result<(), MarbleError>
+    where
+        B: AsRef<[u8]> + Send + 'static,
+        I: IntoIterator<Item = (ObjectId, Option<B>)> + Send + 'static,
+    {
+        self.spawn(move |marble| marble.write_batch(write_batch))
+            .await
+    }
+
+    /// Like `Marble::maintenance`.
+    pub async fn maintenance(&self) -> Result<MaintenanceReport, MarbleError> {
+        self.spawn(|marble| marble.maintenance()).await
+    }
+
+    /// Like `Marble::sync_all`.
+    pub async fn sync_all(&self) -> Result<(), MarbleError> {
+        self.spawn(|marble| marble.sync_all()).await
+    }
+
+    /// Like `Marble::stats`.
+    pub fn stats(&self) -> Stats {
+        self.marble.stats()
+    }
+
+    async fn spawn<F, T>(&self, f: F) -> Result<T, MarbleError>
+    where
+        F: FnOnce(&Marble) -> Result<T, MarbleError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let marble = self.marble.clone();
+
+        match tokio::task::spawn_blocking(move || f(&marble)).await {
+            Ok(res) => res,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Interrupted, e).into()),
+        }
+    }
+}
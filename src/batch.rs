@@ -0,0 +1,61 @@
+use crate::ObjectId;
+
+/// A write batch that is built up one object at a time,
+/// which may be passed to [`crate::Marble::write_batch`]
+/// instead of a collection of `(ObjectId, Option<B>)` pairs.
+///
+/// Objects are written in the order that they were added if
+/// `Config::sort_batches` is disabled. If an object ID is
+/// added more than once, only the last value for it is
+/// written.
+#[derive(Debug, Clone)]
+pub struct Batch<B = Vec<u8>> {
+    objects: Vec<(ObjectId, Option<B>)>,
+}
+
+impl<B> Default for Batch<B> {
+    fn default() -> Batch<B> {
+        Batch { objects: vec![] }
+    }
+}
+
+impl<B> Batch<B> {
+    /// Create an empty batch.
+    pub fn new() -> Batch<B> {
+        Batch::default()
+    }
+
+    /// Write `data` as the new value of an object.
+    pub fn insert(&mut self, object_id: ObjectId, data: B) -> &mut Batch<B> {
+        self.objects.push((object_id, Some(data)));
+        self
+    }
+
+    /// Remove an object, so that reading it returns
+    /// `Ok(None)` after the batch is written.
+    pub fn remove(&mut self, object_id: ObjectId) -> &mut Batch<B> {
+        self.objects.push((object_id, None));
+        self
+    }
+
+    /// The number of inserts and removals in the batch,
+    /// including any that are later replaced by another one
+    /// for the same object ID.
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Returns `true` if nothing has been added to the batch.
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+}
+
+impl<B> IntoIterator for Batch<B> {
+    type Item = (ObjectId, Option<B>);
+    type IntoIter = std::vec::IntoIter<(ObjectId, Option<B>)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.objects.into_iter()
+    }
+}
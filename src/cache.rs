@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::{DiskLocation, Map, ObjectId};
+
+struct CacheEntry {
+    location: DiskLocation,
+    data: Arc<[u8]>,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    entries: Map<ObjectId, CacheEntry>,
+    // maps from last access tick to the object accessed then
+    lru: BTreeMap<u64, ObjectId>,
+    tick: u64,
+    size: usize,
+}
+
+impl CacheInner {
+    fn remove(&mut self, object_id: ObjectId) {
+        if let Some(entry) = self.entries.remove(&object_id) {
+            self.lru.remove(&entry.tick);
+            self.size -= entry.data.len();
+        }
+    }
+}
+
+/// An LRU cache of object contents, keyed by object ID.
+///
+/// Each entry remembers the location that it was read from,
+/// and is only returned while the location table still points
+/// there, so a cached object is never served after it has been
+/// overwritten or deleted, even if invalidation races with a
+/// concurrent read that populates the cache.
+pub(crate) struct ObjectCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+impl ObjectCache {
+    pub(crate) fn new(capacity: usize) -> ObjectCache {
+        ObjectCache {
+            capacity,
+            inner: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub(crate) fn get(&self, object_id: ObjectId, location: DiskLocation) -> Option<Arc<[u8]>> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let entry = inner.entries.get_mut(&object_id)?;
+
+        if entry.location != location {
+            return None;
+        }
+
+        let old_tick = std::mem::replace(&mut entry.tick, tick);
+        let data = entry.data.clone();
+
+        inner.lru.remove(&old_tick);
+        inner.lru.insert(tick, object_id);
+
+        Some(data)
+    }
+
+    pub(crate) fn insert(&self, object_id: ObjectId, location: DiskLocation, data: Arc<[u8]>) {
+        if !self.is_enabled() || data.len() > self.capacity {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.remove(object_id);
+
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        inner.size += data.len();
+        inner.lru.insert(tick, object_id);
+        inner.entries.insert(
+            object_id,
+            CacheEntry {
+                location,
+                data,
+                tick,
+            },
+        );
+
+        while inner.size > self.capacity {
+            let (_, lru_object_id) = inner
+                .lru
+                .pop_first()
+                .expect("cache size is non-zero so it must have entries");
+            let entry = inner.entries.remove(&lru_object_id).unwrap();
+            inner.size -= entry.data.len();
+        }
+    }
+
+    pub(crate) fn invalidate(&self, object_id: ObjectId) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        self.inner.lock().unwrap().remove(object_id);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::{Config, Marble, MarbleError};
+
+/// A durable point in the history of a store, returned by
+/// [`Marble::checkpoint`], that the store may later be
+/// rolled back to with [`Config::restore`].
+///
+/// Checkpoints are plain values, so they may be persisted
+/// by the user, for instance in a file next to the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Checkpoint {
+    /// The sequence number of the newest storage file that
+    /// is part of the checkpoint, as returned by
+    /// `Marble::files_since`.
+    pub lsn: u64,
+    /// The number of storage files that were present at or
+    /// below `lsn` when the checkpoint was taken.
+    pub files: u64,
+}
+
+impl Marble {
+    /// Syncs every completed write batch to disk, and returns
+    /// a `Checkpoint` that includes all of them, which may
+    /// later be passed to `Config::restore` to roll the store
+    /// back to its current contents. Batches that are still
+    /// being written by concurrent calls to `write_batch` are
+    /// not included.
+    ///
+    /// A checkpoint can only be restored while the storage
+    /// files that it includes still exist. Files are removed
+    /// by `maintenance`, `clear` and `repair`, and when the
+    /// store is closed after later write batches replaced or
+    /// removed all of their objects, after which restoring
+    /// the checkpoint fails.
+    pub fn checkpoint(&self) -> Result<Checkpoint, MarbleError> {
+        let files = self.files_since(0);
+
+        self.sync_all()?;
+
+        let lsn = files.last().map_or(0, |(lsn, _path)| *lsn);
+
+        Ok(Checkpoint {
+            lsn,
+            files: files.len() as u64,
+        })
+    }
+}
+
+impl Config {
+    /// Opens the store at `Config::path` as of a
+    /// `Checkpoint` returned by `Marble::checkpoint`, removing
+    /// every storage file that was written after it, or
+    /// moving them aside as configured by
+    /// `Config::recovery_policy`. Objects written after the
+    /// checkpoint are lost, and checkpoints taken after it may
+    /// no longer be restored.
+    ///
+    /// Returns `MarbleError::NotFound` without removing
+    /// anything if storage files included in the checkpoint
+    /// have since been removed, because the store could then
+    /// not be restored to the same contents. Returns an `Io`
+    /// error of kind `PermissionDenied` if `Config::read_only`
+    /// is set.
+    pub fn restore(&self, checkpoint: Checkpoint) -> Result<Marble, MarbleError> {
+        self.open_inner(Some(checkpoint))
+    }
+}
@@ -1,8 +1,90 @@
+use std::fs::File;
 use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{MarbleError, MarbleMetrics};
+
+/// What `Marble::write_batch` does when
+/// `Config::max_space_amplification` is exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Backpressure {
+    /// Return an `Io` error of kind `WouldBlock` without
+    /// writing anything. The batch may be retried after
+    /// calling `maintenance`.
+    Reject,
+    /// Block until a concurrent call to `maintenance` or
+    /// `compact_files` reduces the space amplification to at
+    /// most `max_space_amplification`, which is checked again
+    /// after every maintenance pass. This will block forever
+    /// unless maintenance is performed by another thread,
+    /// and also if the dead space is left in files that
+    /// maintenance does not rewrite because they are above
+    /// `file_compaction_percent`.
+    Block,
+}
+
+/// How storage files are flushed to disk. See
+/// `Config::sync_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SyncMode {
+    /// Flush storage files with `File::sync_all`, which also
+    /// persists metadata such as modification times.
+    Full,
+    /// Flush storage files with `File::sync_data`, which
+    /// skips metadata that is not needed to read the file
+    /// back, and is faster on many filesystems. File sizes
+    /// are still persisted, and the heap directory is still
+    /// fsynced after a new file is renamed into it, so
+    /// written batches are just as durable as with `Full`.
+    /// Only modification times and similar metadata may be
+    /// stale after a crash.
+    Data,
+}
+
+impl SyncMode {
+    pub(crate) fn sync(self, file: &File) -> io::Result<()> {
+        match self {
+            SyncMode::Full => file.sync_all(),
+            SyncMode::Data => file.sync_data(),
+        }
+    }
+}
+
+/// What `Config::restore` does with the storage files that
+/// were written after the checkpoint being restored. See
+/// `Config::recovery_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RecoveryPolicy {
+    /// Remove the files.
+    DeleteAhead,
+    /// Move the files into the `quarantine` directory in
+    /// `Config::path`, where they are kept for manual
+    /// inspection and are not read by the store. They may
+    /// be moved back into the shard directory that they came
+    /// from to undo the restore, as long as nothing has been
+    /// written to the store since.
+    QuarantineAhead,
+}
 
 /// Configuration for configuring `Marble`.
+///
+/// With the `serde` feature enabled, `Config` may be
+/// serialized and deserialized, for instance to keep it in a
+/// configuration file. `partition_function` is not
+/// serialized, and deserialized configs use
+/// `default_partition_function`. Fields that are missing
+/// from a deserialized config take their default values.
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct Config {
     /// Storage files will be kept here.
     pub path: PathBuf,
@@ -25,7 +107,10 @@ pub struct Config {
     pub file_compaction_percent: u8,
     /// The ceiling on the largest allocation this system
     /// will ever attempt to perform in order to read an
-    /// object off of disk.
+    /// object off of disk. Write batches that contain larger
+    /// objects are rejected, so this also limits how far a
+    /// single object can make its storage file grow past
+    /// `target_file_size`.
     pub max_object_size: usize,
     /// The number of total files (of all sizes) that must
     /// exist before "small files" are squished together
@@ -35,21 +120,156 @@ pub struct Config {
     /// `target_file_size`.
     pub small_file_cleanup_threshold: usize,
     /// A partitioning function for objects based on
-    /// object ID and object size. You may override this to
-    /// cause objects to be written into separate files so
-    /// that garbage collection may take advantage of
-    /// locality effects for your workload that are
-    /// correlated to object identifiers or the size of
+    /// object ID, object size and generation. You may
+    /// override this to cause objects to be written into
+    /// separate files so that garbage collection may take
+    /// advantage of locality effects for your workload that
+    /// are correlated to object identifiers or the size of
     /// data.
     ///
+    /// It is called when `Marble::maintenance` rewrites
+    /// objects, with the generation of the file that they
+    /// are rewritten into, from 1 up to a maximum of 3,
+    /// which is the number of times that they have survived
+    /// being rewritten. Objects that survive more rewrites
+    /// tend to be colder, and may be separated from hotter
+    /// objects based on it. Objects written by
+    /// `write_batch` are not partitioned, as described in
+    /// the crate documentation.
+    ///
     /// Ideally, you will colocate objects that have
     /// similar expected lifespans. Doing so minimizes
     /// the costs of copying live data over time during
     /// storage file GC.
-    pub partition_function: fn(object_id: u64, object_size: usize) -> u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub partition_function: fn(object_id: u64, object_size: usize, generation: u8) -> u8,
     /// The minimum number of files within a generation to
     /// collect if below the live compaction percent.
     pub min_compaction_files: usize,
+    /// The number of threads that `maintenance`,
+    /// `maintenance_shard` and `compact_files` rewrite files
+    /// with. When this is greater than 1, the files being
+    /// compacted are split into groups that are rewritten
+    /// into separate new files concurrently, and files are
+    /// only removed once every group has been rewritten.
+    /// Must be non-zero. Defaults to 1.
+    pub compaction_threads: usize,
+    /// The number of bytes of object data that may be kept
+    /// in-memory by `Marble::read_cached`. Setting this to
+    /// 0 disables the cache.
+    pub cache_bytes: usize,
+    /// The size of the buffer that objects are copied into
+    /// while they are streamed into a new storage file.
+    /// Write batches are never buffered in memory as a
+    /// whole, so this bounds the extra memory used by each
+    /// concurrent call to `write_batch` or `maintenance`,
+    /// at the cost of more write syscalls when it is small.
+    pub write_buffer_size: usize,
+    /// Lay out the objects of each write batch in object ID
+    /// order, so that objects with nearby IDs are stored
+    /// near each other. If this is disabled, objects are laid
+    /// out in the order that the batch iterates over them.
+    /// Maintenance always rewrites objects in object ID
+    /// order.
+    pub sort_batches: bool,
+    /// Limits the growth of disk usage when objects are
+    /// written faster than maintenance reclaims the space
+    /// of dead ones. If `Marble::space_amplification` is
+    /// above this value when `write_batch` is called, the
+    /// batch is handled according to `backpressure`.
+    /// Checking this requires looking at every storage file
+    /// for each call to `write_batch`. Must be greater than
+    /// 1.0. Defaults to `None`, which never applies
+    /// backpressure.
+    pub max_space_amplification: Option<f64>,
+    /// What `write_batch` does when `max_space_amplification`
+    /// is exceeded.
+    pub backpressure: Backpressure,
+    /// Open the store without modifying it, for inspecting a
+    /// store that may be damaged. Files left behind by a
+    /// crash are ignored rather than removed, storage files
+    /// are never removed, and `write_batch`, `maintenance`,
+    /// `maintenance_shard`, `compact_files` and `repair`
+    /// return an `Io` error of kind `PermissionDenied`.
+    /// Read-only instances may be opened concurrently with
+    /// each other, but not with an instance that may write.
+    pub read_only: bool,
+    /// How the contents of storage files are flushed to disk
+    /// when they are written, and by `Marble::sync_all`.
+    /// Defaults to `SyncMode::Full`.
+    pub sync_mode: SyncMode,
+    /// Also consider a file rewritable once more than this
+    /// many of the objects stored in it are dead, regardless
+    /// of `file_compaction_percent`. This suits workloads
+    /// with objects of uniform size, where a fixed number of
+    /// dead objects is a better measure of wasted space than
+    /// a percentage of the file. Defaults to `None`, which
+    /// only uses `file_compaction_percent`.
+    pub compaction_dead_objects_threshold: Option<u64>,
+    /// Skip files that were written less than this long ago
+    /// in `maintenance` and `maintenance_shard`, because
+    /// recently written objects are often overwritten again
+    /// soon, which would make rewriting them wasted work.
+    /// The age of a file is based on its modification time,
+    /// so it is kept across restarts. `compact_files` is not
+    /// affected. Defaults to `None`, which compacts files of
+    /// any age.
+    pub min_compaction_age: Option<Duration>,
+    /// Makes `Marble::allocate_object_id` return the IDs of
+    /// deleted objects before allocating new ones, which
+    /// keeps object IDs compact for workloads that delete
+    /// many objects. Defaults to `false`.
+    pub reuse_object_ids: bool,
+    /// Removes `Config::path` and everything in it once the
+    /// `Marble`, and every clone and `Snapshot` of it, is
+    /// dropped, for stores that only hold ephemeral data,
+    /// such as caches and tests. See [`crate::open_in_memory`].
+    /// May not be combined with `read_only`. Defaults to
+    /// `false`.
+    pub temporary: bool,
+    /// Pads object records in new storage files so that each
+    /// one starts at a multiple of this many bytes, such as
+    /// 512 or 4096, in preparation for reading them with
+    /// direct IO. Must be a power of two. Files written with
+    /// a different alignment, or none, are still read
+    /// normally. Defaults to `None`, which writes records
+    /// without any padding.
+    pub record_alignment: Option<usize>,
+    /// The number of times that reads of objects, and the
+    /// opens, syncs and renames of `write_batch`, are retried
+    /// with exponential backoff after failing with a transient
+    /// error such as `EINTR`, `EAGAIN` or `EBUSY`, which may
+    /// happen on network filesystems. Errors like running out
+    /// of space or a failed read of a bad sector are never
+    /// retried. Defaults to 3.
+    pub io_retries: u32,
+    /// What `Config::restore` does with the storage files that
+    /// were written after the checkpoint being restored.
+    /// Defaults to `RecoveryPolicy::DeleteAhead`.
+    pub recovery_policy: RecoveryPolicy,
+    /// Leaves temporary files of writes that were interrupted
+    /// by a crash in the storage directory when opening the
+    /// store, for instance to inspect them, rather than
+    /// removing them. They are reported as
+    /// `RecoveryEvent::IgnoredTemporaryFile`, and are never
+    /// read or overwritten by the store, so they must be
+    /// removed manually. Defaults to `false`.
+    pub keep_tmp_files: bool,
+    /// The maximum number of storage files that are kept
+    /// open at once. Once more files than this are open, the
+    /// handles of the least recently read files are closed,
+    /// and they are opened again when they are next read,
+    /// which allows stores with very many files to stay
+    /// below the process's limit of open files. Files that
+    /// are still being written are not counted. Defaults to
+    /// `None`, which keeps every file open.
+    pub max_open_files: Option<usize>,
+    /// Receives notifications about writes, reads,
+    /// maintenance and removed files, which may be used to
+    /// export metrics. See `MarbleMetrics`. This is not
+    /// serialized when the `serde` feature is enabled.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub metrics: Option<Arc<dyn MarbleMetrics>>,
 }
 
 impl Default for Config {
@@ -62,28 +282,78 @@ impl Default for Config {
             max_object_size: 16 * 1024 * 1024 * 1024, /* 16gb */
             small_file_cleanup_threshold: 64,
             min_compaction_files: 2,
+            compaction_threads: 1,
             fsync_each_batch: false,
             zstd_compression_level: None,
+            cache_bytes: 0,
+            write_buffer_size: 8 * 1024 * 1024,
+            sort_batches: true,
+            max_space_amplification: None,
+            backpressure: Backpressure::Reject,
+            read_only: false,
+            sync_mode: SyncMode::Full,
+            compaction_dead_objects_threshold: None,
+            min_compaction_age: None,
+            reuse_object_ids: false,
+            temporary: false,
+            record_alignment: None,
+            io_retries: 3,
+            recovery_policy: RecoveryPolicy::DeleteAhead,
+            keep_tmp_files: false,
+            max_open_files: None,
+            metrics: None,
         }
     }
 }
 
 impl Config {
-    pub(crate) fn validate(&self) -> io::Result<()> {
+    pub(crate) fn validate(&self) -> Result<(), MarbleError> {
         if self.target_file_size == 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::Unsupported,
-                "Config's target_file_size must be non-zero",
+            return Err(MarbleError::InvalidConfig(
+                "Config's target_file_size must be non-zero".into(),
+            ));
+        }
+
+        if self.compaction_threads == 0 {
+            return Err(MarbleError::InvalidConfig(
+                "Config's compaction_threads must be non-zero".into(),
             ));
         }
 
         if self.file_compaction_percent > 99 {
-            return Err(io::Error::new(
-                io::ErrorKind::Unsupported,
-                "Config's file_compaction_percent must be less than 100",
+            return Err(MarbleError::InvalidConfig(
+                "Config's file_compaction_percent must be less than 100".into(),
             ));
         }
 
+        if let Some(max_space_amplification) = self.max_space_amplification {
+            if max_space_amplification.is_nan() || max_space_amplification <= 1.0 {
+                return Err(MarbleError::InvalidConfig(
+                    "Config's max_space_amplification must be greater than 1.0".into(),
+                ));
+            }
+        }
+
+        if self.temporary && self.read_only {
+            return Err(MarbleError::InvalidConfig(
+                "Config's temporary and read_only may not both be set".into(),
+            ));
+        }
+
+        if self.max_open_files == Some(0) {
+            return Err(MarbleError::InvalidConfig(
+                "Config's max_open_files must be non-zero".into(),
+            ));
+        }
+
+        if let Some(record_alignment) = self.record_alignment {
+            if !record_alignment.is_power_of_two() {
+                return Err(MarbleError::InvalidConfig(
+                    "Config's record_alignment must be a power of two".into(),
+                ));
+            }
+        }
+
         Ok(())
     }
 }
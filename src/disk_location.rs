@@ -51,26 +51,32 @@ impl RelativeDiskLocation {
     }
 }
 
+/// The location of a storage file or of an object within
+/// one, as reported by `Marble::file_fragmentation` and
+/// `Marble::location`. A
+/// location identifies the same file across restarts, until
+/// the file is removed by maintenance.
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
 #[repr(transparent)]
 pub struct DiskLocation(NonZeroU64);
 
 impl DiskLocation {
-    pub const MAX: DiskLocation = DiskLocation(unsafe { NonZeroU64::new_unchecked(u64::MAX) });
+    pub(crate) const MAX: DiskLocation =
+        DiskLocation(unsafe { NonZeroU64::new_unchecked(u64::MAX) });
 
-    pub fn new(location: u64, is_delete: bool) -> DiskLocation {
+    pub(crate) fn new(location: u64, is_delete: bool) -> DiskLocation {
         DiskLocation(NonZeroU64::new(shift_location(location, is_delete)).unwrap())
     }
 
-    pub fn new_fam(location: u64) -> DiskLocation {
+    pub(crate) fn new_fam(location: u64) -> DiskLocation {
         DiskLocation::new(location, false)
     }
 
-    pub fn from_raw(u: u64) -> Option<DiskLocation> {
+    pub(crate) fn from_raw(u: u64) -> Option<DiskLocation> {
         Some(DiskLocation(NonZeroU64::new(u)?))
     }
 
-    pub fn to_raw(&self) -> u64 {
+    pub(crate) fn to_raw(&self) -> u64 {
         self.0.get()
     }
 
@@ -78,11 +84,11 @@ impl DiskLocation {
         unshift_location(self.0.get())
     }
 
-    pub fn lsn(&self) -> u64 {
+    pub(crate) fn lsn(&self) -> u64 {
         self.unshift().0
     }
 
-    pub fn is_delete(&self) -> bool {
+    pub(crate) fn is_delete(&self) -> bool {
         self.unshift().1
     }
 }
@@ -0,0 +1,78 @@
+use std::io;
+
+use crate::ObjectId;
+
+/// The error type returned by `Marble` operations.
+///
+/// `MarbleError` converts to and from `io::Error`, so it may
+/// be propagated with `?` from functions that return
+/// `io::Result`.
+#[derive(Debug)]
+pub enum MarbleError {
+    /// Stored data failed a checksum or is otherwise
+    /// malformed. This usually indicates hardware problems
+    /// or modification of storage files by something other
+    /// than Marble. See `Marble::verify` and `Marble::repair`.
+    Corruption(io::Error),
+    /// A storage file or other resource that an operation
+    /// referred to does not exist. Reading an object that
+    /// does not exist is not an error, and returns `Ok(None)`.
+    NotFound(io::Error),
+    /// An underlying IO operation failed.
+    Io(io::Error),
+    /// The `Config` used to open the store is invalid.
+    InvalidConfig(String),
+    /// A write batch contained an object ID that is greater
+    /// than [`crate::MAX_OBJECT_ID`]. Nothing was written.
+    ReservedObjectId(ObjectId),
+}
+
+impl std::fmt::Display for MarbleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MarbleError::Corruption(e) => write!(f, "corruption detected: {e}"),
+            MarbleError::NotFound(e) => write!(f, "not found: {e}"),
+            MarbleError::Io(e) => write!(f, "io error: {e}"),
+            MarbleError::InvalidConfig(reason) => write!(f, "invalid config: {reason}"),
+            MarbleError::ReservedObjectId(object_id) => write!(
+                f,
+                "object ID {object_id} in write batch is reserved for internal use, the maximum \
+                 usable object ID is {}",
+                crate::MAX_OBJECT_ID,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MarbleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MarbleError::Corruption(e) | MarbleError::NotFound(e) | MarbleError::Io(e) => Some(e),
+            MarbleError::InvalidConfig(_) | MarbleError::ReservedObjectId(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for MarbleError {
+    fn from(e: io::Error) -> MarbleError {
+        match e.kind() {
+            io::ErrorKind::InvalidData => MarbleError::Corruption(e),
+            io::ErrorKind::NotFound => MarbleError::NotFound(e),
+            _ => MarbleError::Io(e),
+        }
+    }
+}
+
+impl From<MarbleError> for io::Error {
+    fn from(e: MarbleError) -> io::Error {
+        match e {
+            MarbleError::Corruption(e) | MarbleError::NotFound(e) | MarbleError::Io(e) => e,
+            MarbleError::InvalidConfig(_) => {
+                io::Error::new(io::ErrorKind::Unsupported, e.to_string())
+            }
+            MarbleError::ReservedObjectId(_) => {
+                io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::{
+    Config, Marble, MarbleError, ObjectId, ObjectMetadata, OBJECT_METADATA_LEN, RESERVED_OBJECT_ID,
+};
+
+const EXPORT_MAGIC: &[u8; 8] = b"marblex2";
+// exports written before objects had metadata, which are
+// imported with metadata of all zeroes
+const EXPORT_MAGIC_V1: &[u8; 8] = b"marblex1";
+
+// Export format:
+//
+// magic | (object ID, length, metadata, bytes)* | end
+//
+// where each object ID and length is a little-endian u64,
+// metadata is the object's `ObjectMetadata`, and end is the
+// reserved object ID followed by the number of exported
+// objects and a crc of everything that precedes the crc.
+// Exports with `EXPORT_MAGIC_V1` have no metadata field.
+
+/// Import objects that were written by [`Marble::export`]
+/// into a new store at the provided path, using the
+/// default configuration.
+pub fn import<P: AsRef<Path>, R: Read>(path: P, reader: &mut R) -> Result<Marble, MarbleError> {
+    let config = Config {
+        path: path.as_ref().into(),
+        ..Config::default()
+    };
+
+    config.import(reader)
+}
+
+struct CrcReader<'a, R> {
+    reader: &'a mut R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<'a, R: Read> CrcReader<'a, R> {
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+impl<'a, R: Read> Read for CrcReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+struct CrcWriter<'a, W> {
+    writer: &'a mut W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<'a, W: Write> Write for CrcWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Marble {
+    /// Write every live object to `writer` in a portable
+    /// format that does not depend on the layout of storage
+    /// files, and which may be loaded into a new store using
+    /// [`Config::import`], along with its `ObjectMetadata`.
+    /// Deleted objects are not exported.
+    /// Objects written or deleted concurrently with this
+    /// method may or may not be reflected in the export.
+    pub fn export<W: Write>(&self, writer: &mut W) -> Result<(), MarbleError> {
+        let mut writer = CrcWriter {
+            writer,
+            hasher: crc32fast::Hasher::new(),
+        };
+
+        writer.write_all(EXPORT_MAGIC)?;
+
+        let mut objects: u64 = 0;
+
+        for object_id in self.allocated_object_ids() {
+            let (data, metadata) = if let Some(read) = self.read_with_metadata(object_id)? {
+                read
+            } else {
+                continue;
+            };
+
+            writer.write_all(&object_id.to_le_bytes())?;
+            writer.write_all(&(data.len() as u64).to_le_bytes())?;
+            writer.write_all(&metadata)?;
+            writer.write_all(&data)?;
+
+            objects += 1;
+        }
+
+        writer.write_all(&RESERVED_OBJECT_ID.to_le_bytes())?;
+        writer.write_all(&objects.to_le_bytes())?;
+
+        let crc = writer.hasher.finalize();
+        writer.writer.write_all(&crc.to_le_bytes())?;
+        writer.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Create a new store at `Config::path` and load it with
+    /// the objects written by [`Marble::export`]. Returns an
+    /// `Io` error of kind `InvalidInput` if the store already
+    /// contains objects, and `Corruption` if the export is
+    /// truncated or corrupt. Objects are written in several
+    /// batches as they are read, so a store that was being
+    /// imported into when an error was returned or the
+    /// process crashed should be removed before retrying.
+    pub fn import<R: Read>(&self, reader: &mut R) -> Result<Marble, MarbleError> {
+        let marble = self.open()?;
+
+        if marble.allocated_object_ids().next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot import into non-empty store at {:?}", self.path),
+            )
+            .into());
+        }
+
+        let mut reader = CrcReader {
+            reader,
+            hasher: crc32fast::Hasher::new(),
+        };
+
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic).map_err(truncated)?;
+        let has_metadata = if &magic == EXPORT_MAGIC {
+            true
+        } else if &magic == EXPORT_MAGIC_V1 {
+            false
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "input is not an export written by Marble::export",
+            )
+            .into());
+        };
+
+        let mut batch: Vec<(ObjectId, Option<(ObjectMetadata, Vec<u8>)>)> = vec![];
+        let mut batch_size = 0;
+        let mut objects: u64 = 0;
+
+        loop {
+            let object_id = reader.read_u64().map_err(truncated)?;
+
+            if object_id == RESERVED_OBJECT_ID {
+                break;
+            }
+
+            let len = reader.read_u64().map_err(truncated)?;
+
+            if len > self.max_object_size as u64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "exported object {object_id} has a size of {len}, which is larger than \
+                         the configured `max_object_size` of {}",
+                        self.max_object_size,
+                    ),
+                )
+                .into());
+            }
+
+            let mut metadata = ObjectMetadata::default();
+            if has_metadata {
+                reader.read_exact(&mut metadata).map_err(truncated)?;
+            }
+
+            let mut data = vec![0; usize::try_from(len).unwrap()];
+            reader.read_exact(&mut data).map_err(truncated)?;
+
+            batch_size += OBJECT_METADATA_LEN + data.len();
+            batch.push((object_id, Some((metadata, data))));
+            objects += 1;
+
+            if batch_size >= self.target_file_size {
+                marble.write_batch_with_metadata(std::mem::take(&mut batch))?;
+                batch_size = 0;
+            }
+        }
+
+        let expected_objects = reader.read_u64().map_err(truncated)?;
+
+        let crc_actual = reader.hasher.clone().finalize();
+        let mut crc_buf = [0; 4];
+        reader.reader.read_exact(&mut crc_buf).map_err(truncated)?;
+        let crc_expected = u32::from_le_bytes(crc_buf);
+
+        if crc_expected != crc_actual || expected_objects != objects {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "corrupt export: expected {expected_objects} objects with crc \
+                     {crc_expected} but read {objects} objects with crc {crc_actual}"
+                ),
+            )
+            .into());
+        }
+
+        if !batch.is_empty() {
+            marble.write_batch_with_metadata(batch)?;
+        }
+
+        marble.sync_all()?;
+
+        Ok(marble)
+    }
+}
+
+fn truncated(e: io::Error) -> io::Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        io::Error::new(io::ErrorKind::InvalidData, "export is truncated")
+    } else {
+        e
+    }
+}
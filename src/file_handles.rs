@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+
+use fault_injection::fallible;
+
+use crate::{DiskLocation, Map};
+
+type Slot = Mutex<Option<Arc<File>>>;
+
+#[derive(Default)]
+struct OpenFilesInner {
+    // maps from each tracked file to its last access tick
+    entries: Map<DiskLocation, u64>,
+    // maps from last access tick to the file accessed then
+    lru: BTreeMap<u64, (DiskLocation, Weak<Slot>)>,
+    tick: u64,
+}
+
+/// An LRU of the storage files whose handles are open, which
+/// closes the least recently used handles once more than
+/// `Config::max_open_files` are open.
+pub(crate) struct OpenFiles {
+    capacity: usize,
+    inner: Mutex<OpenFilesInner>,
+}
+
+impl OpenFiles {
+    pub(crate) fn new(capacity: usize) -> OpenFiles {
+        assert_ne!(capacity, 0);
+        OpenFiles {
+            capacity,
+            inner: Mutex::default(),
+        }
+    }
+
+    fn touch(&self, location: DiskLocation, slot: &Arc<Slot>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if let Some(old_tick) = inner.entries.insert(location, tick) {
+            inner.lru.remove(&old_tick);
+        }
+        inner.lru.insert(tick, (location, Arc::downgrade(slot)));
+
+        while inner.entries.len() > self.capacity {
+            let (_, (lru_location, lru_slot)) = inner
+                .lru
+                .pop_first()
+                .expect("open files are tracked so there must be entries");
+            inner.entries.remove(&lru_location);
+
+            // reads that are using the handle keep it open
+            // until they complete
+            if let Some(lru_slot) = lru_slot.upgrade() {
+                log::trace!("closing handle of file at location {lru_location:?}");
+                lru_slot.lock().unwrap().take();
+            }
+        }
+    }
+
+    fn forget(&self, location: DiskLocation) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(tick) = inner.entries.remove(&location) {
+            inner.lru.remove(&tick);
+        }
+    }
+}
+
+/// The handle of a storage file. If `Config::max_open_files`
+/// is set, it is closed when other files are used more
+/// recently, and opened again from the file's path when it
+/// is next used.
+pub(crate) struct FileHandle {
+    location: DiskLocation,
+    slot: Arc<Slot>,
+    open_files: Option<Arc<OpenFiles>>,
+}
+
+impl FileHandle {
+    pub(crate) fn new(
+        location: DiskLocation,
+        file: File,
+        open_files: Option<Arc<OpenFiles>>,
+    ) -> FileHandle {
+        FileHandle {
+            location,
+            slot: Arc::new(Mutex::new(Some(Arc::new(file)))),
+            open_files,
+        }
+    }
+
+    /// Returns the open handle, reopening it from `path` if
+    /// it was closed. Handles are only closed after `track`
+    /// was called with the path of the file.
+    pub(crate) fn get(&self, path: Option<&Path>) -> io::Result<Arc<File>> {
+        let mut slot = self.slot.lock().unwrap();
+        let file = if let Some(file) = &*slot {
+            file.clone()
+        } else {
+            let path = path.expect("handles of files without a path are never closed");
+            log::trace!("reopening handle of file {path:?}");
+            let file = Arc::new(fallible!(OpenOptions::new().read(true).open(path)));
+            *slot = Some(file.clone());
+            file
+        };
+        drop(slot);
+
+        if path.is_some() {
+            self.track();
+        }
+
+        Ok(file)
+    }
+
+    /// Allows the handle to be closed, once the file has a
+    /// path that it may be reopened from.
+    pub(crate) fn track(&self) {
+        if let Some(open_files) = &self.open_files {
+            open_files.touch(self.location, &self.slot);
+        }
+    }
+}
+
+impl Drop for FileHandle {
+    fn drop(&mut self) {
+        if let Some(open_files) = &self.open_files {
+            open_files.forget(self.location);
+        }
+    }
+}
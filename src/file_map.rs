@@ -11,8 +11,8 @@ use std::sync::{
 use concurrent_map::{ConcurrentMap, Maximum};
 
 use crate::{
-    debug_delay, Config, DiskLocation, FileAndMetadata, LocationTable, Map, Metadata, ObjectId,
-    ZstdDict, NEW_WRITE_BATCH_BIT,
+    debug_delay, Config, DiskLocation, FileAndMetadata, FileHandle, LocationTable, Map, Metadata,
+    ObjectId, OpenFiles, SyncMode, ZstdDict, NEW_WRITE_BATCH_BIT,
 };
 
 impl Maximum for DiskLocation {
@@ -43,15 +43,16 @@ impl<'a> Drop for DeferUnclaim<'a> {
 pub(crate) struct FileMap {
     pub(crate) fams: ConcurrentMap<Reverse<DiskLocation>, Arc<FileAndMetadata>, 16, 1>,
     pub(crate) next_file_lsn: Arc<AtomicU64>,
+    pub(crate) sync_mode: SyncMode,
+    pub(crate) open_files: Option<Arc<OpenFiles>>,
 }
 
 impl FileMap {
     pub fn files_to_defrag<'a>(
         &'a self,
         config: &Config,
+        partition: Option<u8>,
     ) -> io::Result<(Map<u8, Vec<Arc<FileAndMetadata>>>, DeferUnclaim<'a>)> {
-        const MAX_GENERATION: u8 = 3;
-
         let mut claims = DeferUnclaim {
             file_map: self,
             claims: vec![],
@@ -64,6 +65,10 @@ impl FileMap {
         for (location, fam) in &self.fams {
             assert_eq!(location.0, fam.location);
 
+            if partition.map_or(false, |partition| partition != fam.partition) {
+                continue;
+            }
+
             let metadata: &Metadata = if let Some(m) = fam.metadata() {
                 m
             } else {
@@ -77,13 +82,29 @@ impl FileMap {
             let non_empty = live_objects != 0;
             let live_percent = (live_objects * 100) / live_and_dead_objects.max(1);
             let candidate_by_percent = live_percent < u64::from(config.file_compaction_percent);
+            let dead_objects = live_and_dead_objects.saturating_sub(live_objects);
+            let candidate_by_dead_objects = config
+                .compaction_dead_objects_threshold
+                .is_some_and(|threshold| dead_objects > threshold);
             let is_small_file = (metadata.file_size * config.min_compaction_files as u64)
                 < config.target_file_size as u64;
             let over_small_file_cleanup_threshold =
                 config.small_file_cleanup_threshold <= approximate_fam_len;
             let candidate_by_size = over_small_file_cleanup_threshold && is_small_file;
 
-            if non_empty && (candidate_by_percent || candidate_by_size) {
+            let candidate = non_empty
+                && (candidate_by_percent || candidate_by_dead_objects || candidate_by_size);
+
+            if candidate && !fam.is_old_enough_to_compact(config)? {
+                log::trace!(
+                    "not compacting fam at location {:?}, as it is younger than {:?}",
+                    fam.location,
+                    config.min_compaction_age
+                );
+                continue;
+            }
+
+            if candidate {
                 debug_delay();
                 let already_locked = fam.rewrite_claim.swap(true, SeqCst);
                 if already_locked {
@@ -97,7 +118,7 @@ impl FileMap {
 
                 claims.claims.push(location.0);
 
-                let generation = fam.generation.saturating_add(1).min(MAX_GENERATION);
+                let generation = fam.rewrite_generation();
 
                 log::trace!(
                     "fam at location {:?} generation {generation} is ready to be compacted, \
@@ -117,14 +138,90 @@ impl FileMap {
         Ok((files_to_defrag, claims))
     }
 
-    pub fn fam_for_location(&self, location: DiskLocation) -> Arc<FileAndMetadata> {
-        let (_, fam) = self
+    /// Claims the files at the given locations for rewriting,
+    /// grouped by the generation that they will be rewritten
+    /// into. Fails without claiming anything if any of them
+    /// does not exist or is already claimed.
+    pub fn claim_files<'a>(
+        &'a self,
+        locations: &[DiskLocation],
+    ) -> io::Result<(Map<u8, Vec<Arc<FileAndMetadata>>>, DeferUnclaim<'a>)> {
+        let mut claims = DeferUnclaim {
+            file_map: self,
+            claims: vec![],
+        };
+
+        let mut files_to_defrag: Map<u8, Vec<Arc<FileAndMetadata>>> = Map::default();
+
+        for location in locations {
+            let fam = if let Some(fam) = self.fams.get(&Reverse(*location)) {
+                fam
+            } else {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no storage file exists at location {location:?}"),
+                ));
+            };
+
+            if claims.claims.contains(location) {
+                continue;
+            }
+
+            debug_delay();
+            let already_locked = fam.rewrite_claim.swap(true, SeqCst);
+            if already_locked {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!(
+                        "storage file at location {location:?} is concurrently being written, \
+                         rewritten or removed"
+                    ),
+                ));
+            }
+
+            claims.claims.push(*location);
+
+            // files are only unclaimed once their metadata is set
+            assert!(fam.metadata().is_some());
+
+            let entry = files_to_defrag.entry(fam.rewrite_generation()).or_default();
+            entry.push(fam);
+        }
+
+        Ok((files_to_defrag, claims))
+    }
+
+    /// Returns the file that contains `location`. Each file
+    /// is keyed by the LSN of its first byte, and LSNs are
+    /// never shared between files, so the file with the
+    /// highest LSN at or below `location` is the only one
+    /// that may contain it. This holds even after files
+    /// have been removed, as long as nothing still points
+    /// into them, and is checked against the bounds of the
+    /// file that is found.
+    pub fn fam_for_location(&self, location: DiskLocation) -> io::Result<Arc<FileAndMetadata>> {
+        let fam_opt = self
             .fams
             .range((Included(Reverse(location)), Unbounded))
             .next()
-            .expect("no possible storage file for object - likely file corruption");
+            .map(|(_, fam)| fam);
+
+        let in_bounds = |fam: &FileAndMetadata| {
+            // files that are still being written do not have
+            // metadata yet, but locations only point into them
+            // after their objects have been written.
+            fam.metadata().map_or(true, |metadata| {
+                location.lsn() - fam.location.lsn() < metadata.trailer_offset
+            })
+        };
 
-        fam
+        match fam_opt {
+            Some(fam) if in_bounds(&fam) => Ok(fam),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no storage file contains location {location:?} - likely file corruption"),
+            )),
+        }
     }
 
     pub fn insert<'a>(
@@ -133,6 +230,7 @@ impl FileMap {
         written_bytes: u64,
         initial_capacity: u64,
         generation: u8,
+        partition: u8,
         is_gc: bool,
         config: &Config,
         decompressor: ZstdDict,
@@ -149,9 +247,11 @@ impl FileMap {
         log::debug!("inserting new fam at lsn {lsn} location {location:?}",);
 
         let fam = Arc::new(FileAndMetadata {
-            file: file,
+            // the handle is not closed until the file has a path
+            handle: FileHandle::new(location, file, self.open_files.clone()),
             live_objects: initial_capacity.into(),
             generation,
+            partition,
             location,
             synced: config.fsync_each_batch.into(),
             metadata: AtomicPtr::default(),
@@ -160,6 +260,7 @@ impl FileMap {
             // on us until we're done with our write operation.
             path: AtomicPtr::default(),
             rewrite_claim: true.into(),
+            read_only: false,
             zstd_dict: decompressor,
         });
 
@@ -179,7 +280,7 @@ impl FileMap {
         let mut synced_files = false;
         for fam in self.fams.iter().map(|(_k, v)| v) {
             if !fam.synced.load(SeqCst) {
-                fam.file.sync_all()?;
+                self.sync_mode.sync(&*fam.file()?)?;
                 fam.synced.store(true, SeqCst);
                 synced_files = true;
             }
@@ -188,7 +289,14 @@ impl FileMap {
         Ok(synced_files)
     }
 
-    pub fn prune_empty_files<'a>(&'a self, location_table: &LocationTable) -> io::Result<()> {
+    /// Removes files without live objects from the file map,
+    /// returning the number of files removed and their total
+    /// size.
+    pub fn prune_empty_files<'a>(
+        &'a self,
+        config: &Config,
+        location_table: &LocationTable,
+    ) -> io::Result<(usize, u64)> {
         // remove the empty fams
         let mut paths_to_remove = vec![];
 
@@ -216,17 +324,25 @@ impl FileMap {
             }
         }
 
-        for (location, _) in &paths_to_remove {
+        let mut removed_bytes = 0;
+        for (location, path) in &paths_to_remove {
             log::trace!("removing fam at location {:?}", location);
 
             self.verify_file_uninhabited(*location, location_table);
 
-            self.fams.remove(&Reverse(*location)).unwrap();
+            if let Some(metrics) = &config.metrics {
+                metrics.on_file_empty(*location, path);
+            }
+
+            let fam = self.fams.remove(&Reverse(*location)).unwrap();
+            if let Some(metadata) = fam.metadata() {
+                removed_bytes += metadata.file_size;
+            }
         }
 
         drop(claims);
 
-        Ok(())
+        Ok((paths_to_remove.len(), removed_bytes))
     }
 
     pub fn verify_files_uninhabited(
@@ -240,22 +356,30 @@ impl FileMap {
     }
 
     /// Returns the counts of (files, total file size, total stored objects, live objects)
-    pub(crate) fn stats(&self) -> (usize, u64, u64, u64) {
+    pub(crate) fn stats(&self) -> (usize, u64, u64, u64, u64) {
         let mut live_objects = 0;
         let mut stored_objects = 0;
 
         let mut fams_len = 0;
         let mut total_file_size = 0;
+        let mut total_trailer_size = 0;
         for (_, fam) in &self.fams {
             if let Some(metadata) = fam.metadata() {
                 fams_len += 1;
                 total_file_size += metadata.file_size;
+                total_trailer_size += metadata.file_size - metadata.trailer_offset;
                 live_objects += fam.live_objects.load(SeqCst);
                 stored_objects += metadata.present_objects;
             }
         }
 
-        (fams_len, total_file_size, stored_objects, live_objects)
+        (
+            fams_len,
+            total_file_size,
+            total_trailer_size,
+            stored_objects,
+            live_objects,
+        )
     }
 
     pub fn delete_partially_installed_fam(&self, location: DiskLocation, tmp_path: PathBuf) {
@@ -1,30 +1,208 @@
-use std::io::{self, Read};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering::SeqCst},
+    Arc,
+};
+use std::time::{Duration, Instant};
 
 use fault_injection::annotate;
 
 use crate::{
-    hash, read_range_at, read_trailer_from_buf, uninit_boxed_slice, DiskLocation, Map, Marble,
-    ObjectId, RelativeDiskLocation, HEADER_LEN,
+    decode_record_len, file_map::DeferUnclaim, hash, read_range_at, read_trailer_from_buf,
+    record_has_metadata, writepath::BatchData, DiskLocation, FileAndMetadata, Map, Marble,
+    MarbleError, ObjectId, HEADER_LEN,
 };
 
+/// The results of a call to [`Marble::maintenance`],
+/// [`Marble::maintenance_shard`],
+/// [`Marble::maintenance_budgeted`] or
+/// [`Marble::compact_files`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    /// The number of storage files whose live objects were
+    /// rewritten into new files.
+    pub files_rewritten: usize,
+    /// The number of live objects that were rewritten,
+    /// including deletions that still had to be recorded.
+    pub objects_rewritten: usize,
+    /// The number of storage files that were removed because
+    /// no live objects remained in them, including files
+    /// emptied by write batches rather than by this call.
+    pub files_removed: usize,
+    /// The total size of the removed storage files, minus the
+    /// size of the new files that objects were rewritten
+    /// into.
+    pub bytes_reclaimed: u64,
+}
+
+/// The limits of `Marble::maintenance_budgeted`.
+#[derive(Clone, Copy)]
+struct Budget {
+    max_bytes: u64,
+    // `None` if the time is too far in the future to represent
+    deadline: Option<Instant>,
+}
+
+/// The work done by rewriting one or more groups of files.
+#[derive(Default)]
+struct Rewritten {
+    objects: usize,
+    files: usize,
+    bytes: u64,
+    written_file_bytes: u64,
+}
+
 impl Marble {
     /// Defragments backing storage files, blocking
     /// concurrent calls to `write_batch` but not
-    /// blocking concurrent calls to `read`. Returns the
-    /// number of rewritten objects.
-    pub fn maintenance(&self) -> io::Result<usize> {
+    /// blocking concurrent calls to `read`. Returns a
+    /// `MaintenanceReport` describing the work done.
+    pub fn maintenance(&self) -> Result<MaintenanceReport, MarbleError> {
         log::debug!("performing maintenance");
 
-        let (files_to_defrag, claims): (Map<u8, Vec<_>>, _) =
-            self.file_map.files_to_defrag(&self.config)?;
+        self.maintenance_inner(None).map_err(MarbleError::from)
+    }
+
+    /// Like `maintenance`, but only defragments storage files
+    /// that belong to the given shard, which is the partition
+    /// assigned by `Config::partition_function` when objects
+    /// were rewritten. Files created by `write_batch` belong
+    /// to shard 0. This allows the cost of maintenance to be
+    /// spread out by compacting one shard at a time. Live
+    /// objects that are rewritten may still be moved into
+    /// files of other shards. Returns a `MaintenanceReport`
+    /// describing the work done.
+    pub fn maintenance_shard(&self, shard: u8) -> Result<MaintenanceReport, MarbleError> {
+        log::debug!("performing maintenance on shard {shard}");
+
+        self.maintenance_inner(Some(shard))
+            .map_err(MarbleError::from)
+    }
 
-        // use this old_locations Map in the outer loop to reuse the
-        // allocation and avoid resizing as often.
-        let mut old_locations: Map<ObjectId, DiskLocation> = Map::default();
+    /// Like `maintenance`, but limits the work done by a single
+    /// call, so that compaction may be spread out over many
+    /// calls instead of causing long pauses. Files are
+    /// compacted starting with the most fragmented ones, until
+    /// storage files with a total size of at least `max_bytes`
+    /// have been selected, or until `max_time` has passed, and
+    /// the remaining work is left for later calls. Files are
+    /// rewritten in groups of about `Config::target_file_size`,
+    /// and the time is only checked between groups, so either
+    /// limit may be exceeded by up to one group, and at least
+    /// one group is rewritten if any file is eligible, so that
+    /// every call makes progress. Returns a
+    /// `MaintenanceReport` describing the work done.
+    #[doc(alias = "incremental_maintenance")]
+    pub fn maintenance_budgeted(
+        &self,
+        max_bytes: u64,
+        max_time: Duration,
+    ) -> Result<MaintenanceReport, MarbleError> {
+        log::debug!("performing maintenance of at most {max_bytes} bytes in {max_time:?}");
+
+        let budget = Budget {
+            max_bytes,
+            deadline: Instant::now().checked_add(max_time),
+        };
+
+        self.check_writable()?;
+
+        let (files_to_defrag, claims) = self.file_map.files_to_defrag(&self.config, None)?;
+
+        self.defragment(
+            files_to_defrag,
+            claims,
+            self.config.min_compaction_files,
+            Some(budget),
+        )
+        .map_err(MarbleError::from)
+    }
 
-        let mut rewritten_objects = 0;
+    /// Runs `maintenance` repeatedly until
+    /// `Marble::space_amplification` is at or below `target`,
+    /// returning the combined `MaintenanceReport` of every
+    /// pass. This stops early once a pass makes no progress,
+    /// because it rewrote and removed nothing or did not
+    /// reduce space amplification, so the target may not be
+    /// reached, for instance if it is below what
+    /// `Config::file_compaction_percent` allows.
+    pub fn compact_until(&self, target: f64) -> Result<MaintenanceReport, MarbleError> {
+        let mut total = MaintenanceReport::default();
+
+        let mut space_amplification = self.space_amplification();
+
+        while space_amplification > target {
+            let report = self.maintenance()?;
+
+            total.files_rewritten += report.files_rewritten;
+            total.objects_rewritten += report.objects_rewritten;
+            total.files_removed += report.files_removed;
+            total.bytes_reclaimed += report.bytes_reclaimed;
+
+            let previous = space_amplification;
+            space_amplification = self.space_amplification();
+
+            if report == MaintenanceReport::default() || space_amplification >= previous {
+                log::debug!(
+                    "stopping compaction at space amplification {space_amplification}, which \
+                     maintenance made no progress on reducing to {target}"
+                );
+                break;
+            }
+        }
+
+        Ok(total)
+    }
+
+    /// Rewrites the live objects of the storage files at the
+    /// given locations, as reported by
+    /// `Marble::file_fragmentation`, and then removes them,
+    /// regardless of how fragmented they are. This allows
+    /// custom maintenance policies to be implemented outside
+    /// of `maintenance`. Objects that have been replaced
+    /// since the files were written are not rewritten.
+    /// Returns `MarbleError::NotFound` without rewriting
+    /// anything if one of the files no longer exists, or an
+    /// `Io` error of kind `WouldBlock` if one is concurrently
+    /// being written or rewritten. Returns a
+    /// `MaintenanceReport` describing the work done.
+    pub fn compact_files(
+        &self,
+        locations: &[DiskLocation],
+    ) -> Result<MaintenanceReport, MarbleError> {
+        log::debug!("compacting files {locations:?}");
+
+        self.check_writable()?;
+
+        let (files_to_defrag, claims) = self.file_map.claim_files(locations)?;
+
+        self.defragment(files_to_defrag, claims, 1, None)
+            .map_err(MarbleError::from)
+    }
+
+    fn maintenance_inner(&self, partition: Option<u8>) -> io::Result<MaintenanceReport> {
+        self.check_writable()?;
+
+        let (files_to_defrag, claims) = self.file_map.files_to_defrag(&self.config, partition)?;
+
+        self.defragment(
+            files_to_defrag,
+            claims,
+            self.config.min_compaction_files,
+            None,
+        )
+    }
+
+    fn defragment(
+        &self,
+        files_to_defrag: Map<u8, Vec<Arc<FileAndMetadata>>>,
+        claims: DeferUnclaim<'_>,
+        min_compaction_files: usize,
+        budget: Option<Budget>,
+    ) -> io::Result<MaintenanceReport> {
+        let mut groups = vec![];
 
-        // rewrite the live objects
         for (generation, file_to_defrag) in files_to_defrag {
             log::trace!(
                 "compacting files {:?} with generation {}",
@@ -32,155 +210,339 @@ impl Marble {
                 generation
             );
 
-            if file_to_defrag.len() < self.config.min_compaction_files {
+            if file_to_defrag.len() < min_compaction_files {
                 // skip batch with too few files (claims
                 // auto-released by Drop of DeferUnclaim
                 continue;
             }
 
-            let mut batch = Map::default();
-            let mut rewritten_fam_locations = vec![];
+            groups.push((generation, file_to_defrag));
+        }
 
-            for fam in file_to_defrag {
-                log::trace!(
-                    "rewriting any surviving objects in file at location {:?}",
-                    fam.location
-                );
-                rewritten_fam_locations.push(fam.location);
-                let metadata: &crate::Metadata = fam
-                    .metadata()
-                    .expect("anything being defragged should have metadata already set");
+        if let Some(budget) = budget {
+            // files that are left out stay claimed until the
+            // claims are dropped below
+            groups = budgeted_groups(
+                groups,
+                budget.max_bytes,
+                self.config.target_file_size as u64,
+            );
+        }
+
+        let threads = self.config.compaction_threads;
+
+        if threads > 1 {
+            // split the files of each generation between the
+            // threads, so that even a single generation is
+            // rewritten in parallel. each chunk keeps at least
+            // `min_compaction_files` files, so that small files
+            // are still merged into fewer files rather than each
+            // being rewritten on its own.
+            let min_chunk_len = min_compaction_files.max(1);
+            groups = groups
+                .into_iter()
+                .flat_map(|(generation, files)| {
+                    let chunks = threads.min(files.len() / min_chunk_len).max(1);
+                    let chunk_size = files.len().div_ceil(chunks);
+                    files
+                        .chunks(chunk_size)
+                        .map(|chunk| (generation, chunk.to_vec()))
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+        }
 
-                let path: &std::path::PathBuf = fam.path().unwrap();
+        // rewrite the live objects, with each thread taking the
+        // next group of files until none are left
+        let next_group = AtomicUsize::new(0);
+
+        let rewrite_groups = || -> io::Result<Rewritten> {
+            // reuse the old_locations allocation across groups
+            // to avoid resizing as often.
+            let mut old_locations: Map<ObjectId, DiskLocation> = Map::default();
+            let mut rewritten = Rewritten::default();
+
+            while let Some((generation, files)) = groups.get(next_group.fetch_add(1, SeqCst)) {
+                let deadline = budget.and_then(|budget| budget.deadline);
+                let out_of_time = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                if out_of_time && rewritten.files > 0 {
+                    log::debug!("stopping maintenance after running out of time");
+                    break;
+                }
 
-                // TODO handle trailer read using full buf
-                let file_buf = read_range_at(&fam.file, 0, metadata.file_size)?;
+                let group = self.rewrite_files(*generation, files, &mut old_locations)?;
 
-                let (trailer, zstd_dict) = read_trailer_from_buf(
-                    &file_buf[usize::try_from(metadata.trailer_offset).unwrap()..],
-                )?;
+                rewritten.objects += group.objects;
+                rewritten.files += group.files;
+                rewritten.bytes += group.bytes;
+                rewritten.written_file_bytes += group.written_file_bytes;
+            }
 
-                let mut buf_reader = std::io::Cursor::new(file_buf);
+            Ok(rewritten)
+        };
 
-                let mut offset = 0_u64;
+        let results: Vec<io::Result<Rewritten>> = if threads > 1 && groups.len() > 1 {
+            std::thread::scope(|scope| {
+                let workers: Vec<_> = (0..threads.min(groups.len()))
+                    .map(|_| scope.spawn(rewrite_groups))
+                    .collect();
 
-                while offset < metadata.trailer_offset {
-                    let mut header = [0_u8; HEADER_LEN];
-                    buf_reader.read_exact(&mut header)?;
+                workers
+                    .into_iter()
+                    .map(|worker| worker.join().unwrap())
+                    .collect()
+            })
+        } else {
+            vec![rewrite_groups()]
+        };
 
-                    let crc_expected: [u8; 4] = header[0..4].try_into().unwrap();
-                    let pid_buf = header[4..12].try_into().unwrap();
-                    let object_id = u64::from_le_bytes(pid_buf);
-                    let len_buf = header[12..20].try_into().unwrap();
-                    let len = usize::try_from(u64::from_le_bytes(len_buf)).unwrap();
+        let mut rewritten_objects = 0;
+        let mut rewritten_files = 0;
+        let mut rewritten_bytes = 0;
+        let mut written_file_bytes = 0;
 
-                    if len >= self.config.max_object_size {
-                        log::warn!("corrupt object size detected: {} bytes", len);
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "corrupt object size or configured max_object_size has gone down \
-                             since this object was written",
-                        ));
-                    }
+        for result in results {
+            let rewritten = result?;
 
-                    let current_location = self
-                        .location_table
-                        .load(object_id)
-                        .expect("anything being rewritten should exist in the location table");
+            rewritten_objects += rewritten.objects;
+            rewritten_files += rewritten.files;
+            rewritten_bytes += rewritten.bytes;
+            written_file_bytes += rewritten.written_file_bytes;
+        }
 
-                    let rewritten_location =
-                        RelativeDiskLocation::new(offset, false).to_absolute(fam.location.lsn());
+        drop(claims);
 
-                    // all objects present before the trailer are not deletes
-                    let mut object_buf = uninit_boxed_slice(len);
+        if let Some(metrics) = &self.config.metrics {
+            if rewritten_files > 0 {
+                metrics.on_compaction(rewritten_files, rewritten_bytes);
+            }
+        }
 
-                    buf_reader.read_exact(&mut object_buf)?;
+        let (removed_files, removed_bytes) = self.prune_empty_files()?;
 
-                    let crc_actual = hash(len_buf, pid_buf, &object_buf);
+        Ok(MaintenanceReport {
+            files_rewritten: rewritten_files,
+            objects_rewritten: rewritten_objects,
+            files_removed: removed_files,
+            bytes_reclaimed: removed_bytes.saturating_sub(written_file_bytes),
+        })
+    }
 
-                    if crc_expected != crc_actual {
-                        log::error!(
-                            "crc mismatch when reading object {} at offset {} in file {:?} - \
-                             expected {:?} actual {:?}",
-                            object_id,
-                            offset,
-                            path,
-                            crc_expected,
-                            crc_actual,
-                        );
-                        return Err(annotate!(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "crc mismatch in maintenance routine",
-                        )));
-                    }
+    /// Rewrites the live objects of a group of files with the
+    /// same generation into new files.
+    fn rewrite_files(
+        &self,
+        generation: u8,
+        files: &[Arc<FileAndMetadata>],
+        old_locations: &mut Map<ObjectId, DiskLocation>,
+    ) -> io::Result<Rewritten> {
+        let mut rewritten_bytes = 0;
+
+        let mut batch = Map::default();
+        let mut rewritten_fam_locations = vec![];
 
+        for fam in files {
+            log::trace!(
+                "rewriting any surviving objects in file at location {:?}",
+                fam.location
+            );
+            rewritten_fam_locations.push(fam.location);
+            let metadata: &crate::Metadata = fam
+                .metadata()
+                .expect("anything being defragged should have metadata already set");
+
+            let path: &std::path::PathBuf = fam.path().unwrap();
+
+            // TODO handle trailer read using full buf
+            let file_buf = read_range_at(&*fam.file()?, 0, metadata.file_size)?;
+
+            let (trailer, zstd_dict) = read_trailer_from_buf(
+                &file_buf[usize::try_from(metadata.trailer_offset).unwrap()..],
+            )?;
+
+            let object_section = &file_buf[..usize::try_from(metadata.trailer_offset).unwrap()];
+
+            log::trace!(
+                "read trailer for lsn {} at offset {}",
+                fam.location.lsn(),
+                metadata.trailer_offset,
+            );
+
+            // records are found through the trailer rather than
+            // by scanning the file from the start, because they
+            // may be separated by padding for
+            // `Config::record_alignment`
+            for (object_id, relative_location) in trailer {
+                let rewritten_location = relative_location.to_absolute(fam.location.lsn());
+                let current_location = self
+                    .location_table
+                    .load(object_id)
+                    .expect("anything being rewritten should exist in the location table");
+
+                if relative_location.is_delete() {
                     if rewritten_location == current_location {
                         // can attempt to rewrite
                         log::trace!(
                             "rewriting object {object_id} at rewritten location \
                              {rewritten_location:?}"
                         );
-                        batch.insert(object_id, Some(zstd_dict.decompress(object_buf)));
+                        batch.insert(object_id, None);
                         old_locations.insert(object_id, rewritten_location);
                     } else {
                         log::trace!(
-                            "not rewriting object {object_id}, as the location being defragmented \
-                             {rewritten_location:?} does not match the current location in the \
-                             location table {current_location:?}"
+                            "not rewriting object {object_id}, as the location being \
+                             defragmented {rewritten_location:?} does not match the current \
+                             location in the location table {current_location:?}"
                         );
                     }
+                    continue;
+                }
 
-                    offset += (HEADER_LEN + len) as u64;
+                let offset = rewritten_location.lsn() - fam.location.lsn();
+                let record = usize::try_from(offset)
+                    .ok()
+                    .and_then(|offset| object_section.get(offset..))
+                    .filter(|record| record.len() >= HEADER_LEN)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "corrupt trailer entry points past the object data",
+                        )
+                    })?;
+
+                let crc_expected: [u8; 4] = record[0..4].try_into().unwrap();
+                let pid_buf = record[4..12].try_into().unwrap();
+                let len_buf = record[12..20].try_into().unwrap();
+                // an unknown record version is reported as a
+                // corrupt size below
+                let len = decode_record_len(len_buf)
+                    .and_then(|len| usize::try_from(len).ok())
+                    .unwrap_or(usize::MAX);
+
+                if len >= self.config.max_object_size || len > record.len() - HEADER_LEN {
+                    log::warn!("corrupt object size detected: {} bytes", len);
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "corrupt object size or configured max_object_size has gone down \
+                         since this object was written",
+                    ));
                 }
 
-                log::trace!(
-                    "trying to read trailer at file for lsn {} offset {} items",
-                    metadata.trailer_offset,
-                    fam.location.lsn(),
-                );
+                // all objects present before the trailer are not deletes
+                let object_buf: Box<[u8]> = Box::from(&record[HEADER_LEN..HEADER_LEN + len]);
+
+                let crc_actual = hash(len_buf, pid_buf, &object_buf);
+
+                if crc_expected != crc_actual {
+                    log::error!(
+                        "crc mismatch when reading object {} at offset {} in file {:?} - \
+                         expected {:?} actual {:?}",
+                        object_id,
+                        offset,
+                        path,
+                        crc_expected,
+                        crc_actual,
+                    );
+                    return Err(annotate!(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "crc mismatch in maintenance routine",
+                    )));
+                }
 
-                for (object_id, relative_location) in trailer {
-                    if relative_location.is_delete() {
-                        let rewritten_location = relative_location.to_absolute(fam.location.lsn());
-                        let current_location = self
-                            .location_table
-                            .load(object_id)
-                            .expect("anything being rewritten should exist in the location table");
-
-                        if rewritten_location == current_location {
-                            // can attempt to rewrite
-                            log::trace!(
-                                "rewriting object {object_id} at rewritten location \
-                                 {rewritten_location:?}"
-                            );
-                            batch.insert(object_id, None);
-                            old_locations.insert(object_id, rewritten_location);
-                        } else {
-                            log::trace!(
-                                "not rewriting object {object_id}, as the location being \
-                                 defragmented {rewritten_location:?} does not match the current \
-                                 location in the location table {current_location:?}"
-                            );
-                        }
-                    }
+                if rewritten_location == current_location {
+                    // can attempt to rewrite
+                    log::trace!(
+                        "rewriting object {object_id} at rewritten location \
+                         {rewritten_location:?}"
+                    );
+                    let data = zstd_dict.decompress(object_buf);
+                    rewritten_bytes += data.len() as u64;
+                    // metadata is rewritten as part of the data
+                    let data = if record_has_metadata(len_buf) {
+                        BatchData::ObjectWithMetadata(data)
+                    } else {
+                        BatchData::Object(data)
+                    };
+                    batch.insert(object_id, Some(data));
+                    old_locations.insert(object_id, rewritten_location);
+                } else {
+                    log::trace!(
+                        "not rewriting object {object_id}, as the location being defragmented \
+                         {rewritten_location:?} does not match the current location in the \
+                         location table {current_location:?}"
+                    );
                 }
             }
+        }
 
-            rewritten_objects += batch.len();
+        let rewritten_objects = batch.len();
 
-            log::trace!("{rewritten_objects}, {}", batch.len());
+        log::trace!("{rewritten_objects}, {}", batch.len());
 
-            self.shard_batch(batch, generation, &old_locations)?;
-            old_locations.clear();
+        let written_file_bytes = self.shard_batch(batch, generation, old_locations, None, None)?;
+        old_locations.clear();
 
-            self.file_map
-                .verify_files_uninhabited(&rewritten_fam_locations, &self.location_table);
-        }
+        self.file_map
+            .verify_files_uninhabited(&rewritten_fam_locations, &self.location_table);
 
-        drop(claims);
+        Ok(Rewritten {
+            objects: rewritten_objects,
+            files: rewritten_fam_locations.len(),
+            bytes: rewritten_bytes,
+            written_file_bytes,
+        })
+    }
+}
 
-        self.prune_empty_files()?;
+/// Selects the most fragmented files of `groups` until their
+/// total size reaches `max_bytes`, and splits them into
+/// groups of files of the same generation with a total size
+/// of about `max_group_bytes` each.
+fn budgeted_groups(
+    groups: Vec<(u8, Vec<Arc<FileAndMetadata>>)>,
+    max_bytes: u64,
+    max_group_bytes: u64,
+) -> Vec<(u8, Vec<Arc<FileAndMetadata>>)> {
+    let file_size = |fam: &FileAndMetadata| fam.metadata().map_or(0, |metadata| metadata.file_size);
+
+    let mut files: Vec<(u8, Arc<FileAndMetadata>)> = groups
+        .into_iter()
+        .flat_map(|(generation, files)| files.into_iter().map(move |fam| (generation, fam)))
+        .collect();
+
+    files.sort_by_key(|(_, fam)| {
+        let live_objects = fam.live_objects.load(SeqCst);
+        let present_objects = fam
+            .metadata()
+            .map_or(1, |metadata| metadata.present_objects);
+        (live_objects * 1000) / present_objects.max(1)
+    });
+
+    let mut selected: BTreeMap<u8, Vec<Arc<FileAndMetadata>>> = BTreeMap::new();
+    let mut selected_bytes = 0;
+    for (generation, fam) in files {
+        if selected_bytes >= max_bytes && !selected.is_empty() {
+            break;
+        }
+        selected_bytes += file_size(&fam);
+        selected.entry(generation).or_default().push(fam);
+    }
 
-        Ok(rewritten_objects)
+    let mut groups = vec![];
+    for (generation, files) in selected {
+        let mut group = vec![];
+        let mut group_bytes = 0;
+        for fam in files {
+            let size = file_size(&fam);
+            if !group.is_empty() && group_bytes + size > max_group_bytes {
+                groups.push((generation, std::mem::take(&mut group)));
+                group_bytes = 0;
+            }
+            group_bytes += size;
+            group.push(fam);
+        }
+        groups.push((generation, group));
     }
+
+    groups
 }
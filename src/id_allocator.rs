@@ -0,0 +1,248 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::Ordering::Acquire;
+
+use fault_injection::{annotate, fallible};
+
+use crate::{sync_directory, Marble, MarbleError, ObjectId, MAX_OBJECT_ID};
+
+const LEASE_FILE: &str = "object_id_lease";
+const LEASE_TMP_FILE: &str = "object_id_lease-tmp";
+
+/// The number of object IDs that are reserved for
+/// allocation each time that the lease file is written.
+/// IDs that were reserved but not allocated before a
+/// restart are skipped, so that they are never reused.
+const LEASE_SIZE: u64 = 1024;
+
+// Lease file format:
+//
+// lease | crc
+//
+// where lease is a little-endian u64 that every allocated
+// object ID is below, and crc is a crc32 of lease.
+
+/// The state of `Marble::allocate_object_id`, which is
+/// loaded from the lease file the first time that it is
+/// needed.
+#[derive(Debug, Default)]
+pub(crate) struct IdLease {
+    next: ObjectId,
+    limit: ObjectId,
+    // the IDs of deleted objects that may be reused, if
+    // `Config::reuse_object_ids` is set
+    free: BTreeSet<ObjectId>,
+}
+
+impl Marble {
+    /// Allocates a new object ID that has never been returned
+    /// by this method before, even across restarts, for users
+    /// that do not manage object IDs themselves.
+    ///
+    /// Allocated IDs are always greater than the ID of every
+    /// object that has been written, and than every ID that
+    /// was allocated before, so they never collide with
+    /// objects written with IDs chosen by the user, as long
+    /// as the user only chooses IDs that are below the IDs
+    /// allocated so far. IDs are allocated in increasing
+    /// order, but some IDs are skipped after a restart.
+    /// Until an object is written with an allocated ID, it is
+    /// not reported by `allocated_object_ids`.
+    ///
+    /// The allocation state is persisted in a small file in
+    /// `Config::path` once for every 1024 IDs. Returns an
+    /// `Io` error if there are no more IDs to allocate below
+    /// [`crate::MAX_OBJECT_ID`].
+    ///
+    /// If `Config::reuse_object_ids` is set, the IDs of
+    /// objects that were deleted by `write_batch` are
+    /// returned first, lowest first, and new IDs are only
+    /// allocated once none are left. Deletions are stored
+    /// like any other write, so the reusable IDs are found
+    /// again after a restart. A reused ID is returned again
+    /// after a restart if no object was written with it, and
+    /// it is only returned while its object is still
+    /// deleted. Reading a reused ID never returns the data
+    /// of the deleted object.
+    #[doc(alias = "allocate_id")]
+    pub fn allocate_object_id(&self) -> Result<ObjectId, MarbleError> {
+        self.check_writable()?;
+
+        let mut lease_opt = self.id_lease.lock().unwrap();
+        let lease = self.load_lease(&mut lease_opt)?;
+
+        while let Some(object_id) = lease.free.pop_first() {
+            // the object may have been written again since it
+            // was deleted
+            if self.is_deleted(object_id) {
+                return Ok(object_id);
+            }
+        }
+
+        let max_object_id = self.max_object_id.load(Acquire);
+        let object_id = lease.next.max(max_object_id.saturating_add(1));
+
+        if object_id > MAX_OBJECT_ID {
+            return Err(io::Error::other(format!(
+                "no object IDs are left to allocate below the maximum of {MAX_OBJECT_ID}"
+            ))
+            .into());
+        }
+
+        if object_id >= lease.limit {
+            let limit = object_id.saturating_add(LEASE_SIZE);
+            write_lease(&self.config.path, limit)?;
+            lease.limit = limit;
+        }
+
+        lease.next = object_id + 1;
+
+        Ok(object_id)
+    }
+
+    /// Allocates `count` consecutive object IDs at once, which
+    /// are never returned by this method or by
+    /// `allocate_object_id` again, even across restarts. This
+    /// allows, for instance, several components of an
+    /// application to each own a range of IDs that they write
+    /// without coordinating with each other.
+    ///
+    /// Like those returned by `allocate_object_id`, the range
+    /// starts above the ID of every object that has been
+    /// written, and every ID that was allocated before. IDs
+    /// of deleted objects are never reused for a range. The
+    /// end of the range is persisted before it is returned.
+    /// Returns an `Io` error if fewer than `count` IDs are
+    /// left below [`crate::MAX_OBJECT_ID`].
+    #[doc(alias = "reserve_pages")]
+    #[doc(alias = "allocate_object_ids")]
+    pub fn reserve_object_ids(&self, count: u64) -> Result<Range<ObjectId>, MarbleError> {
+        self.check_writable()?;
+
+        let mut lease_opt = self.id_lease.lock().unwrap();
+        let lease = self.load_lease(&mut lease_opt)?;
+
+        let max_object_id = self.max_object_id.load(Acquire);
+        let start = lease.next.max(max_object_id.saturating_add(1));
+
+        let end_opt = start
+            .checked_add(count)
+            .filter(|end| *end <= MAX_OBJECT_ID + 1);
+        let end = if let Some(end) = end_opt {
+            end
+        } else {
+            return Err(io::Error::other(format!(
+                "fewer than {count} object IDs are left to allocate below the maximum of \
+                 {MAX_OBJECT_ID}"
+            ))
+            .into());
+        };
+
+        if end > lease.limit {
+            let limit = end.saturating_add(LEASE_SIZE);
+            write_lease(&self.config.path, limit)?;
+            lease.limit = limit;
+        }
+
+        lease.next = end;
+
+        Ok(start..end)
+    }
+}
+
+impl Marble {
+    /// Returns the state of `allocate_object_id`, reading the
+    /// lease file if it was not read yet.
+    fn load_lease<'a>(&self, lease_opt: &'a mut Option<IdLease>) -> io::Result<&'a mut IdLease> {
+        if lease_opt.is_none() {
+            let limit = read_lease(&self.config.path)?;
+            let free = if self.config.reuse_object_ids {
+                self.deleted_object_ids()
+            } else {
+                BTreeSet::new()
+            };
+            *lease_opt = Some(IdLease {
+                next: limit,
+                limit,
+                free,
+            });
+        }
+
+        Ok(lease_opt.as_mut().unwrap())
+    }
+
+    /// Makes the IDs of objects deleted by a write batch
+    /// available to `allocate_object_id`, if
+    /// `Config::reuse_object_ids` is set and allocation has
+    /// already started. Otherwise, they are found by scanning
+    /// the location table when it starts.
+    pub(crate) fn free_object_ids_for_reuse(&self, deleted: Vec<ObjectId>) {
+        if let Some(lease) = &mut *self.id_lease.lock().unwrap() {
+            lease.free.extend(deleted);
+        }
+    }
+
+    fn deleted_object_ids(&self) -> BTreeSet<ObjectId> {
+        let max = self.max_object_id.load(Acquire);
+
+        (0..=max)
+            .filter(|object_id| self.is_deleted(*object_id))
+            .collect()
+    }
+
+    fn is_deleted(&self, object_id: ObjectId) -> bool {
+        self.location_table
+            .load(object_id)
+            .is_some_and(|location| location.is_delete())
+    }
+}
+
+fn read_lease(path: &Path) -> io::Result<ObjectId> {
+    let mut file = match File::open(path.join(LEASE_FILE)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(annotate!(e)),
+    };
+
+    let mut buf = [0_u8; 12];
+    fallible!(file.read_exact(&mut buf));
+
+    let lease_buf: [u8; 8] = buf[..8].try_into().unwrap();
+    let crc_expected: [u8; 4] = buf[8..].try_into().unwrap();
+
+    if crc32fast::hash(&lease_buf).to_le_bytes() != crc_expected {
+        return Err(annotate!(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "crc mismatch in object ID lease file",
+        )));
+    }
+
+    Ok(u64::from_le_bytes(lease_buf))
+}
+
+/// Atomically replaces the lease file, so that a crash
+/// leaves either the old or the new lease.
+fn write_lease(path: &Path, lease: ObjectId) -> io::Result<()> {
+    let lease_buf = lease.to_le_bytes();
+    let crc = crc32fast::hash(&lease_buf).to_le_bytes();
+
+    let tmp_path = path.join(LEASE_TMP_FILE);
+
+    let mut file = fallible!(OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path));
+    fallible!(file.write_all(&lease_buf));
+    fallible!(file.write_all(&crc));
+    fallible!(file.sync_all());
+    drop(file);
+
+    fallible!(fs::rename(&tmp_path, path.join(LEASE_FILE)));
+
+    let directory = fallible!(File::open(path));
+    sync_directory(&directory)
+}
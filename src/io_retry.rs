@@ -0,0 +1,53 @@
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use fault_injection::maybe;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(1);
+const MAX_BACKOFF: Duration = Duration::from_millis(64);
+
+/// Runs an IO operation, retrying it up to `retries` times
+/// with exponential backoff while it fails with an error
+/// that may succeed when attempted again, such as `EINTR`,
+/// `EAGAIN` or `EBUSY` on a network filesystem. Other errors,
+/// like running out of space, are returned immediately.
+///
+/// `op` must be safe to repeat after it fails, so this is
+/// only used for positional reads, opens, syncs and renames,
+/// and not for writes through a `BufWriter`, which may have
+/// partially completed.
+pub(crate) fn retry_io<T>(retries: u32, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut backoff = INITIAL_BACKOFF;
+
+    for _ in 0..retries {
+        match attempt(&mut op) {
+            Err(e) if is_transient(&e) => {
+                log::debug!("retrying IO operation in {backoff:?} after transient error: {e:?}");
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            res => return res,
+        }
+    }
+
+    attempt(&mut op)
+}
+
+fn attempt<T>(op: &mut impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    // a fault injected here fails the attempt like a transient
+    // error would, so `Config::io_retries` can be tested with
+    // `FAULT_INJECT_COUNTER`
+    if let Err(e) = maybe!(Ok::<(), io::Error>(())) {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, e));
+    }
+
+    op()
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::ResourceBusy
+    )
+}
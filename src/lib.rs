@@ -7,8 +7,10 @@
 //! At a high-level, it supports atomic batch writes and
 //! single-object reads. Garbage collection is manual.
 //! All operations are blocking. Nothing is cached
-//! in-memory except for zstd dictionaries and file
-//! handles to all storage files. Objects may be
+//! in-memory except for zstd dictionaries, file
+//! handles to all storage files, and objects read
+//! through `Marble::read_cached` if a cache size is
+//! set with `Config::cache_bytes`. Objects may be
 //! sharded upon GC by providing a custom
 //! `Config::partition_function`. Partitioning
 //! is not performed on the write batch when it
@@ -63,7 +65,8 @@
 //! // after a few more batches that may have caused fragmentation
 //! // by overwriting previous objects, perform maintenance which
 //! // will defragment the object store based on `Config` settings.
-//! let objects_defragmented = marble.maintenance().unwrap();
+//! let report = marble.maintenance().unwrap();
+//! dbg!(report.objects_rewritten);
 //!
 //! // print out system statistics
 //! dbg!(marble.stats());
@@ -118,7 +121,7 @@
 //! // This function shards objects into partitions
 //! // similarly to a slab allocator that groups objects
 //! // into size buckets based on powers of two.
-//! fn shard_by_size(object_id: u64, object_size: usize) -> u8 {
+//! fn shard_by_size(object_id: u64, object_size: usize, generation: u8) -> u8 {
 //!     let next_po2 = object_size.next_power_of_two();
 //!     u8::try_from(next_po2.trailing_zeros()).unwrap()
 //! }
@@ -141,10 +144,10 @@ use std::sync::{
         AtomicBool, AtomicPtr, AtomicU64,
         Ordering::{Acquire, SeqCst},
     },
-    Arc,
+    Arc, Condvar, Mutex, RwLock,
 };
 
-use fault_injection::fallible;
+use fault_injection::{annotate, fallible, maybe};
 
 #[derive(Clone, Copy)]
 pub struct LocationHasher(u64);
@@ -180,38 +183,101 @@ impl std::hash::Hasher for LocationHasher {
 
 type Map<K, V> = std::collections::HashMap<K, V, std::hash::BuildHasherDefault<LocationHasher>>;
 
+#[cfg(feature = "async")]
+mod async_marble;
+mod batch;
+mod cache;
+mod checkpoint;
 mod config;
 mod debug_delay;
 #[cfg(feature = "runtime_validation")]
 mod debug_history;
 mod disk_location;
+mod error;
+mod export;
+mod file_handles;
 mod file_map;
 mod gc;
+mod id_allocator;
+mod io_retry;
 mod location_table;
+mod metrics;
 mod readpath;
 mod recovery;
+mod ref_count;
+mod snapshot;
 mod trailer;
+mod verify;
 mod writepath;
 mod zstd;
 
-pub use config::Config;
+#[cfg(feature = "async")]
+pub use async_marble::AsyncMarble;
+pub use batch::Batch;
+use cache::ObjectCache;
+pub use checkpoint::Checkpoint;
+pub use config::{Backpressure, Config, RecoveryPolicy, SyncMode};
 use debug_delay::debug_delay;
-use disk_location::{DiskLocation, RelativeDiskLocation};
+pub use disk_location::DiskLocation;
+use disk_location::RelativeDiskLocation;
+pub use error::MarbleError;
+pub use export::import;
+use file_handles::{FileHandle, OpenFiles};
 use file_map::FileMap;
+pub use gc::MaintenanceReport;
+use io_retry::retry_io;
 use location_table::LocationTable;
-use trailer::{read_trailer, read_trailer_from_buf, write_trailer};
+pub use metrics::{MarbleMetrics, RecoveryEvent};
+pub use readpath::BufferProvider;
+pub use snapshot::Snapshot;
+use trailer::{read_trailer, read_trailer_from_buf, write_trailer, TRAILER_MIN_LEN};
+pub use verify::{RepairReport, VerifyReport};
 use zstd::ZstdDict;
 
 const HEADER_LEN: usize = 20;
+/// The format version of object records, which is stored in
+/// the most significant byte of the length field of their
+/// header. Records written before the format was versioned
+/// have version 0, because no object is large enough to
+/// set that byte.
+const RECORD_VERSION: u8 = 1;
+/// The format version of records whose data begins with the
+/// `ObjectMetadata` passed to
+/// `Marble::write_batch_with_metadata`.
+const METADATA_RECORD_VERSION: u8 = 2;
 const NEW_WRITE_BATCH_BIT: u64 = 1 << 62;
 const NEW_WRITE_BATCH_MASK: u64 = u64::MAX - NEW_WRITE_BATCH_BIT;
 
-type ObjectId = u64;
+/// The identifier that objects are stored under. Any `u64`
+/// up to and including [`MAX_OBJECT_ID`] may be used.
+pub type ObjectId = u64;
+
+/// The highest object ID that may be written. `u64::MAX` is
+/// reserved for internal use, and write batches that contain
+/// it will be rejected.
+pub const MAX_OBJECT_ID: ObjectId = RESERVED_OBJECT_ID - 1;
+
+const RESERVED_OBJECT_ID: ObjectId = u64::MAX;
+
+/// A few bytes of application-defined metadata, such as a
+/// type tag or version, that may be stored along with an
+/// object by [`Marble::write_batch_with_metadata`].
+pub type ObjectMetadata = [u8; OBJECT_METADATA_LEN];
+
+/// The size of [`ObjectMetadata`].
+pub const OBJECT_METADATA_LEN: usize = 8;
 
 fn read_range_at(file: &File, start: u64, end: u64) -> io::Result<Vec<u8>> {
     use std::os::unix::fs::FileExt;
 
-    let buf_sz: usize = (end - start).try_into().unwrap();
+    let buf_sz: usize = if let Some(buf_sz) = end.checked_sub(start) {
+        buf_sz.try_into().unwrap()
+    } else {
+        return Err(annotate!(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("attempted to read range {start}..{end}, which ends before it starts"),
+        )));
+    };
 
     let mut buf = Vec::with_capacity(buf_sz);
 
@@ -225,17 +291,61 @@ fn read_range_at(file: &File, start: u64, end: u64) -> io::Result<Vec<u8>> {
 }
 
 fn uninit_boxed_slice(len: usize) -> Box<[u8]> {
-    use std::alloc::{alloc, Layout};
+    use std::alloc::{alloc, handle_alloc_error, Layout};
+
+    // `alloc` must not be called with a zero-sized layout
+    if len == 0 {
+        return Box::default();
+    }
 
     let layout = Layout::array::<u8>(len).unwrap();
 
     unsafe {
         let ptr = alloc(layout);
+        if ptr.is_null() {
+            handle_alloc_error(layout);
+        }
         let slice = std::slice::from_raw_parts_mut(ptr, len);
         Box::from_raw(slice)
     }
 }
 
+/// Encodes the length field of a record header for an object
+/// of `len` bytes, tagged with `version`, which is either
+/// `RECORD_VERSION` or `METADATA_RECORD_VERSION`.
+fn encode_record_len(len: usize, version: u8) -> [u8; 8] {
+    let mut len_buf = (len as u64).to_le_bytes();
+    assert_eq!(
+        len_buf[7], 0,
+        "object of {len} bytes is too large to be stored"
+    );
+    len_buf[7] = version;
+    len_buf
+}
+
+/// Decodes the length field of a record header, returning
+/// `None` if the record has an unknown format version, which
+/// is also how corrupt lengths usually appear.
+fn decode_record_len(len_buf: [u8; 8]) -> Option<u64> {
+    match len_buf[7] {
+        // version 1 only differs from version 0 in being
+        // tagged with its version, and version 2 records store
+        // their metadata as part of their data
+        0..=2 => {
+            let mut len_buf = len_buf;
+            len_buf[7] = 0;
+            Some(u64::from_le_bytes(len_buf))
+        }
+        _ => None,
+    }
+}
+
+/// Returns whether the data of a record begins with
+/// `ObjectMetadata`, based on the length field of its header.
+fn record_has_metadata(len_buf: [u8; 8]) -> bool {
+    len_buf[7] == METADATA_RECORD_VERSION
+}
+
 fn hash(len_buf: [u8; 8], pid_buf: [u8; 8], object_buf: &[u8]) -> [u8; 4] {
     let mut hasher = crc32fast::Hasher::new();
     hasher.update(&len_buf);
@@ -267,6 +377,14 @@ pub struct Stats {
     pub files: usize,
     /// The sum of the sizes of all files currently on-disk.
     pub total_file_size: u64,
+    /// The sum of the sizes of the trailers of all files
+    /// currently on-disk, included in `total_file_size`.
+    /// Trailers record the location of every object written
+    /// to a file, including objects that were later replaced
+    /// or removed, and are read at recovery to rebuild the
+    /// location table. Calls to `maintenance` that
+    /// defragment storage files shrink them.
+    pub total_trailer_size: u64,
     /// The number of compressed bytes that have been written due
     /// to calls to both `write_batch` and rewrites caused by
     /// calls to `maintenance` since this instance of `Marble` was recovered.
@@ -285,6 +403,18 @@ pub struct Stats {
     /// calls to [`crate::Marble::write_batch`] since this instance
     /// was recovered.
     pub high_level_user_bytes_written: u64,
+    /// The number of objects, including deletions, that have
+    /// been written by write batches since this instance was
+    /// recovered, not counting rewrites by `maintenance`.
+    pub objects_written: u64,
+    /// The number of write batches that have been written
+    /// since this instance was recovered. Each file written by
+    /// [`crate::Marble::bulk_load`] counts as one batch.
+    pub write_batches: u64,
+    /// How long the most recent write batch took to write, in
+    /// nanoseconds, including any fsyncs, or 0 if none has
+    /// been written since this instance was recovered.
+    pub last_write_batch_nanos: u64,
     /// Compression ratio for read objects since this `Marble` instance was recovered. 1.0 means no compression, 2.0 means that we saved 50% space by compressing, etc...
     pub read_compression_ratio: f32,
     /// Compression ratio for objects written since this `Marble` instance was recovered. 1.0 means no compression, 2.0 means that we saved 50% space by compressing, etc...
@@ -305,12 +435,46 @@ pub struct Stats {
     pub space_amplification: f32,
 }
 
+/// The fragmentation of a single storage file, as reported
+/// by [`Marble::file_fragmentation`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileFragmentation {
+    /// The location of the file.
+    pub location: DiskLocation,
+    /// The path of the file.
+    pub path: PathBuf,
+    /// The shard that the file belongs to. Files created by
+    /// `write_batch` belong to shard 0, and files rewritten
+    /// by maintenance belong to the partition assigned by
+    /// `Config::partition_function`.
+    pub shard: u8,
+    /// The number of times that the objects in this file
+    /// have survived being rewritten by maintenance, up to
+    /// a maximum of 3.
+    pub generation: u8,
+    /// The size of the file.
+    pub file_size: u64,
+    /// The number of objects originally written to the file.
+    pub stored_objects: u64,
+    /// The number of objects in the file that have not been
+    /// replaced or removed in other files.
+    pub live_objects: u64,
+    /// The percentage of `stored_objects` that are still
+    /// live, comparable to `Config::file_compaction_percent`.
+    pub live_percent: u8,
+}
+
 #[derive(Default, Debug, Clone, Copy)]
 struct Metadata {
     lsn: u64,
     trailer_offset: u64,
+    // the number of objects that the file was written with,
+    // counting deletions, in the same unit as `live_objects`
+    // of `FileAndMetadata`, so that the two may be compared
+    // to find the live percent of the file
     present_objects: u64,
     generation: u8,
+    partition: u8,
     file_size: u64,
 }
 
@@ -323,14 +487,20 @@ impl Metadata {
             trailer_offset: u64::from_str_radix(&splits.next()?, 16).ok()?,
             present_objects: u64::from_str_radix(&splits.next()?, 16).ok()?,
             generation: u8::from_str_radix(splits.next()?, 16).ok()?,
+            // files written before partitions were recorded
+            // in file names belong to partition 0
+            partition: match splits.next() {
+                Some(partition) => u8::from_str_radix(partition, 16).ok()?,
+                None => 0,
+            },
             file_size,
         })
     }
 
     fn to_file_name(&self) -> String {
         let ret = format!(
-            "{:016x}-{:016x}-{:016x}-{:01x}",
-            self.lsn, self.trailer_offset, self.present_objects, self.generation
+            "{:016x}-{:016x}-{:016x}-{:01x}-{:02x}",
+            self.lsn, self.trailer_offset, self.present_objects, self.generation, self.partition
         );
         ret
     }
@@ -338,23 +508,33 @@ impl Metadata {
 
 #[derive(Debug)]
 struct FileAndMetadata {
-    file: File,
+    handle: FileHandle,
     location: DiskLocation,
     path: AtomicPtr<PathBuf>,
     metadata: AtomicPtr<Metadata>,
+    // the number of objects and deletions in this file that
+    // the location table still points to, regardless of
+    // their size
     live_objects: AtomicU64,
     generation: u8,
+    partition: u8,
     rewrite_claim: AtomicBool,
     synced: AtomicBool,
+    // files of read-only instances are never removed
+    read_only: bool,
     zstd_dict: ZstdDict,
 }
 
 impl Drop for FileAndMetadata {
     fn drop(&mut self) {
         let empty = self.live_objects.load(Acquire) == 0;
-        if empty {
-            if let Err(e) = std::fs::remove_file(self.path().unwrap()) {
-                eprintln!("failed to remove empty FileAndMetadata on drop: {:?}", e);
+        if empty && !self.read_only {
+            match std::fs::remove_file(self.path().unwrap()) {
+                // the whole directory of a temporary store
+                // may have been removed already
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => eprintln!("failed to remove empty FileAndMetadata on drop: {:?}", e),
+                Ok(()) => {}
             }
         }
 
@@ -370,6 +550,32 @@ impl Drop for FileAndMetadata {
 }
 
 impl FileAndMetadata {
+    /// The generation that live objects in this file are
+    /// written into when it is defragmented.
+    fn rewrite_generation(&self) -> u8 {
+        const MAX_GENERATION: u8 = 3;
+
+        self.generation.saturating_add(1).min(MAX_GENERATION)
+    }
+
+    /// Whether this file was last modified at least
+    /// `Config::min_compaction_age` ago. Files modified in
+    /// the future, according to the system clock, are young.
+    fn is_old_enough_to_compact(&self, config: &Config) -> io::Result<bool> {
+        let min_age = if let Some(min_age) = config.min_compaction_age {
+            min_age
+        } else {
+            return Ok(true);
+        };
+
+        let modified = fallible!(fallible!(self.file()?.metadata()).modified());
+        let age = std::time::SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+
+        Ok(age >= min_age)
+    }
+
     fn metadata(&self) -> Option<&Metadata> {
         let metadata_ptr = self.metadata.load(Acquire);
         if metadata_ptr.is_null() {
@@ -391,6 +597,14 @@ impl FileAndMetadata {
         let meta_ptr = Box::into_raw(Box::new(metadata));
         let old_meta_ptr = self.metadata.swap(meta_ptr, SeqCst);
         assert!(old_meta_ptr.is_null());
+
+        self.handle.track();
+    }
+
+    /// The open handle of this file, which is reopened if it
+    /// was closed because of `Config::max_open_files`.
+    fn file(&self) -> io::Result<Arc<File>> {
+        self.handle.get(self.path().map(PathBuf::as_path))
     }
 
     fn path(&self) -> Option<&PathBuf> {
@@ -406,7 +620,7 @@ impl FileAndMetadata {
 
 /// Shard based on rough size ranges corresponding to SSD
 /// page and block sizes
-pub fn default_partition_function(_object_id: u64, size: usize) -> u8 {
+pub fn default_partition_function(_object_id: u64, size: usize, _generation: u8) -> u8 {
     const SUBPAGE_MAX: usize = PAGE_MIN - 1;
     const PAGE_MIN: usize = 2048;
     const PAGE_MAX: usize = 16 * 1024;
@@ -427,7 +641,7 @@ pub fn default_partition_function(_object_id: u64, size: usize) -> u8 {
 
 /// Open the system with default configuration at the
 /// provided path.
-pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Marble> {
+pub fn open<P: AsRef<Path>>(path: P) -> Result<Marble, MarbleError> {
     let config = Config {
         path: path.as_ref().into(),
         ..Config::default()
@@ -436,11 +650,57 @@ pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Marble> {
     config.open()
 }
 
+/// Open a new, empty store with default configuration that is
+/// removed when it is dropped, as with `Config::temporary`,
+/// for caches and tests. Its storage files are kept in a
+/// uniquely named directory in the memory-backed `/dev/shm`
+/// where it exists, and in `std::env::temp_dir` otherwise,
+/// so that they are written and compacted exactly as on
+/// disk.
+pub fn open_in_memory() -> Result<Marble, MarbleError> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let shm = Path::new("/dev/shm");
+    let parent = if shm.is_dir() {
+        shm.to_path_buf()
+    } else {
+        std::env::temp_dir()
+    };
+
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_nanos());
+    let name = format!(
+        "marble-{}-{}-{nanos}",
+        std::process::id(),
+        COUNTER.fetch_add(1, SeqCst)
+    );
+
+    let config = Config {
+        path: parent.join(name),
+        temporary: true,
+        ..Config::default()
+    };
+
+    config.open()
+}
+
 /// Garbage-collecting object store. A nice solution to back
 /// a pagecache, for people building their own databases.
 ///
 /// Writes should generally be performed by some background
 /// process whose job it is to clean logs etc...
+///
+/// `Marble` is `Send + Sync`, and every method takes `&self`,
+/// so it may be shared across threads either by cloning it,
+/// which is cheap and shares the same underlying store, or
+/// by wrapping it in an `Arc`.
+///
+/// When the last clone of a `Marble` is dropped, any
+/// storage files that have not been synced yet are synced
+/// on a best-effort basis, and errors are logged rather
+/// than returned. Call `Marble::sync_all` explicitly if you
+/// need to know that written batches are durable.
 #[derive(Clone)]
 pub struct Marble {
     // maps from ObjectId to DiskLocation
@@ -448,7 +708,30 @@ pub struct Marble {
     max_object_id: Arc<AtomicU64>,
     file_map: FileMap,
     config: Config,
-    directory_lock: Arc<File>,
+    // holds the locked heap directory handle
+    sync_on_drop: Arc<SyncOnDrop>,
+    cache: Arc<ObjectCache>,
+    // held for writing while a snapshot copies the location
+    // table, which must not observe a partially installed batch
+    snapshot_lock: Arc<RwLock<()>>,
+    // notified after every maintenance pass, which wakes up
+    // writers that are blocked by `Config::backpressure`
+    space_reclaimed: Arc<(Mutex<()>, Condvar)>,
+    // set when a write fails after it was partially applied,
+    // after which writes are refused until the store is
+    // reopened and recovered from what is on disk
+    poisoned: Arc<AtomicBool>,
+    // the ID of the last batch written by `write_batch_once`,
+    // which is read from storage the first time it is needed.
+    // held for the whole call, so that batches with IDs are
+    // written one at a time.
+    last_batch_id: Arc<Mutex<Option<Option<u64>>>>,
+    // the state of `allocate_object_id`, which is read from
+    // storage the first time it is needed
+    id_lease: Arc<Mutex<Option<id_allocator::IdLease>>>,
+    // the reference counts of `increment_ref`, which are read
+    // from storage the first time they are needed
+    ref_counts: Arc<Mutex<Option<ref_count::RefCounts>>>,
     #[cfg(feature = "runtime_validation")]
     debug_history: Arc<std::sync::Mutex<debug_history::DebugHistory>>,
     decompressed_bytes_read: Arc<AtomicU64>,
@@ -456,11 +739,20 @@ pub struct Marble {
     decompressed_bytes_written: Arc<AtomicU64>,
     compressed_bytes_written: Arc<AtomicU64>,
     high_level_user_bytes_written: Arc<AtomicU64>,
+    objects_written: Arc<AtomicU64>,
+    write_batches: Arc<AtomicU64>,
+    last_write_batch_nanos: Arc<AtomicU64>,
 }
 
+// only reads atomics and lock-free maps, and never takes any
+// of the locks above, so that a `Marble` may be formatted
+// from anywhere, even while one of its locks is held.
 impl std::fmt::Debug for Marble {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Marble")
+            .field("config", &self.config)
+            .field("next_file_lsn", &self.file_map.next_file_lsn.load(Acquire))
+            .field("poisoned", &self.poisoned.load(Acquire))
             .field("stats", &self.stats())
             .finish()
     }
@@ -472,6 +764,108 @@ impl std::fmt::Display for Marble {
     }
 }
 
+// `Marble` is shared across threads by the examples and
+// benchmarks, so losing `Send` or `Sync` would be a breaking
+// change.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Marble>();
+    assert_send_sync::<Snapshot>();
+};
+
+/// Shared by every clone of a `Marble`, so that unsynced
+/// storage files are synced when the last one is dropped.
+struct SyncOnDrop {
+    file_map: FileMap,
+    directory_lock: File,
+    /// Handles to the sub-directories of the heap directory
+    /// that storage files are written into, one per shard.
+    shard_directories: Mutex<Map<u8, File>>,
+    /// The path of a store opened with `Config::temporary`,
+    /// which is removed instead of being synced.
+    temporary_path: Option<PathBuf>,
+}
+
+impl SyncOnDrop {
+    /// Fsyncs the heap directory and every shard directory,
+    /// making renamed storage files durable.
+    fn sync_directories(&self) -> io::Result<()> {
+        sync_directory(&self.directory_lock)?;
+        for shard_directory in self.shard_directories.lock().unwrap().values() {
+            sync_directory(shard_directory)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SyncOnDrop {
+    fn drop(&mut self) {
+        if let Some(path) = &self.temporary_path {
+            if let Err(e) = std::fs::remove_dir_all(path) {
+                log::error!("failed to remove temporary store at {:?}: {:?}", path, e);
+            }
+            return;
+        }
+
+        let res = self.file_map.sync_all().and_then(|synced_files| {
+            if synced_files {
+                self.sync_directories()
+            } else {
+                Ok(())
+            }
+        });
+
+        if let Err(e) = res {
+            log::error!(
+                "failed to sync storage files while dropping Marble: {:?}",
+                e
+            );
+        }
+    }
+}
+
+/// Fsyncs a directory using a handle that was opened during
+/// recovery or when the directory was created.
+fn sync_directory(directory: &File) -> io::Result<()> {
+    match maybe!(directory.sync_all()) {
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported
+            ) =>
+        {
+            // some platforms and filesystems do not support
+            // fsyncing a directory, and persist renames without it.
+            log::debug!("ignoring unsupported fsync on directory: {:?}", e);
+            Ok(())
+        }
+        other => other,
+    }
+}
+
+/// Sums the sizes of all files below `path`, skipping files
+/// that are removed while it runs.
+fn directory_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+
+    for entry_res in fallible!(std::fs::read_dir(path)) {
+        let entry = fallible!(entry_res);
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(annotate!(e)),
+        };
+
+        if metadata.is_dir() {
+            size += directory_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
 impl Marble {
     /// Statistics about current files, intended to inform
     /// decisions about when to call `maintenance` based on
@@ -482,7 +876,8 @@ impl Marble {
     #[doc(alias = "metrics")]
     #[doc(alias = "info")]
     pub fn stats(&self) -> Stats {
-        let (fams_len, total_file_size, stored_objects, live_objects) = self.file_map.stats();
+        let (fams_len, total_file_size, total_trailer_size, stored_objects, live_objects) =
+            self.file_map.stats();
 
         let compressed_bytes_read = self.compressed_bytes_read.load(Acquire);
         let decompressed_bytes_read = self.decompressed_bytes_read.load(Acquire);
@@ -509,6 +904,7 @@ impl Marble {
             live_ratio,
             files: fams_len,
             total_file_size,
+            total_trailer_size,
             compressed_bytes_read,
             compressed_bytes_written,
             decompressed_bytes_read,
@@ -516,27 +912,172 @@ impl Marble {
             read_compression_ratio,
             written_compression_ratio,
             high_level_user_bytes_written,
+            objects_written: self.objects_written.load(Acquire),
+            write_batches: self.write_batches.load(Acquire),
+            last_write_batch_nanos: self.last_write_batch_nanos.load(Acquire),
             write_amplification,
             space_amplification,
         }
     }
 
-    fn prune_empty_files(&self) -> io::Result<()> {
-        self.file_map.prune_empty_files(&self.location_table)
+    /// Reports the fragmentation of each storage file, for
+    /// implementing custom maintenance policies. Files that
+    /// are still being written are not included.
+    #[doc(alias = "file_frag")]
+    pub fn file_fragmentation(&self) -> Vec<FileFragmentation> {
+        let mut ret = vec![];
+
+        for (location, fam) in &self.file_map.fams {
+            let metadata = if let Some(metadata) = fam.metadata() {
+                metadata
+            } else {
+                continue;
+            };
+
+            let live_objects = fam.live_objects.load(Acquire);
+            let live_percent = (live_objects * 100) / metadata.present_objects.max(1);
+
+            ret.push(FileFragmentation {
+                location: location.0,
+                path: fam.path().unwrap().clone(),
+                shard: fam.partition,
+                generation: fam.generation,
+                file_size: metadata.file_size,
+                stored_objects: metadata.present_objects,
+                live_objects,
+                live_percent: u8::try_from(live_percent).unwrap(),
+            });
+        }
+
+        ret.sort_by_key(|ff| ff.location);
+
+        ret
+    }
+
+    /// The ratio of the total size of all storage files to
+    /// the estimated size of the live data stored in them.
+    /// 1.0 means that there is no dead space, and 2.0 means
+    /// that half of the space used is taken up by objects
+    /// that have since been overwritten or deleted. This is
+    /// intended to be compared against a threshold to decide
+    /// when to call `maintenance`.
+    ///
+    /// The live data of each file is estimated by assuming
+    /// that its live objects are of average size for that
+    /// file. Returns 1.0 for a store without any storage
+    /// files, and `f64::INFINITY` if there are storage files
+    /// but none of them contain live objects.
+    pub fn space_amplification(&self) -> f64 {
+        let mut total_file_size = 0;
+        let mut live_bytes = 0.0;
+
+        for (_, fam) in &self.file_map.fams {
+            let metadata = if let Some(metadata) = fam.metadata() {
+                metadata
+            } else {
+                continue;
+            };
+
+            let live_objects = fam.live_objects.load(Acquire);
+            let live_ratio = live_objects as f64 / metadata.present_objects.max(1) as f64;
+
+            total_file_size += metadata.file_size;
+            live_bytes += metadata.file_size as f64 * live_ratio;
+        }
+
+        if total_file_size == 0 {
+            1.0
+        } else if live_bytes == 0.0 {
+            f64::INFINITY
+        } else {
+            total_file_size as f64 / live_bytes
+        }
+    }
+
+    /// The total size in bytes of all files in
+    /// `Config::path`, for monitoring the footprint of the
+    /// store. Unlike `Stats::total_file_size`, this stats the
+    /// files on disk, so it also includes storage files that
+    /// are still being written or are waiting to be removed,
+    /// leftover temporary files, and the small files used
+    /// for locking and for `allocate_object_id`.
+    #[doc(alias = "disk_usage")]
+    pub fn size_on_disk(&self) -> Result<u64, MarbleError> {
+        let size = directory_size(&self.config.path)?;
+
+        Ok(size)
+    }
+
+    /// Returns an error if this instance was opened with
+    /// `Config::read_only`.
+    fn check_writable(&self) -> io::Result<()> {
+        if self.config.read_only {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "this operation modifies the store, which was opened with `Config::read_only`",
+            ))
+        } else if self.poisoned.load(Acquire) {
+            Err(io::Error::other(
+                "this store is poisoned because an earlier write failed after it was partially \
+                 applied, and must be reopened before it can be modified again",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Refuses further writes after an IO error that may have
+    /// left the in-memory state out of sync with what is on
+    /// disk, such as a failed fsync, whose failure may not be
+    /// reported again if it is retried.
+    fn poison(&self, error: &io::Error) {
+        log::error!("refusing further writes until the store is reopened after error: {error:?}");
+        self.poisoned.store(true, SeqCst);
+    }
+
+    /// Removes storage files without live objects, returning
+    /// the number of files removed and their total size.
+    fn prune_empty_files(&self) -> io::Result<(usize, u64)> {
+        let (removed_files, removed_bytes) = self
+            .file_map
+            .prune_empty_files(&self.config, &self.location_table)?;
+
+        // called after every maintenance pass, which may have
+        // reduced space amplification even without removing
+        // files
+        {
+            let (mu, cv) = &*self.space_reclaimed;
+            let _guard = mu.lock().unwrap();
+            cv.notify_all();
+        }
+
+        if let Some(metrics) = &self.config.metrics {
+            for _ in 0..removed_files {
+                metrics.on_file_removed();
+            }
+        }
+
+        Ok((removed_files, removed_bytes))
     }
 
     /// If `Config::fsync_each_batch` is `false`, this
     /// method can be called at a desired interval to
     /// ensure that the written batches are durable on
     /// disk.
-    pub fn sync_all(&self) -> io::Result<()> {
-        let synced_files = self.file_map.sync_all()?;
+    pub fn sync_all(&self) -> Result<(), MarbleError> {
+        let synced_files = self.file_map.sync_all().inspect_err(|e| self.poison(e))?;
         if synced_files {
-            fallible!(self.directory_lock.sync_all());
+            self.sync_directory()?;
         }
         Ok(())
     }
 
+    fn sync_directory(&self) -> io::Result<()> {
+        self.sync_on_drop
+            .sync_directories()
+            .inspect_err(|e| self.poison(e))
+    }
+
     /// Intended for use in recovery, to bootstrap a higher level object ID allocator.
     ///
     /// Returns a tuple of 1 higher than the current max allocated object ID,
@@ -555,6 +1096,129 @@ impl Marble {
         (max + 1, iter)
     }
 
+    /// Returns the number of stored objects, not counting
+    /// objects that have been deleted. This scans the
+    /// location table, and takes time proportional to the
+    /// highest object ID that has been written.
+    #[doc(alias = "page_count")]
+    #[doc(alias = "object_count")]
+    pub fn len(&self) -> usize {
+        let max = self.max_object_id.load(Acquire);
+
+        (0..=max)
+            .filter(|oid| {
+                self.location_table
+                    .load(*oid)
+                    .is_some_and(|location| !location.is_delete())
+            })
+            .count()
+    }
+
+    /// Returns `true` if no objects are stored. See `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every stored object, for instance to reset a
+    /// cache without recreating its directory. The objects
+    /// are removed by a single write batch, so a crash either
+    /// removes all of them or none, and the storage files
+    /// that held them are removed afterwards. The file
+    /// recording the removals remains, like after any other
+    /// batch of removals. Objects written by concurrent calls
+    /// to `write_batch` may or may not be removed. This scans
+    /// the location table as `len` does.
+    #[doc(alias = "truncate")]
+    pub fn clear(&self) -> Result<(), MarbleError> {
+        let object_ids: Vec<ObjectId> = self.range(0, ObjectId::MAX).collect();
+
+        self.write_batch(
+            object_ids
+                .into_iter()
+                .map(|object_id| (object_id, None::<&[u8]>)),
+        )?;
+
+        self.prune_empty_files()?;
+
+        Ok(())
+    }
+
+    /// Deletes every stored object in `start..end` as a single
+    /// write batch, for instance to evict a contiguous part of
+    /// the keyspace, and returns the number of objects that
+    /// were deleted. The objects are found with
+    /// `Marble::range`, so this takes time proportional to the
+    /// size of the range, and the internal object stored
+    /// under the reserved object ID is never deleted. Objects
+    /// written concurrently within the range may or may not
+    /// be deleted.
+    pub fn delete_range(&self, start: ObjectId, end: ObjectId) -> Result<u64, MarbleError> {
+        let object_ids: Vec<ObjectId> = self.range(start, end).collect();
+
+        self.delete_batch(&object_ids)?;
+
+        Ok(object_ids.len() as u64)
+    }
+
+    /// Returns an iterator over the IDs of stored objects in
+    /// `start..end`, in ascending order, not including
+    /// objects that have been deleted. This allows object IDs
+    /// to be used as an ordered keyspace. This scans the
+    /// location table, and takes time proportional to the
+    /// size of the range, up to the highest object ID that
+    /// has been written.
+    pub fn range<'a>(
+        &'a self,
+        start: ObjectId,
+        end: ObjectId,
+    ) -> impl 'a + Iterator<Item = ObjectId> {
+        let max = self.max_object_id.load(Acquire);
+
+        (start..end.min(max + 1)).filter(move |oid| {
+            self.location_table
+                .load(*oid)
+                .is_some_and(|location| !location.is_delete())
+        })
+    }
+
+    /// Intended for incremental backups. Returns the sequence
+    /// number and path of every storage file created after
+    /// the sequence number `lsn`, sorted by sequence number.
+    /// Storage files are never modified after they are
+    /// returned here, and each one contains everything needed
+    /// to recover the objects stored in it, so copying all
+    /// files returned by successive calls into the `heap`
+    /// directory of a new location produces a store that
+    /// can be opened with the same contents as this one at
+    /// the time of the last call. Files that are removed by
+    /// maintenance after being copied are harmless to keep,
+    /// and are removed by maintenance of the restored store.
+    ///
+    /// Pass 0 to receive all files, and the highest returned
+    /// sequence number to receive only the files created
+    /// since the previous call. Files that are still being
+    /// written are never returned, and neither are any newer
+    /// files, so that they are not skipped by the next call.
+    pub fn files_since(&self, lsn: u64) -> Vec<(u64, PathBuf)> {
+        let mut in_progress_lsn = u64::MAX;
+        let mut files = vec![];
+
+        for (location, fam) in &self.file_map.fams {
+            let file_lsn = location.0.lsn() & NEW_WRITE_BATCH_MASK;
+            if fam.metadata().is_none() {
+                // the file is still being written
+                in_progress_lsn = in_progress_lsn.min(file_lsn);
+            } else if file_lsn > lsn {
+                files.push((file_lsn, fam.path().unwrap().clone()));
+            }
+        }
+
+        files.retain(|(file_lsn, _)| *file_lsn < in_progress_lsn);
+        files.sort();
+
+        files
+    }
+
     /// Returns an Iterator over all currently allocated object IDs.
     pub fn allocated_object_ids<'a>(&'a self) -> impl 'a + Iterator<Item = u64> {
         let max = self.max_object_id.load(Acquire);
@@ -0,0 +1,75 @@
+use std::path::{Path, PathBuf};
+
+use crate::DiskLocation;
+
+/// Hooks that are called as `Marble` performs work, which
+/// may be implemented to export metrics to a monitoring
+/// system. Set `Config::metrics` to receive them. Every
+/// method has an empty default implementation.
+///
+/// Hooks are called synchronously from the threads that
+/// perform the work, so they should be cheap, for instance
+/// by incrementing atomic counters.
+pub trait MarbleMetrics: std::fmt::Debug + Send + Sync {
+    /// Called after `Marble::write_batch` has written a
+    /// batch, with the number of uncompressed bytes of
+    /// object data and the number of objects (including
+    /// deletions) in it.
+    fn on_write_batch(&self, _bytes: u64, _objects: usize) {}
+
+    /// Called after an object has been read from a storage
+    /// file, with its uncompressed size in bytes. Reads that
+    /// are served by the cache of `Marble::read_cached` do
+    /// not call this.
+    fn on_read(&self, _bytes: u64) {}
+
+    /// Called after maintenance has rewritten the live
+    /// objects of `files` storage files, with the number of
+    /// uncompressed bytes of object data that were rewritten.
+    fn on_compaction(&self, _files: usize, _rewritten_bytes: u64) {}
+
+    /// Called when a storage file that no longer contains
+    /// live objects is removed from the store.
+    fn on_file_removed(&self) {}
+
+    /// Called with the location and path of a storage file
+    /// whose objects have all been deleted or rewritten
+    /// elsewhere, just before it is removed from the store,
+    /// for instance to drop external references to it. The
+    /// file is deleted from disk once reads that are still
+    /// using it complete. This is followed by a call to
+    /// `on_file_removed`.
+    fn on_file_empty(&self, _location: DiskLocation, _path: &Path) {}
+
+    /// Called when opening a store finds, removes or ignores
+    /// a file other than a storage file, which is also
+    /// logged. See `RecoveryEvent`.
+    fn on_recovery_event(&self, _event: RecoveryEvent) {}
+}
+
+/// Something unusual that was found in the storage directory
+/// while opening a store, as reported to
+/// `MarbleMetrics::on_recovery_event`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecoveryEvent {
+    /// A storage file that was not completely written before
+    /// a crash was removed.
+    RemovedTemporaryFile(PathBuf),
+    /// A storage file that was not completely written before
+    /// a crash was ignored rather than removed, because the
+    /// store was opened with `Config::read_only` or
+    /// `Config::keep_tmp_files`.
+    IgnoredTemporaryFile(PathBuf),
+    /// A storage file that was written after the checkpoint
+    /// passed to `Config::restore` was removed.
+    RemovedFileAfterCheckpoint(PathBuf),
+    /// A storage file that was written after the checkpoint
+    /// passed to `Config::restore` was moved to the given
+    /// path in the quarantine directory, because
+    /// `Config::recovery_policy` is
+    /// `RecoveryPolicy::QuarantineAhead`.
+    QuarantinedFileAfterCheckpoint(PathBuf),
+    /// A file or directory that does not belong to the store
+    /// was found and left alone.
+    UnknownFile(PathBuf),
+}
@@ -1,17 +1,153 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs::File;
 use std::io;
 use std::os::unix::fs::FileExt;
-use std::sync::atomic::Ordering;
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 use fault_injection::{annotate, fallible};
 
-use crate::{hash, uninit_boxed_slice, Marble, ObjectId, HEADER_LEN};
+use crate::{
+    decode_record_len, read_trailer, record_has_metadata, retry_io, uninit_boxed_slice,
+    DiskLocation, FileAndMetadata, Marble, MarbleError, ObjectId, ObjectMetadata, HEADER_LEN,
+    OBJECT_METADATA_LEN, RESERVED_OBJECT_ID,
+};
+
+/// The result of a read that returns something along with
+/// an object's data.
+type ReadWith<T> = Result<Option<(Box<[u8]>, T)>, MarbleError>;
+
+/// An object ID and data that is returned by `scan_file`.
+type ScannedRecord = Result<(ObjectId, Box<[u8]>), MarbleError>;
+
+/// Supplies the buffers that [`Marble::read_with`] reads
+/// objects into, for instance from a pool of reusable
+/// buffers or from a custom allocator.
+pub trait BufferProvider {
+    /// The buffer that an object is read into.
+    type Buffer: AsRef<[u8]> + AsMut<[u8]>;
+
+    /// Returns a buffer of exactly `len` bytes. Its previous
+    /// contents do not matter, as they are overwritten by
+    /// the read.
+    fn buffer(&self, len: usize) -> Self::Buffer;
+}
+
+/// Allocates a new buffer for every read from the global
+/// allocator, as used by `read`.
+pub(crate) struct BoxedBuffers;
+
+impl BufferProvider for BoxedBuffers {
+    type Buffer = Box<[u8]>;
+
+    fn buffer(&self, len: usize) -> Box<[u8]> {
+        uninit_boxed_slice(len)
+    }
+}
+
+fn provider_buffer<P: BufferProvider>(provider: &P, len: usize) -> P::Buffer {
+    let mut buffer = provider.buffer(len);
+    assert_eq!(
+        buffer.as_mut().len(),
+        len,
+        "BufferProvider::buffer must return a buffer of the requested length"
+    );
+    buffer
+}
+
+/// An object record that was read from a storage file.
+pub(crate) struct Record<B = Box<[u8]>> {
+    pub(crate) object_id: ObjectId,
+    pub(crate) data: B,
+    /// All zeroes if the object was written without metadata.
+    pub(crate) metadata: ObjectMetadata,
+    pub(crate) crc: u32,
+}
 
 impl Marble {
     /// Read a object out of storage. If this object is
     /// unknown or has been removed, returns `Ok(None)`.
     /// If there is an IO problem, returns Err.
-    pub fn read(&self, object_id: ObjectId) -> io::Result<Option<Box<[u8]>>> {
-        let location = if let Some(location) = self.location_table.load(object_id) {
+    ///
+    /// Probing for objects that may not exist is cheap:
+    /// absent objects are found in memory without any IO or
+    /// allocation, and errors are only returned for IO
+    /// problems and corruption, such as
+    /// `MarbleError::Corruption` for a record whose crc does
+    /// not match, never for an object that does not exist.
+    #[doc(alias = "get")]
+    pub fn read(&self, object_id: ObjectId) -> Result<Option<Box<[u8]>>, MarbleError> {
+        let read = self.read_inner(object_id, None, &BoxedBuffers)?;
+
+        Ok(read.map(|record| record.data))
+    }
+
+    /// Like `read`, but also returns the crc32 that was stored
+    /// along with the object and checked while reading it,
+    /// for callers that deduplicate or cross-check objects
+    /// using their own hashes without reading them again.
+    /// The crc covers the object's record header, which holds
+    /// its length and ID, followed by its data as stored,
+    /// after any compression with `Config::zstd_compression_level`.
+    pub fn read_with_crc(&self, object_id: ObjectId) -> ReadWith<u32> {
+        let read = self.read_inner(object_id, None, &BoxedBuffers)?;
+
+        Ok(read.map(|record| (record.data, record.crc)))
+    }
+
+    /// Like `read`, but also returns the `ObjectMetadata`
+    /// that was stored along with the object by
+    /// `write_batch_with_metadata`, or all zeroes if it was
+    /// written without metadata.
+    #[doc(alias = "read_meta")]
+    pub fn read_with_metadata(&self, object_id: ObjectId) -> ReadWith<ObjectMetadata> {
+        let read = self.read_inner(object_id, None, &BoxedBuffers)?;
+
+        Ok(read.map(|record| (record.data, record.metadata)))
+    }
+
+    /// Like `read`, but gives up with an `Io` error of kind
+    /// `Interrupted` if `cancel` is set by the time that the
+    /// object's header has been read, before its data is
+    /// read. This allows reads from a slow device to be
+    /// abandoned, for instance when a request reaches its
+    /// deadline, by setting `cancel` from another thread.
+    pub fn read_cancellable(
+        &self,
+        object_id: ObjectId,
+        cancel: &AtomicBool,
+    ) -> Result<Option<Box<[u8]>>, MarbleError> {
+        let read = self.read_inner(object_id, Some(cancel), &BoxedBuffers)?;
+
+        Ok(read.map(|record| record.data))
+    }
+
+    /// Like `read`, but reads the object's data into a buffer
+    /// returned by `provider` instead of allocating a new one,
+    /// so that buffers may be pooled and reused across reads.
+    /// The provider is asked for one buffer of the object's
+    /// length for every object that is found.
+    pub fn read_with<P: BufferProvider>(
+        &self,
+        object_id: ObjectId,
+        provider: &P,
+    ) -> Result<Option<P::Buffer>, MarbleError> {
+        let read = self.read_inner(object_id, None, provider)?;
+
+        Ok(read.map(|record| record.data))
+    }
+
+    fn read_inner<P: BufferProvider>(
+        &self,
+        object_id: ObjectId,
+        cancel: Option<&AtomicBool>,
+        provider: &P,
+    ) -> Result<Option<Record<P::Buffer>>, MarbleError> {
+        let location = if let Some(location) = self.load_location(object_id) {
             location
         } else {
             return Ok(None);
@@ -21,57 +157,648 @@ impl Marble {
             return Ok(None);
         }
 
-        let fam = self.file_map.fam_for_location(location);
+        let read = self.read_location(object_id, location, cancel, provider)?;
+
+        Ok(read.map(|(_location, record)| record))
+    }
+
+    /// Reads the internal object stored under the reserved
+    /// object ID, which is hidden from the other read methods.
+    pub(crate) fn read_reserved(&self) -> Result<Option<Box<[u8]>>, MarbleError> {
+        let location = match self.location_table.load(RESERVED_OBJECT_ID) {
+            Some(location) if !location.is_delete() => location,
+            _ => return Ok(None),
+        };
+
+        let read = self.read_location(RESERVED_OBJECT_ID, location, None, &BoxedBuffers)?;
+
+        Ok(read.map(|(_location, record)| record.data))
+    }
+
+    /// Loads the current location of an object, treating the
+    /// reserved object ID as unknown, so that the internal
+    /// object stored under it is never returned to callers.
+    fn load_location(&self, object_id: ObjectId) -> Option<DiskLocation> {
+        if object_id == RESERVED_OBJECT_ID {
+            return None;
+        }
+
+        self.location_table.load(object_id)
+    }
+
+    /// Read an object through the in-memory cache configured
+    /// by `Config::cache_bytes`, avoiding IO and copies when
+    /// it is already present. Objects read using this method
+    /// are inserted into the cache, and cached objects are
+    /// never returned after they have been overwritten or
+    /// deleted. If the cache is disabled, this behaves like
+    /// `read`.
+    pub fn read_cached(&self, object_id: ObjectId) -> Result<Option<Arc<[u8]>>, MarbleError> {
+        let location = if let Some(location) = self.load_location(object_id) {
+            location
+        } else {
+            return Ok(None);
+        };
+
+        if location.is_delete() {
+            return Ok(None);
+        }
+
+        if let Some(cached) = self.cache.get(object_id, location) {
+            return Ok(Some(cached));
+        }
+
+        let (location, data) = if let Some((location, record)) =
+            self.read_location(object_id, location, None, &BoxedBuffers)?
+        {
+            (location, Arc::<[u8]>::from(record.data))
+        } else {
+            return Ok(None);
+        };
+
+        self.cache.insert(object_id, location, data.clone());
+
+        Ok(Some(data))
+    }
+
+    /// Returns the path of the storage file that currently
+    /// holds an object, and the offset within it that the
+    /// object's record begins at, for correlating an object
+    /// with on-disk state while debugging. Returns `None` if
+    /// this object is unknown or has been removed. The
+    /// object may be moved by a concurrent call to
+    /// `maintenance` at any time.
+    pub fn locate(&self, object_id: ObjectId) -> Option<(PathBuf, u64)> {
+        let mut location = self.load_location(object_id)?;
+
+        loop {
+            if location.is_delete() {
+                return None;
+            }
+
+            if let Ok(fam) = self.file_map.fam_for_location(location) {
+                let path = fam.path()?.clone();
+                let file_offset = location.lsn() - fam.location.lsn();
+                return Some((path, file_offset));
+            }
+
+            // the file was removed by maintenance after the
+            // location was loaded, as in `read_location`.
+            let current = self.location_table.load(object_id)?;
+            if current == location {
+                return None;
+            }
+            location = current;
+        }
+    }
+
+    /// Returns the location of the record that currently
+    /// holds an object, which may be passed to
+    /// `read_at_location` after the object has been
+    /// overwritten. Returns `None` if this object is unknown
+    /// or has been removed.
+    pub fn location(&self, object_id: ObjectId) -> Option<DiskLocation> {
+        self.load_location(object_id)
+            .filter(|location| !location.is_delete())
+    }
+
+    /// Like `location`, but resolves many objects at once,
+    /// returning their locations in the same order as
+    /// `object_ids`. Write batches are blocked from updating
+    /// the location table while the locations are loaded, so
+    /// each write batch is either entirely reflected in the
+    /// result or not at all, as with [`Marble::snapshot`].
+    pub fn locations(&self, object_ids: &[ObjectId]) -> Vec<Option<DiskLocation>> {
+        let _snapshot_guard = self.snapshot_lock.write().unwrap();
+
+        object_ids
+            .iter()
+            .map(|object_id| self.location(*object_id))
+            .collect()
+    }
+
+    /// Hints to the operating system that the given objects
+    /// are about to be read, so that the parts of storage
+    /// files that hold them are loaded into its page cache in
+    /// the background, which reduces the latency of reading
+    /// them afterwards. Objects are grouped by storage file,
+    /// and the header of each record is read to find the
+    /// range of bytes that it spans. Unknown and removed
+    /// objects are ignored.
+    ///
+    /// This uses `posix_fadvise` with `POSIX_FADV_WILLNEED`
+    /// on 64-bit Linux, and does nothing on other platforms.
+    pub fn prefetch(&self, object_ids: &[ObjectId]) -> Result<(), MarbleError> {
+        let mut files: BTreeMap<DiskLocation, (Arc<FileAndMetadata>, Vec<u64>)> = BTreeMap::new();
+
+        for object_id in object_ids {
+            let location = if let Some(location) = self.location(*object_id) {
+                location
+            } else {
+                continue;
+            };
+
+            // the file may have been removed by maintenance
+            // since the location was loaded
+            let fam = if let Ok(fam) = self.file_map.fam_for_location(location) {
+                fam
+            } else {
+                continue;
+            };
+
+            let file_offset = location.lsn() - fam.location.lsn();
+            files
+                .entry(fam.location)
+                .or_insert_with(|| (fam, vec![]))
+                .1
+                .push(file_offset);
+        }
+
+        for (fam, mut offsets) in files.into_values() {
+            offsets.sort_unstable();
+            offsets.dedup();
+
+            let file = fam.file()?;
+
+            // the headers are requested first, so that they are
+            // loaded concurrently rather than one at a time by
+            // the reads below
+            for offset in &offsets {
+                advise_will_need(&file, *offset, HEADER_LEN as u64)?;
+            }
+
+            for offset in offsets {
+                let mut header_buf = [0_u8; HEADER_LEN];
+                fallible!(file.read_exact_at(&mut header_buf, offset));
+
+                // a corrupt length is reported when the object
+                // is read
+                let len_buf = header_buf[12..].try_into().unwrap();
+                if let Some(len) = decode_record_len(len_buf) {
+                    advise_will_need(&file, offset + HEADER_LEN as u64, len)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the length of an object's data without reading
+    /// it, for instance to allocate a buffer for it, or
+    /// `None` if this object is unknown or has been removed.
+    /// Only the header of the object's record is read, along
+    /// with the start of its compressed data if its file is
+    /// compressed. The object ID in the header is checked,
+    /// but the crc is not, because it covers the data.
+    pub fn object_len(&self, object_id: ObjectId) -> Result<Option<usize>, MarbleError> {
+        let location = if let Some(location) = self.location(object_id) {
+            location
+        } else {
+            return Ok(None);
+        };
+
+        let (location, fam) = if let Some(found) = self.fam_for_object(object_id, location)? {
+            found
+        } else {
+            return Ok(None);
+        };
 
         let file_offset = location.lsn() - fam.location.lsn();
+        let file = fam.file()?;
 
         let mut header_buf = [0_u8; HEADER_LEN];
-        fallible!(fam.file.read_exact_at(&mut header_buf, file_offset));
+        fallible!(retry_io(self.config.io_retries, || {
+            file.read_exact_at(&mut header_buf, file_offset)
+        }));
+
+        let pid_buf: [u8; 8] = header_buf[4..12].try_into().unwrap();
+        let len_buf: [u8; 8] = header_buf[12..].try_into().unwrap();
+
+        let read_pid = u64::from_le_bytes(pid_buf);
+        if read_pid != object_id {
+            return Err(MarbleError::Corruption(annotate!(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "expected to find object {object_id} at location {location:?}, but found \
+                     a record of object {read_pid}"
+                ),
+            ))));
+        }
+
+        let corrupt_len = || {
+            MarbleError::Corruption(annotate!(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupted length detected in the record at offset {file_offset}"),
+            )))
+        };
+
+        let len = decode_record_len(len_buf)
+            .and_then(|len| usize::try_from(len).ok())
+            .ok_or_else(corrupt_len)?;
+
+        let object_end = (file_offset + HEADER_LEN as u64).saturating_add(len as u64);
+        if fam
+            .metadata()
+            .is_some_and(|metadata| object_end > metadata.trailer_offset)
+        {
+            return Err(corrupt_len());
+        }
+
+        let stored_len = if fam.zstd_dict.decompressor().is_some() {
+            let mut frame_header = vec![0_u8; len.min(crate::zstd::FRAME_HEADER_MAX_LEN)];
+            fallible!(retry_io(self.config.io_retries, || {
+                file.read_exact_at(&mut frame_header, file_offset + HEADER_LEN as u64)
+            }));
+
+            crate::zstd::frame_content_size(&frame_header)
+                .and_then(|size| usize::try_from(size).ok())
+                .ok_or_else(corrupt_len)?
+        } else {
+            len
+        };
+
+        let metadata_len = if record_has_metadata(len_buf) {
+            OBJECT_METADATA_LEN
+        } else {
+            0
+        };
+
+        stored_len
+            .checked_sub(metadata_len)
+            .map(Some)
+            .ok_or_else(corrupt_len)
+    }
+
+    /// Reads the object record at a location that was
+    /// returned by `Marble::location`, returning the object
+    /// ID that it was written for and its data, even if the
+    /// object has since been overwritten or removed. This is
+    /// intended for debugging, and for inspecting earlier
+    /// versions of objects while their storage files still
+    /// exist. The record's crc is checked as with `read`.
+    ///
+    /// Returns `MarbleError::NotFound` if the storage file
+    /// that held the record has been removed by
+    /// maintenance, and `MarbleError::Corruption` if the
+    /// location does not point at a valid record.
+    pub fn read_at_location(
+        &self,
+        location: DiskLocation,
+    ) -> Result<(ObjectId, Box<[u8]>), MarbleError> {
+        let fam = self.file_map.fam_for_location(location).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no storage file contains location {location:?}"),
+            )
+        })?;
+
+        if location.is_delete() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("location {location:?} is a deletion, not an object record"),
+            )
+            .into());
+        }
+
+        let record = self.read_record(&fam, location, None, &BoxedBuffers)?;
+
+        Ok((record.object_id, record.data))
+    }
+
+    /// Iterates over every object record in the storage file
+    /// at `location`, as reported by
+    /// `Marble::file_fragmentation`, in the order that they
+    /// are laid out in the file, regardless of whether they
+    /// are still the current version of their object. This is
+    /// intended for tooling that exports or inspects single
+    /// files. Deletions are not included, and each record's
+    /// crc is checked as with `read`.
+    ///
+    /// Records are found through the file's trailer, so that
+    /// any padding between them is skipped. Returns
+    /// `MarbleError::NotFound` if no storage file exists at
+    /// `location`. A record that is cut short or otherwise
+    /// damaged is returned as a `MarbleError::Corruption`,
+    /// after which iteration ends.
+    pub fn scan_file(
+        &self,
+        location: DiskLocation,
+    ) -> Result<impl Iterator<Item = ScannedRecord>, MarbleError> {
+        let not_found = || {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no storage file exists at location {location:?}"),
+            )
+        };
+
+        let fam: Arc<FileAndMetadata> = self
+            .file_map
+            .fams
+            .get(&Reverse(location))
+            .ok_or_else(not_found)?;
+
+        // files that are still being written have no trailer yet
+        let metadata = fam.metadata().ok_or_else(not_found)?;
+
+        let (trailer, _zstd_dict) =
+            read_trailer(&*fam.file()?, metadata.trailer_offset, metadata.file_size)?;
+
+        let mut records: Vec<(DiskLocation, ObjectId)> = trailer
+            .into_iter()
+            .filter(|(_, relative_location)| !relative_location.is_delete())
+            .map(|(object_id, relative_location)| {
+                (relative_location.to_absolute(fam.location.lsn()), object_id)
+            })
+            .collect();
+        records.sort_unstable();
+
+        let marble = self.clone();
+        let mut failed = false;
+
+        Ok(records.into_iter().map_while(move |(location, object_id)| {
+            if failed {
+                return None;
+            }
+
+            let res = marble.read_from_fam(&fam, object_id, location, None, &BoxedBuffers);
+            failed = res.is_err();
+
+            Some(
+                res.map(|record| (record.object_id, record.data))
+                    .map_err(MarbleError::from),
+            )
+        }))
+    }
+
+    /// Reads the object stored at `location`, returning the
+    /// location that it was actually read from.
+    fn read_location<P: BufferProvider>(
+        &self,
+        object_id: ObjectId,
+        location: DiskLocation,
+        cancel: Option<&AtomicBool>,
+        provider: &P,
+    ) -> io::Result<Option<(DiskLocation, Record<P::Buffer>)>> {
+        let (location, fam) = if let Some(found) = self.fam_for_object(object_id, location)? {
+            found
+        } else {
+            return Ok(None);
+        };
+
+        let record = self.read_from_fam(&fam, object_id, location, cancel, provider)?;
+
+        Ok(Some((location, record)))
+    }
+
+    /// Finds the file holding an object that was loaded from
+    /// the location table at `location`, along with the
+    /// location of the object in it, or `None` if the object
+    /// has since been removed.
+    fn fam_for_object(
+        &self,
+        object_id: ObjectId,
+        mut location: DiskLocation,
+    ) -> io::Result<Option<(DiskLocation, Arc<FileAndMetadata>)>> {
+        loop {
+            // holding the fam keeps its file open, so it can be
+            // read even if maintenance removes it concurrently.
+            let fam_res = self.file_map.fam_for_location(location);
+
+            match fam_res {
+                Ok(fam) => return Ok(Some((location, fam))),
+                Err(e) => {
+                    // maintenance may have rewritten the object
+                    // and removed its previous file after its
+                    // location was loaded, in which case the
+                    // location table already points somewhere
+                    // else.
+                    match self.location_table.load(object_id) {
+                        Some(current) if current == location => return Err(e),
+                        Some(current) if !current.is_delete() => location = current,
+                        _ => return Ok(None),
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn read_from_fam<P: BufferProvider>(
+        &self,
+        fam: &FileAndMetadata,
+        object_id: ObjectId,
+        location: DiskLocation,
+        cancel: Option<&AtomicBool>,
+        provider: &P,
+    ) -> io::Result<Record<P::Buffer>> {
+        let record = self.read_record(fam, location, cancel, provider)?;
+
+        if record.object_id != object_id {
+            return Err(annotate!(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "expected to read object {object_id} at location {location:?}, but found a \
+                     record of object {}",
+                    record.object_id
+                ),
+            )));
+        }
+
+        Ok(record)
+    }
+
+    /// Reads and checks the record at `location` into a
+    /// buffer from `provider`, splitting off its metadata if
+    /// it has any. Fails with `Interrupted` after reading the
+    /// header if `cancel` is set.
+    fn read_record<P: BufferProvider>(
+        &self,
+        fam: &FileAndMetadata,
+        location: DiskLocation,
+        cancel: Option<&AtomicBool>,
+        provider: &P,
+    ) -> io::Result<Record<P::Buffer>> {
+        let file_offset = location.lsn() - fam.location.lsn();
+        let file = fam.file()?;
+
+        let mut header_buf = [0_u8; HEADER_LEN];
+        fallible!(retry_io(self.config.io_retries, || {
+            file.read_exact_at(&mut header_buf, file_offset)
+        }));
 
         let crc_expected: [u8; 4] = header_buf[0..4].try_into().unwrap();
         let pid_buf: [u8; 8] = header_buf[4..12].try_into().unwrap();
         let len_buf: [u8; 8] = header_buf[12..].try_into().unwrap();
 
-        let len: usize = if let Ok(len) = u64::from_le_bytes(len_buf).try_into() {
+        let len_opt = decode_record_len(len_buf).and_then(|len| usize::try_from(len).ok());
+        let len: usize = if let Some(len) = len_opt {
             len
         } else {
             return Err(io::Error::new(
-                io::ErrorKind::Other,
+                io::ErrorKind::InvalidData,
                 "corrupted length detected",
             ));
         };
 
-        let mut compressed_buf: Box<[u8]> = uninit_boxed_slice(len);
+        // a corrupt or torn length must not cause a read past the
+        // end of the object data, or a gigantic allocation
+        let object_end = (file_offset + HEADER_LEN as u64).saturating_add(len as u64);
+        if let Some(metadata) = fam.metadata() {
+            if object_end > metadata.trailer_offset {
+                return Err(annotate!(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "corrupted length detected: the record at offset {file_offset} has a \
+                         length of {len}, which extends past the end of the object data at \
+                         offset {}",
+                        metadata.trailer_offset,
+                    ),
+                )));
+            }
+        }
+
+        if cancel.is_some_and(|cancel| cancel.load(Ordering::Acquire)) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                format!("read of the record at offset {file_offset} was cancelled"),
+            ));
+        }
 
         let object_offset = file_offset + HEADER_LEN as u64;
-        fallible!(fam.file.read_exact_at(&mut compressed_buf, object_offset));
+        let read_pid = u64::from_le_bytes(pid_buf);
+
+        let metadata_len = if record_has_metadata(len_buf) {
+            OBJECT_METADATA_LEN
+        } else {
+            0
+        };
+        let mut metadata = [0; OBJECT_METADATA_LEN];
 
-        let crc_actual = hash(len_buf, pid_buf, &compressed_buf);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&len_buf);
+        hasher.update(&pid_buf);
+
+        let check_crc = |hasher: crc32fast::Hasher| {
+            if crc_expected == hasher.finalize().to_le_bytes() {
+                return Ok(());
+            }
 
-        if crc_expected != crc_actual {
             log::warn!(
                 "crc mismatch when reading object at offset {} in file {:?}",
                 object_offset,
                 file_offset
             );
-            return Err(annotate!(io::Error::new(
+            Err(annotate!(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "crc mismatch",
-            )));
-        }
+            )))
+        };
 
-        let read_pid = u64::from_le_bytes(pid_buf);
+        let too_short = || {
+            annotate!(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "object record is too short to contain its metadata",
+            ))
+        };
 
-        assert_eq!(object_id, read_pid);
+        let (data, decompressed_len) = if let Some(decompressor) = fam.zstd_dict.decompressor() {
+            let mut compressed_buf: Box<[u8]> = uninit_boxed_slice(len);
+            fallible!(retry_io(self.config.io_retries, || {
+                file.read_exact_at(&mut compressed_buf, object_offset)
+            }));
 
-        self.compressed_bytes_read
-            .fetch_add(compressed_buf.len() as u64, Ordering::Relaxed);
+            hasher.update(&compressed_buf);
+            check_crc(hasher)?;
+
+            if metadata_len == 0 {
+                let data = decompressor
+                    .decompress_into(&compressed_buf, |len| provider_buffer(provider, len));
+                let decompressed_len = data.as_ref().len();
+                (data, decompressed_len)
+            } else {
+                let decompressed_buf =
+                    decompressor.decompress_into(&compressed_buf, uninit_boxed_slice);
+                if decompressed_buf.len() < metadata_len {
+                    return Err(too_short());
+                }
+                let (metadata_buf, data_buf) = decompressed_buf.split_at(metadata_len);
+                metadata.copy_from_slice(metadata_buf);
+
+                let mut data = provider_buffer(provider, data_buf.len());
+                data.as_mut().copy_from_slice(data_buf);
+                (data, decompressed_buf.len())
+            }
+        } else {
+            // uncompressed data is read straight into the
+            // provided buffer, after any metadata
+            if len < metadata_len {
+                return Err(too_short());
+            }
+            let metadata_buf = &mut metadata[..metadata_len];
+            fallible!(retry_io(self.config.io_retries, || {
+                file.read_exact_at(metadata_buf, object_offset)
+            }));
 
-        let decompressed_buf = fam.zstd_dict.decompress(compressed_buf);
+            let mut data = provider_buffer(provider, len - metadata_len);
+            fallible!(retry_io(self.config.io_retries, || {
+                file.read_exact_at(data.as_mut(), object_offset + metadata_len as u64)
+            }));
+
+            hasher.update(&metadata[..metadata_len]);
+            hasher.update(data.as_ref());
+            check_crc(hasher)?;
+
+            (data, len)
+        };
+
+        self.compressed_bytes_read
+            .fetch_add(len as u64, Ordering::Relaxed);
 
         self.decompressed_bytes_read
-            .fetch_add(decompressed_buf.len() as u64, Ordering::Relaxed);
+            .fetch_add(decompressed_len as u64, Ordering::Relaxed);
+
+        if let Some(metrics) = &self.config.metrics {
+            metrics.on_read(decompressed_len as u64);
+        }
+
+        Ok(Record {
+            object_id: read_pid,
+            data,
+            metadata,
+            crc: u32::from_le_bytes(crc_expected),
+        })
+    }
+}
+
+/// Advises the operating system that a range of a file will
+/// be read soon.
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+fn advise_will_need(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::raw::c_int;
+    use std::os::unix::io::AsRawFd;
+
+    const POSIX_FADV_WILLNEED: c_int = 3;
 
-        Ok(Some(decompressed_buf))
+    extern "C" {
+        fn posix_fadvise(fd: c_int, offset: i64, len: i64, advice: c_int) -> c_int;
     }
+
+    let (offset, len) = match (i64::try_from(offset), i64::try_from(len)) {
+        (Ok(offset), Ok(len)) => (offset, len),
+        _ => return Ok(()),
+    };
+
+    // posix_fadvise returns an error number rather than
+    // setting errno
+    let ret = unsafe { posix_fadvise(file.as_raw_fd(), offset, len, POSIX_FADV_WILLNEED) };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(annotate!(io::Error::from_raw_os_error(ret)))
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+fn advise_will_need(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
 }
@@ -1,138 +1,229 @@
 use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::ops::Bound::{Included, Unbounded};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{AtomicPtr, AtomicU64, Ordering::SeqCst},
-    Arc,
+    Arc, Mutex,
 };
 
 use concurrent_map::ConcurrentMap;
-use fault_injection::fallible;
+use fault_injection::{annotate, fallible};
 
+use crate::writepath::shard_directory_name;
 use crate::{
-    read_trailer, Config, DiskLocation, FileAndMetadata, FileMap, LocationTable, Map, Marble,
-    Metadata, NEW_WRITE_BATCH_MASK,
+    read_trailer, Checkpoint, Config, DiskLocation, FileAndMetadata, FileHandle, FileMap,
+    LocationTable, Map, Marble, MarbleError, Metadata, ObjectCache, ObjectId, OpenFiles,
+    RecoveryEvent, RecoveryPolicy, RelativeDiskLocation, SyncOnDrop, ZstdDict,
+    NEW_WRITE_BATCH_MASK, RESERVED_OBJECT_ID, TRAILER_MIN_LEN,
 };
 
 const HEAP_DIR_SUFFIX: &str = "heap";
 const WARN: &str = "DO_NOT_PUT_YOUR_FILES_HERE";
-const LEGEND: &str = "             lsn   trailer_offset  present_objects generation";
+const QUARANTINE_DIR: &str = "quarantine";
+const LEGEND: &str = "             lsn   trailer_offset  present_objects generation partition";
+
+/// A storage file found in the heap directory, along with
+/// the metadata parsed from its name.
+type StorageFile = (Metadata, fs::DirEntry);
 
 impl Config {
-    pub fn open(&self) -> io::Result<Marble> {
+    /// Open the store at `Config::path`, recovering the
+    /// location of every object from its storage files.
+    ///
+    /// The storage directory is exclusively locked for as
+    /// long as the returned `Marble`, or any clone or
+    /// `Snapshot` of it, is alive, so that two processes
+    /// can never modify the same store at once. Opening a
+    /// store that is already open returns an `Io` error of
+    /// kind `WouldBlock`. Returns `MarbleError::Corruption`
+    /// if the LSN ranges of two storage files overlap, for
+    /// instance because one of them was renamed, rather than
+    /// reading objects from the wrong file.
+    pub fn open(&self) -> Result<Marble, MarbleError> {
+        self.open_inner(None)
+    }
+
+    /// Opens the store, first removing the storage files that
+    /// were written after `checkpoint` if one is given.
+    pub(crate) fn open_inner(&self, checkpoint: Option<Checkpoint>) -> Result<Marble, MarbleError> {
         let config = self.clone();
 
         use fs2::FileExt;
 
         config.validate()?;
 
+        if checkpoint.is_some() && config.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "a store can not be restored to a checkpoint with `Config::read_only` set",
+            )
+            .into());
+        }
+
         log::debug!("opening Marble at {:?}", config.path);
 
         // initialize directories if not present
         let heap_dir = config.path.join(HEAP_DIR_SUFFIX);
 
-        if let Err(e) = fs::read_dir(&heap_dir) {
-            if e.kind() == io::ErrorKind::NotFound {
-                let _ = fs::create_dir_all(&heap_dir);
+        if !config.read_only {
+            if let Err(e) = fs::read_dir(&heap_dir) {
+                if e.kind() == io::ErrorKind::NotFound {
+                    let _ = fs::create_dir_all(&heap_dir);
+                }
             }
-        }
-
-        let _ = File::create(config.path.join(HEAP_DIR_SUFFIX).join(LEGEND));
-        let _ = File::create(config.path.join(WARN));
 
-        let mut file_lock_opts = OpenOptions::new();
-        file_lock_opts.create(true).read(true).write(true);
+            let _ = File::create(config.path.join(HEAP_DIR_SUFFIX).join(LEGEND));
+            let _ = File::create(config.path.join(WARN));
+        }
 
         let directory_lock = fallible!(File::open(config.path.join(HEAP_DIR_SUFFIX)));
-        fallible!(directory_lock.try_lock_exclusive());
+        if config.read_only {
+            // several read-only instances may inspect a store
+            // at once, but not while it is open for writing.
+            // this is not called as a method because it would
+            // resolve to `File::try_lock_shared` on newer
+            // versions of std.
+            fallible!(FileExt::try_lock_shared(&directory_lock));
+        } else {
+            fallible!(directory_lock.try_lock_exclusive());
+        }
 
-        let fams = ConcurrentMap::default();
+        let fams: ConcurrentMap<_, Arc<FileAndMetadata>, 16, 1> = ConcurrentMap::default();
         let mut max_file_lsn = 0;
         let mut max_file_size = 0;
+        let mut max_object_id = 0;
 
-        let mut recovery_page_table = Map::default();
-
-        let files = read_storage_directory(heap_dir)?;
-
-        for (metadata, entry) in files {
-            let mut options = OpenOptions::new();
-            options.read(true);
-
-            let mut file = fallible!(options.open(entry.path()));
+        let location_table: LocationTable = LocationTable::default();
+        let open_files = config
+            .max_open_files
+            .map(|max_open_files| Arc::new(OpenFiles::new(max_open_files)));
+        #[cfg(feature = "runtime_validation")]
+        let mut debug_history = crate::debug_history::DebugHistory::default();
 
-            let (trailer, zstd_dict) =
-                read_trailer(&mut file, metadata.trailer_offset, metadata.file_size)?;
+        let (mut files, shard_directories) = read_storage_directory(heap_dir, &config)?;
 
-            for (object_id, relative_loc) in trailer {
-                // add file base LSN to relative offset
-                let location = relative_loc.to_absolute(metadata.lsn);
+        if let Some(checkpoint) = checkpoint {
+            remove_files_after_checkpoint(
+                checkpoint,
+                &mut files,
+                &directory_lock,
+                &shard_directories,
+                &config,
+            )?;
+        }
 
-                log::trace!("inserting object_id {object_id} at location {location:?}");
-                if let Some(old) = recovery_page_table.insert(object_id, location) {
-                    assert!(
-                        (old.lsn() & NEW_WRITE_BATCH_MASK)
-                            < (location.lsn() & NEW_WRITE_BATCH_MASK),
-                        "must always apply locations in monotonic order. old {old:?} should be < \
-                         new {location:?}"
-                    );
-                }
-            }
+        check_file_lsn_ranges(&files)?;
+
+        // trailers are applied directly to the location table as
+        // each file is read, keeping the live object count of
+        // every file up to date, rather than being collected
+        // into an intermediate map first.
+        open_storage_files(files, |recovered_file| {
+            let RecoveredFile {
+                metadata,
+                path,
+                file,
+                file_size,
+                trailer,
+                zstd_dict,
+            } = recovered_file;
 
-            let file_size = fallible!(entry.metadata()).len();
             max_file_size = max_file_size.max(file_size);
             max_file_lsn = max_file_lsn.max(metadata.lsn & NEW_WRITE_BATCH_MASK);
 
             let file_location = DiskLocation::new_fam(metadata.lsn);
+            let lsn = metadata.lsn;
 
             let fam = FileAndMetadata {
                 live_objects: 0.into(),
                 metadata: AtomicPtr::default(),
                 path: AtomicPtr::default(),
-                file: file,
+                handle: FileHandle::new(file_location, file, open_files.clone()),
                 location: file_location,
                 generation: metadata.generation,
+                partition: metadata.partition,
                 rewrite_claim: false.into(),
                 synced: true.into(),
-                zstd_dict: zstd_dict,
+                read_only: config.read_only,
+                zstd_dict,
             };
 
-            fam.install_metadata_and_path(metadata, entry.path().into());
+            fam.install_metadata_and_path(metadata, path);
 
+            let fam = Arc::new(fam);
             log::debug!("inserting new fam at location {:?}", file_location);
-            assert!(fams.insert(Reverse(file_location), Arc::new(fam)).is_none());
-        }
+            assert!(fams.insert(Reverse(file_location), fam.clone()).is_none());
 
-        let location_table: LocationTable = LocationTable::default();
-        #[cfg(feature = "runtime_validation")]
-        let mut debug_history = crate::debug_history::DebugHistory::default();
+            for (object_id, relative_loc) in trailer {
+                // add file base LSN to relative offset
+                let location = relative_loc.to_absolute(lsn);
 
-        // initialize fam utilization from page table
-        let mut max_object_id = 0;
-        for (object_id, disk_location) in recovery_page_table {
-            max_object_id = max_object_id.max(object_id);
-            #[cfg(feature = "runtime_validation")]
-            debug_history.mark_add(object_id, disk_location);
-            let (_l, fam) = fams
-                .range((Included(Reverse(disk_location)), Unbounded))
-                .next()
-                .unwrap();
-            fam.live_objects.fetch_add(1, SeqCst);
-            location_table.store(object_id, disk_location);
-        }
+                log::trace!("inserting object_id {object_id} at location {location:?}");
+                if let Some(old) = location_table.load(object_id) {
+                    assert!(
+                        (old.lsn() & NEW_WRITE_BATCH_MASK)
+                            < (location.lsn() & NEW_WRITE_BATCH_MASK),
+                        "must always apply locations in monotonic order. old {old:?} should be < \
+                         new {location:?}"
+                    );
+                    let (_l, old_fam) = fams
+                        .range((Included(Reverse(old)), Unbounded))
+                        .next()
+                        .unwrap();
+                    old_fam.live_objects.fetch_sub(1, SeqCst);
+                    #[cfg(feature = "runtime_validation")]
+                    debug_history.mark_remove(object_id, old);
+                }
 
+                if object_id != RESERVED_OBJECT_ID {
+                    max_object_id = max_object_id.max(object_id);
+                }
+                #[cfg(feature = "runtime_validation")]
+                debug_history.mark_add(object_id, location);
+                fam.live_objects.fetch_add(1, SeqCst);
+                location_table.store(object_id, location);
+            }
+
+            Ok(())
+        })?;
+
+        // a new store starts at lsn 1, because a location of 0
+        // is never valid, and a recovered store continues after
+        // the end of its last file.
         let next_file_lsn = AtomicU64::new(max_file_lsn + max_file_size + 1);
 
+        let cache = ObjectCache::new(config.cache_bytes);
+
+        let file_map = FileMap {
+            fams,
+            next_file_lsn: Arc::new(next_file_lsn),
+            sync_mode: config.sync_mode,
+            open_files,
+        };
+
+        let sync_on_drop = Arc::new(SyncOnDrop {
+            file_map: file_map.clone(),
+            directory_lock,
+            shard_directories: Mutex::new(shard_directories),
+            temporary_path: config.temporary.then(|| config.path.clone()),
+        });
+
         Ok(Marble {
             location_table,
             max_object_id: Arc::new(max_object_id.into()),
-            file_map: FileMap {
-                fams: fams,
-                next_file_lsn: Arc::new(next_file_lsn),
-            },
+            file_map,
             config,
-            directory_lock: Arc::new(directory_lock),
+            sync_on_drop,
+            cache: Arc::new(cache),
+            snapshot_lock: Arc::default(),
+            space_reclaimed: Arc::default(),
+            poisoned: Arc::default(),
+            last_batch_id: Arc::default(),
+            id_lease: Arc::default(),
+            ref_counts: Arc::default(),
             #[cfg(feature = "runtime_validation")]
             debug_history: Arc::new(debug_history.into()),
             compressed_bytes_read: Arc::new(0.into()),
@@ -140,16 +231,163 @@ impl Config {
             compressed_bytes_written: Arc::new(0.into()),
             decompressed_bytes_written: Arc::new(0.into()),
             high_level_user_bytes_written: Arc::new(0.into()),
+            objects_written: Arc::new(0.into()),
+            write_batches: Arc::new(0.into()),
+            last_write_batch_nanos: Arc::new(0.into()),
         })
     }
 }
 
-fn read_storage_directory(heap_dir: PathBuf) -> io::Result<Vec<(Metadata, fs::DirEntry)>> {
+/// Finds the storage files in the heap directory and its
+/// shard directories, sorted by LSN, and opens a handle to
+/// each shard directory for fsyncing. Files written by
+/// earlier versions directly into the heap directory are
+/// recovered as well.
+fn read_storage_directory(
+    heap_dir: PathBuf,
+    config: &Config,
+) -> io::Result<(Vec<StorageFile>, Map<u8, File>)> {
     let mut files = vec![];
+    let mut shard_directories = Map::default();
+
+    let shard_dirs = read_storage_files(&heap_dir, config, &mut files)?;
+
+    for (shard, shard_dir) in shard_dirs {
+        let nested_dirs = read_storage_files(&shard_dir, config, &mut files)?;
+        for nested_dir in nested_dirs.into_values() {
+            log::error!(
+                "encountered strange directory in internal directory: {:?}",
+                nested_dir,
+            );
+            report(config, RecoveryEvent::UnknownFile(nested_dir));
+        }
+        shard_directories.insert(shard, fallible!(File::open(&shard_dir)));
+    }
+
+    files.sort_by_key(|(metadata, _)| metadata.lsn & NEW_WRITE_BATCH_MASK);
+
+    Ok((files, shard_directories))
+}
+
+/// Removes the storage files written after `checkpoint`
+/// from `files`, which is sorted by LSN, and from disk, or
+/// moves them into the quarantine directory, according to
+/// `Config::recovery_policy`. Files are removed newest
+/// first, so that a crash leaves the store as it was after
+/// some earlier write batch.
+fn remove_files_after_checkpoint(
+    checkpoint: Checkpoint,
+    files: &mut Vec<StorageFile>,
+    heap_directory: &File,
+    shard_directories: &Map<u8, File>,
+    config: &Config,
+) -> io::Result<()> {
+    let retained = files
+        .partition_point(|(metadata, _)| metadata.lsn & NEW_WRITE_BATCH_MASK <= checkpoint.lsn);
+
+    if (retained as u64) < checkpoint.files {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "only {retained} of the {} storage files included in checkpoint {checkpoint:?} \
+                 still exist, so the store can not be restored to it",
+                checkpoint.files,
+            ),
+        ));
+    }
+
+    let quarantine_dir = config.path.join(QUARANTINE_DIR);
+    let quarantine = config.recovery_policy == RecoveryPolicy::QuarantineAhead;
+    if quarantine && retained < files.len() {
+        fallible!(fs::create_dir_all(&quarantine_dir));
+        crate::sync_directory(&fallible!(File::open(&config.path)))?;
+    }
+
+    for (metadata, entry) in files.drain(retained..).rev() {
+        if quarantine {
+            let quarantined_path = quarantine_dir.join(entry.file_name());
+            log::warn!(
+                "moving storage file {:?} with lsn {} to {:?} while restoring checkpoint {:?}",
+                entry.path(),
+                metadata.lsn & NEW_WRITE_BATCH_MASK,
+                quarantined_path,
+                checkpoint
+            );
+            fallible!(fs::rename(entry.path(), &quarantined_path));
+            report(
+                config,
+                RecoveryEvent::QuarantinedFileAfterCheckpoint(quarantined_path),
+            );
+        } else {
+            log::warn!(
+                "removing storage file {:?} with lsn {} while restoring checkpoint {:?}",
+                entry.path(),
+                metadata.lsn & NEW_WRITE_BATCH_MASK,
+                checkpoint
+            );
+            fallible!(fs::remove_file(entry.path()));
+            report(
+                config,
+                RecoveryEvent::RemovedFileAfterCheckpoint(entry.path()),
+            );
+        }
+    }
+
+    if quarantine {
+        crate::sync_directory(&fallible!(File::open(&quarantine_dir)))?;
+    }
+    crate::sync_directory(heap_directory)?;
+    for shard_directory in shard_directories.values() {
+        crate::sync_directory(shard_directory)?;
+    }
+
+    Ok(())
+}
+
+/// Checks that the LSN ranges of the storage files, which
+/// are sorted by LSN, are disjoint. Otherwise, locations in
+/// one file would be resolved to another when reading.
+fn check_file_lsn_ranges(files: &[StorageFile]) -> io::Result<()> {
+    for pair in files.windows(2) {
+        let (previous, previous_entry) = &pair[0];
+        let (next, next_entry) = &pair[1];
+
+        let previous_lsn = previous.lsn & NEW_WRITE_BATCH_MASK;
+        let next_lsn = next.lsn & NEW_WRITE_BATCH_MASK;
+
+        if next_lsn <= previous_lsn || next_lsn < previous_lsn + previous.trailer_offset {
+            return Err(annotate!(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "storage file {:?} with lsn {next_lsn} overlaps the lsn range of storage \
+                     file {:?}, which starts at lsn {previous_lsn} and holds {} bytes of \
+                     objects",
+                    next_entry.path(),
+                    previous_entry.path(),
+                    previous.trailer_offset,
+                ),
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the storage files in `dir` to `files`, removing
+/// temporary files left behind by a crash, and returns the
+/// shard directories found in it.
+fn read_storage_files(
+    dir: &Path,
+    config: &Config,
+    files: &mut Vec<StorageFile>,
+) -> io::Result<BTreeMap<u8, PathBuf>> {
+    let mut shard_dirs = BTreeMap::new();
+
     // parse file names
-    for entry_res in fallible!(fs::read_dir(heap_dir)) {
+    for entry_res in fallible!(fs::read_dir(dir)) {
         let entry = fallible!(entry_res);
-        let file_size = fallible!(entry.metadata()).len();
+        let entry_metadata = fallible!(entry.metadata());
+        let file_size = entry_metadata.len();
         let path = entry.path();
         let name = path
             .file_name()
@@ -159,25 +397,54 @@ fn read_storage_directory(heap_dir: PathBuf) -> io::Result<Vec<(Metadata, fs::Di
 
         log::trace!("examining filename {} in heap directory", name);
 
+        if entry_metadata.is_dir() {
+            match u8::from_str_radix(name, 16) {
+                Ok(shard) if shard_directory_name(shard) == name => {
+                    shard_dirs.insert(shard, path.clone());
+                }
+                _ => {
+                    log::error!(
+                        "encountered strange directory in internal directory: {:?}",
+                        path,
+                    );
+                    report(config, RecoveryEvent::UnknownFile(path.clone()));
+                }
+            }
+            continue;
+        }
+
         // remove files w/ temp name
         if name.ends_with("tmp") {
+            if config.read_only || config.keep_tmp_files {
+                log::warn!(
+                    "ignoring heap file that was not fully written before the last crash: {:?}",
+                    entry.path()
+                );
+                report(config, RecoveryEvent::IgnoredTemporaryFile(entry.path()));
+                continue;
+            }
+
             log::warn!(
                 "removing heap file that was not fully written before the last crash: {:?}",
                 entry.path()
             );
 
             fallible!(fs::remove_file(entry.path()));
+            report(config, RecoveryEvent::RemovedTemporaryFile(entry.path()));
             continue;
         }
 
         let metadata = match Metadata::parse(name, file_size) {
             Some(mn) => mn,
             None => {
-                if name != LEGEND {
+                // legend file names, including those written by
+                // earlier versions, start with a space
+                if !name.starts_with(' ') {
                     log::error!(
                         "encountered strange file in internal directory: {:?}",
                         entry.path(),
                     );
+                    report(config, RecoveryEvent::UnknownFile(entry.path()));
                 }
                 continue;
             }
@@ -186,7 +453,119 @@ fn read_storage_directory(heap_dir: PathBuf) -> io::Result<Vec<(Metadata, fs::Di
         files.push((metadata, entry));
     }
 
-    files.sort_by_key(|(metadata, _)| metadata.lsn & NEW_WRITE_BATCH_MASK);
+    Ok(shard_dirs)
+}
+
+/// Reports an event to `Config::metrics`.
+fn report(config: &Config, event: RecoveryEvent) {
+    if let Some(metrics) = &config.metrics {
+        metrics.on_recovery_event(event);
+    }
+}
+
+/// A storage file that has been opened and had its trailer
+/// read during recovery.
+struct RecoveredFile {
+    metadata: Metadata,
+    path: PathBuf,
+    file: File,
+    file_size: u64,
+    trailer: Vec<(ObjectId, RelativeDiskLocation)>,
+    zstd_dict: ZstdDict,
+}
+
+/// Opens the storage files and reads their trailers using
+/// several threads, because this dominates the time that
+/// recovery takes for stores with many files. Files are
+/// passed to `apply` in the same order as `files`, so that
+/// their trailers may be applied in LSN order. Only a few
+/// files per thread are read ahead of the ones being
+/// applied, so the memory used for trailers does not grow
+/// with the size of the store.
+fn open_storage_files<F>(files: Vec<StorageFile>, mut apply: F) -> io::Result<()>
+where
+    F: FnMut(RecoveredFile) -> io::Result<()>,
+{
+    const FILES_PER_THREAD: usize = 4;
+
+    let threads = std::thread::available_parallelism()
+        .map(usize::from)
+        .unwrap_or(1);
+
+    let mut files = files.into_iter();
+    loop {
+        let window: Vec<_> = files.by_ref().take(threads * FILES_PER_THREAD).collect();
+        if window.is_empty() {
+            return Ok(());
+        }
+
+        let chunk_size = window.len().div_ceil(threads);
+
+        let mut chunks = vec![];
+        let mut window = window.into_iter();
+        loop {
+            let chunk: Vec<_> = window.by_ref().take(chunk_size).collect();
+            if chunk.is_empty() {
+                break;
+            }
+            chunks.push(chunk);
+        }
+
+        let recovered_files = std::thread::scope(|scope| {
+            let handles: Vec<_> = chunks
+                .into_iter()
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .into_iter()
+                            .map(|(metadata, entry)| open_storage_file(metadata, entry))
+                            .collect::<io::Result<Vec<_>>>()
+                    })
+                })
+                .collect();
+
+            let mut recovered_files = vec![];
+            for handle in handles {
+                recovered_files.extend(handle.join().expect("recovery thread panicked")?);
+            }
+            Ok::<_, io::Error>(recovered_files)
+        })?;
+
+        for recovered_file in recovered_files {
+            apply(recovered_file)?;
+        }
+    }
+}
+
+fn open_storage_file(metadata: Metadata, entry: fs::DirEntry) -> io::Result<RecoveredFile> {
+    let mut options = OpenOptions::new();
+    options.read(true);
+
+    let file = fallible!(options.open(entry.path()));
+
+    if metadata.file_size < metadata.trailer_offset.saturating_add(TRAILER_MIN_LEN) {
+        return Err(annotate!(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "storage file {:?} has a size of {}, which is too small to contain its trailer at \
+                 offset {}. It was probably truncated by a crash or by something other than Marble.",
+                entry.path(),
+                metadata.file_size,
+                metadata.trailer_offset,
+            ),
+        )));
+    }
+
+    let (trailer, zstd_dict) = read_trailer(&file, metadata.trailer_offset, metadata.file_size)?;
+
+    let file_size = fallible!(entry.metadata()).len();
 
-    Ok(files)
+    Ok(RecoveredFile {
+        metadata,
+        path: entry.path(),
+        file,
+        file_size,
+        trailer,
+        zstd_dict,
+    })
 }
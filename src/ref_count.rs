@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use fault_injection::{annotate, fallible};
+
+use crate::writepath::{BatchData, NEW_WRITE_GENERATION};
+use crate::{sync_directory, Map, Marble, MarbleError, ObjectId};
+
+const REF_COUNT_FILE: &str = "object_ref_counts";
+const REF_COUNT_TMP_FILE: &str = "object_ref_counts-tmp";
+
+// Reference count file format:
+//
+// entry count | (object ID | reference count)* | crc
+//
+// where every field is a little-endian u64 except for crc,
+// which is a crc32 of everything before it. Objects with a
+// reference count of 1 are not included.
+
+/// The reference counts of objects that have more than one
+/// reference, which are loaded from the reference count file
+/// the first time that they are needed.
+pub(crate) type RefCounts = BTreeMap<ObjectId, u64>;
+
+impl Marble {
+    /// Adds a reference to a stored object, for instance when
+    /// deduplicated content that it holds is referred to by
+    /// another logical object, and returns its new reference
+    /// count. Every stored object starts with a reference
+    /// count of 1, and `decrement_ref` only deletes it once
+    /// its last reference is removed.
+    ///
+    /// Reference counts are persisted in a small file in
+    /// `Config::path` that is replaced on every change, so
+    /// they are intended for a modest number of shared
+    /// objects. Deleting an object with `write_batch`
+    /// discards its reference count, while overwriting it
+    /// keeps it. Returns `MarbleError::NotFound` if the
+    /// object is not stored.
+    pub fn increment_ref(&self, object_id: ObjectId) -> Result<u64, MarbleError> {
+        self.check_writable()?;
+
+        let mut ref_counts_opt = self.ref_counts.lock().unwrap();
+        let ref_counts = self.load_ref_counts(&mut ref_counts_opt)?;
+
+        if self.location(object_id).is_none() {
+            return Err(not_stored(object_id));
+        }
+
+        let ref_count = ref_counts.get(&object_id).copied().unwrap_or(1) + 1;
+
+        let mut new_ref_counts = ref_counts.clone();
+        new_ref_counts.insert(object_id, ref_count);
+        write_ref_counts(&self.config.path, &new_ref_counts)?;
+        *ref_counts = new_ref_counts;
+
+        Ok(ref_count)
+    }
+
+    /// Removes a reference to a stored object that was added
+    /// by `increment_ref`, and returns its new reference
+    /// count. Once the count reaches 0, the object is deleted
+    /// as if by `write_batch`. Returns `MarbleError::NotFound`
+    /// if the object is not stored.
+    ///
+    /// If this fails or is interrupted by a crash before the
+    /// deletion is written, the object may be left stored
+    /// with a reference count of 1.
+    pub fn decrement_ref(&self, object_id: ObjectId) -> Result<u64, MarbleError> {
+        self.check_writable()?;
+
+        let mut ref_counts_opt = self.ref_counts.lock().unwrap();
+        let ref_counts = self.load_ref_counts(&mut ref_counts_opt)?;
+
+        if self.location(object_id).is_none() {
+            return Err(not_stored(object_id));
+        }
+
+        let ref_count = ref_counts.get(&object_id).copied().unwrap_or(1) - 1;
+
+        if ref_count > 0 {
+            let mut new_ref_counts = ref_counts.clone();
+            if ref_count == 1 {
+                new_ref_counts.remove(&object_id);
+            } else {
+                new_ref_counts.insert(object_id, ref_count);
+            }
+            write_ref_counts(&self.config.path, &new_ref_counts)?;
+            *ref_counts = new_ref_counts;
+
+            return Ok(ref_count);
+        }
+
+        // the lock is held while the object is deleted, so
+        // that `increment_ref` cannot add a reference to it
+        // in the meantime
+        self.apply_backpressure()?;
+
+        let deletion = [(object_id, None::<BatchData<&[u8]>>)];
+        self.shard_batch(
+            deletion,
+            NEW_WRITE_GENERATION,
+            &Map::default(),
+            None,
+            Some(&mut ref_counts_opt),
+        )?;
+
+        Ok(0)
+    }
+
+    /// Returns the reference count of an object, which is 1
+    /// unless it was changed with `increment_ref` or
+    /// `decrement_ref`, or 0 if the object is not stored.
+    pub fn ref_count(&self, object_id: ObjectId) -> Result<u64, MarbleError> {
+        let mut ref_counts_opt = self.ref_counts.lock().unwrap();
+        let ref_counts = self.load_ref_counts(&mut ref_counts_opt)?;
+
+        if self.location(object_id).is_none() {
+            return Ok(0);
+        }
+
+        Ok(ref_counts.get(&object_id).copied().unwrap_or(1))
+    }
+
+    /// Discards the reference counts of objects that were
+    /// deleted by a write batch.
+    pub(crate) fn discard_ref_counts(&self, object_ids: &[ObjectId]) -> io::Result<()> {
+        let mut ref_counts_opt = self.ref_counts.lock().unwrap();
+        self.discard_ref_counts_locked(&mut ref_counts_opt, object_ids)
+    }
+
+    /// Like `discard_ref_counts`, for callers that already
+    /// hold the lock of the reference counts. They are
+    /// discarded in memory even if writing the file fails,
+    /// as the objects are deleted either way, and the next
+    /// change writes the file again.
+    pub(crate) fn discard_ref_counts_locked(
+        &self,
+        ref_counts_opt: &mut Option<RefCounts>,
+        object_ids: &[ObjectId],
+    ) -> io::Result<()> {
+        let ref_counts = self.load_ref_counts(ref_counts_opt)?;
+
+        if !object_ids
+            .iter()
+            .any(|object_id| ref_counts.contains_key(object_id))
+        {
+            return Ok(());
+        }
+
+        let mut new_ref_counts = ref_counts.clone();
+        for object_id in object_ids {
+            new_ref_counts.remove(object_id);
+        }
+        let res = write_ref_counts(&self.config.path, &new_ref_counts);
+        *ref_counts = new_ref_counts;
+
+        res
+    }
+
+    fn load_ref_counts<'a>(
+        &self,
+        ref_counts_opt: &'a mut Option<RefCounts>,
+    ) -> io::Result<&'a mut RefCounts> {
+        if ref_counts_opt.is_none() {
+            let mut ref_counts = read_ref_counts(&self.config.path)?;
+
+            // a crash after an object was deleted may have left
+            // its reference count behind
+            ref_counts.retain(|object_id, _| self.location(*object_id).is_some());
+
+            *ref_counts_opt = Some(ref_counts);
+        }
+
+        Ok(ref_counts_opt.as_mut().unwrap())
+    }
+}
+
+fn not_stored(object_id: ObjectId) -> MarbleError {
+    MarbleError::NotFound(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("object {object_id} is not stored"),
+    ))
+}
+
+fn read_ref_counts(path: &Path) -> io::Result<RefCounts> {
+    let mut file = match File::open(path.join(REF_COUNT_FILE)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(RefCounts::new()),
+        Err(e) => return Err(annotate!(e)),
+    };
+
+    let mut buf = vec![];
+    fallible!(file.read_to_end(&mut buf));
+
+    let corrupt = || {
+        annotate!(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupt object reference count file",
+        ))
+    };
+
+    if buf.len() < 12 || (buf.len() - 12) % 16 != 0 {
+        return Err(corrupt());
+    }
+
+    let (entries_buf, crc_buf) = buf.split_at(buf.len() - 4);
+
+    if crc32fast::hash(entries_buf).to_le_bytes() != crc_buf {
+        return Err(corrupt());
+    }
+
+    let count = u64::from_le_bytes(entries_buf[..8].try_into().unwrap());
+    if count != (entries_buf.len() as u64 - 8) / 16 {
+        return Err(corrupt());
+    }
+
+    Ok(entries_buf[8..]
+        .chunks(16)
+        .map(|entry| {
+            let object_id = u64::from_le_bytes(entry[..8].try_into().unwrap());
+            let ref_count = u64::from_le_bytes(entry[8..].try_into().unwrap());
+            (object_id, ref_count)
+        })
+        .collect())
+}
+
+/// Atomically replaces the reference count file, so that a
+/// crash leaves either the old or the new reference counts.
+fn write_ref_counts(path: &Path, ref_counts: &RefCounts) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(8 + ref_counts.len() * 16 + 4);
+    buf.extend_from_slice(&(ref_counts.len() as u64).to_le_bytes());
+    for (object_id, ref_count) in ref_counts {
+        buf.extend_from_slice(&object_id.to_le_bytes());
+        buf.extend_from_slice(&ref_count.to_le_bytes());
+    }
+    let crc = crc32fast::hash(&buf).to_le_bytes();
+    buf.extend_from_slice(&crc);
+
+    let tmp_path = path.join(REF_COUNT_TMP_FILE);
+
+    let mut file = fallible!(OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path));
+    fallible!(file.write_all(&buf));
+    fallible!(file.sync_all());
+    drop(file);
+
+    fallible!(fs::rename(&tmp_path, path.join(REF_COUNT_FILE)));
+
+    let directory = fallible!(File::open(path));
+    sync_directory(&directory)
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::sync::{atomic::Ordering::Acquire, Arc};
+
+use crate::{
+    readpath::BoxedBuffers, DiskLocation, FileAndMetadata, Map, Marble, MarbleError, ObjectId,
+};
+
+/// A consistent view of the objects stored in a `Marble` at
+/// the time that it was created with [`Marble::snapshot`].
+///
+/// Write batches that complete after the snapshot is
+/// created are not visible through it. Storage files that
+/// the snapshot reads from are kept on disk until it is
+/// dropped, even if maintenance removes them from the
+/// store in the mean time.
+pub struct Snapshot {
+    marble: Marble,
+    locations: Map<ObjectId, DiskLocation>,
+    fams: BTreeMap<DiskLocation, Arc<FileAndMetadata>>,
+}
+
+impl std::fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("objects", &self.locations.len())
+            .field("files", &self.fams.len())
+            .finish()
+    }
+}
+
+impl Snapshot {
+    /// Read an object as of the time that this snapshot was
+    /// created. If this object was unknown or had been
+    /// removed at that time, returns `Ok(None)`. If there
+    /// is an IO problem, returns Err.
+    pub fn read(&self, object_id: ObjectId) -> Result<Option<Box<[u8]>>, MarbleError> {
+        let location = if let Some(location) = self.locations.get(&object_id) {
+            *location
+        } else {
+            return Ok(None);
+        };
+
+        let data = self.read_location(object_id, location)?;
+
+        Ok(Some(data))
+    }
+
+    fn read_location(
+        &self,
+        object_id: ObjectId,
+        location: DiskLocation,
+    ) -> Result<Box<[u8]>, MarbleError> {
+        let (_, fam) = self
+            .fams
+            .range(..=location)
+            .next_back()
+            .expect("snapshot pins every file that its locations point into");
+
+        let record = self
+            .marble
+            .read_from_fam(fam, object_id, location, None, &BoxedBuffers)?;
+
+        Ok(record.data)
+    }
+}
+
+impl Marble {
+    /// Capture the current contents of the store, so that
+    /// several objects may be read as of the same point in
+    /// time while write batches and maintenance continue.
+    /// Each write batch is either entirely visible through
+    /// the snapshot or not at all.
+    ///
+    /// This copies the location of every stored object, and
+    /// takes time and memory proportional to the number of
+    /// objects. Write batches are blocked from updating the
+    /// location table while the copy is made.
+    pub fn snapshot(&self) -> Snapshot {
+        let snapshot_guard = self.snapshot_lock.write().unwrap();
+
+        let max = self.max_object_id.load(Acquire);
+
+        let mut locations = Map::default();
+
+        for object_id in 0..=max {
+            if let Some(location) = self.location_table.load(object_id) {
+                if !location.is_delete() {
+                    locations.insert(object_id, location);
+                }
+            }
+        }
+
+        // every location copied above points into a file
+        // that is still present, because files are only
+        // removed after nothing in the location table
+        // points into them.
+        let fams = self
+            .file_map
+            .fams
+            .iter()
+            .map(|(location, fam)| (location.0, fam))
+            .collect();
+
+        drop(snapshot_guard);
+
+        Snapshot {
+            marble: self.clone(),
+            locations,
+            fams,
+        }
+    }
+
+    /// Iterate over every stored object along with its data,
+    /// for instance to rebuild an external index. This
+    /// iterates over a [`Marble::snapshot`] taken when it is
+    /// called, so write batches and maintenance may continue
+    /// concurrently without objects being skipped or seen
+    /// twice. Objects are read in the order that they are
+    /// laid out in storage files, not in object ID order.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(ObjectId, Box<[u8]>), MarbleError>> {
+        let snapshot = self.snapshot();
+
+        let mut locations: Vec<(DiskLocation, ObjectId)> = snapshot
+            .locations
+            .iter()
+            .map(|(object_id, location)| (*location, *object_id))
+            .collect();
+        locations.sort_unstable();
+
+        locations.into_iter().map(move |(location, object_id)| {
+            let data = snapshot.read_location(object_id, location)?;
+            Ok((object_id, data))
+        })
+    }
+}
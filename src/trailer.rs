@@ -6,6 +6,10 @@ use fault_injection::{annotate, fallible};
 
 use crate::{read_range_at, Map, ObjectId, RelativeDiskLocation, ZstdDict};
 
+/// The size of a trailer without any entries or dictionary:
+/// crc (4) | entry count (8) | dictionary size (8)
+pub(crate) const TRAILER_MIN_LEN: u64 = 4 + 8 + 8;
+
 pub(crate) fn read_trailer(
     file: &File,
     trailer_offset: u64,
@@ -18,7 +22,7 @@ pub(crate) fn read_trailer(
 pub(crate) fn read_trailer_from_buf(
     buf: &[u8],
 ) -> io::Result<(Vec<(ObjectId, RelativeDiskLocation)>, ZstdDict)> {
-    if buf.len() < 20 {
+    if (buf.len() as u64) < TRAILER_MIN_LEN {
         return Err(annotate!(io::Error::new(
             io::ErrorKind::InvalidData,
             format!("trailer is smaller than the minimum possible size")
@@ -109,7 +113,11 @@ pub(crate) fn write_trailer<'a>(
 
     log::trace!("writing offsets at trailer offset {}", buf.len());
 
-    for (object_id, relative_location) in new_shifted_relative_locations {
+    let mut sorted_locations: Vec<(&ObjectId, &RelativeDiskLocation)> =
+        new_shifted_relative_locations.iter().collect();
+    sorted_locations.sort_unstable_by_key(|(object_id, _)| **object_id);
+
+    for (object_id, relative_location) in sorted_locations {
         let object_id_bytes: &[u8; 8] = &object_id.to_le_bytes();
         let loc_bytes: &[u8; 8] = &relative_location.to_raw().to_le_bytes();
         buf.extend_from_slice(object_id_bytes);
@@ -140,7 +148,6 @@ pub(crate) fn write_trailer<'a>(
         "wrote zstd dict with crc {}",
         crc32fast::hash(zstd_dict_buffer)
     );
-    fallible!(file.sync_all());
 
     Ok(())
 }
@@ -0,0 +1,317 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::io;
+use std::ops::Bound::{Included, Unbounded};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering::Acquire;
+
+use fault_injection::fallible;
+
+use crate::writepath::{BatchData, NEW_WRITE_GENERATION};
+use crate::{
+    decode_record_len, hash, DiskLocation, FileAndMetadata, Map, Marble, MarbleError, ObjectId,
+    HEADER_LEN, RESERVED_OBJECT_ID,
+};
+
+/// The results of a call to [`Marble::verify`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The number of stored objects that were read and checked.
+    pub objects_checked: u64,
+    /// Objects whose stored bytes fail their crc check, or
+    /// whose header contains a different object ID or an
+    /// impossible length.
+    pub corrupt_objects: Vec<ObjectId>,
+    /// Objects whose location does not point at the object
+    /// section of any existing storage file.
+    pub dangling_objects: Vec<ObjectId>,
+    /// Storage files that no object location points into.
+    /// These are typically removed by the next call to
+    /// `Marble::maintenance`.
+    pub orphan_files: Vec<PathBuf>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_objects.is_empty()
+            && self.dangling_objects.is_empty()
+            && self.orphan_files.is_empty()
+    }
+}
+
+/// The results of a call to [`Marble::repair`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Objects that were corrupt or dangling, and which have
+    /// been deleted from the store so that reading them
+    /// returns `Ok(None)`.
+    pub quarantined_objects: Vec<ObjectId>,
+    /// Storage files that were removed because no live
+    /// objects remained in them after quarantining.
+    pub removed_files: Vec<PathBuf>,
+}
+
+enum Check {
+    Ok,
+    Corrupt,
+    Dangling,
+}
+
+impl Marble {
+    /// Reads every stored object and checks it against its
+    /// crc and embedded object ID, and checks that every
+    /// location points into an existing storage file. Nothing
+    /// is modified, so problems that are found are only
+    /// reported. Writes and maintenance that run concurrently
+    /// with this method may cause spurious reports.
+    ///
+    /// Returns Err if there is an IO problem that prevents
+    /// verification from completing.
+    pub fn verify(&self) -> Result<VerifyReport, MarbleError> {
+        let mut report = VerifyReport::default();
+        let mut inhabited_files = HashSet::new();
+
+        let max = self.max_object_id.load(Acquire);
+
+        // the ID of the last batch written by
+        // `write_batch_once` is stored like an object
+        for object_id in (0..=max).chain([RESERVED_OBJECT_ID]) {
+            let location = if let Some(location) = self.location_table.load(object_id) {
+                location
+            } else {
+                continue;
+            };
+
+            let fam = if let Some((_, fam)) = self
+                .file_map
+                .fams
+                .range((Included(Reverse(location)), Unbounded))
+                .next()
+            {
+                fam
+            } else {
+                report.dangling_objects.push(object_id);
+                continue;
+            };
+
+            if location.is_delete() {
+                inhabited_files.insert(fam.location);
+                continue;
+            }
+
+            let check = verify_object(&fam, object_id, location, true)?;
+
+            report.objects_checked += 1;
+
+            match check {
+                Check::Ok => {
+                    inhabited_files.insert(fam.location);
+                }
+                Check::Corrupt => {
+                    inhabited_files.insert(fam.location);
+                    report.corrupt_objects.push(object_id);
+                }
+                Check::Dangling => report.dangling_objects.push(object_id),
+            }
+        }
+
+        for (location, fam) in &self.file_map.fams {
+            if !inhabited_files.contains(&location.0) {
+                if let Some(path) = fam.path() {
+                    report.orphan_files.push(path.clone());
+                }
+            }
+        }
+
+        report.orphan_files.sort();
+
+        Ok(report)
+    }
+
+    /// Runs [`Marble::verify`] and deletes every corrupt or
+    /// dangling object that it finds, then removes storage
+    /// files that no longer contain any live objects, leaving
+    /// a degraded but consistent store. The deletions are
+    /// written as a single durable write batch before any
+    /// file is removed, so a crash during repair leaves the
+    /// store either unrepaired or repaired, and calling this
+    /// again after recovery is always safe.
+    ///
+    /// This should not be called concurrently with writes or
+    /// maintenance, which may cause healthy objects to be
+    /// reported as corrupt and then deleted.
+    pub fn repair(&self) -> Result<RepairReport, MarbleError> {
+        self.check_writable()?;
+
+        let verify_report = self.verify()?;
+
+        let mut quarantined_objects = verify_report.corrupt_objects;
+        quarantined_objects.extend(verify_report.dangling_objects);
+        quarantined_objects.sort_unstable();
+
+        if !quarantined_objects.is_empty() {
+            log::warn!("repair is quarantining objects {quarantined_objects:?}");
+
+            let deletions = quarantined_objects
+                .iter()
+                .filter(|object_id| **object_id != RESERVED_OBJECT_ID)
+                .map(|object_id| (*object_id, None::<BatchData<&[u8]>>));
+
+            // a damaged batch ID is removed, after which
+            // `write_batch_once` writes any batch again
+            let batch_id = quarantined_objects
+                .contains(&RESERVED_OBJECT_ID)
+                .then_some(None);
+
+            self.shard_batch(
+                deletions,
+                NEW_WRITE_GENERATION,
+                &Map::default(),
+                batch_id,
+                None,
+            )?;
+            self.sync_all()?;
+
+            if batch_id.is_some() {
+                *self.last_batch_id.lock().unwrap() = None;
+            }
+        }
+
+        let files_before = self.file_paths();
+        self.prune_empty_files()?;
+        let files_after = self.file_paths();
+
+        let mut removed_files: Vec<PathBuf> =
+            files_before.difference(&files_after).cloned().collect();
+        removed_files.sort();
+
+        Ok(RepairReport {
+            quarantined_objects,
+            removed_files,
+        })
+    }
+
+    /// A cheap consistency check between the location table
+    /// and the storage files, for deciding whether a full
+    /// [`Marble::verify`] is needed. Only the header of each
+    /// stored object is read, and the IDs of objects whose
+    /// location does not point at a record header with their
+    /// object ID and a possible length are returned in
+    /// ascending order. Unlike `verify`, corruption of the
+    /// stored bytes of an object is not detected.
+    ///
+    /// Returns Err if there is an IO problem that prevents
+    /// the check from completing.
+    #[doc(alias = "check_consistency")]
+    pub fn check_locations(&self) -> Result<Vec<ObjectId>, MarbleError> {
+        let mut inconsistent = vec![];
+
+        let max = self.max_object_id.load(Acquire);
+
+        for object_id in (0..=max).chain([RESERVED_OBJECT_ID]) {
+            let location = match self.location_table.load(object_id) {
+                Some(location) if !location.is_delete() => location,
+                _ => continue,
+            };
+
+            let check = if let Some((_, fam)) = self
+                .file_map
+                .fams
+                .range((Included(Reverse(location)), Unbounded))
+                .next()
+            {
+                verify_object(&fam, object_id, location, false)?
+            } else {
+                Check::Dangling
+            };
+
+            if !matches!(check, Check::Ok) {
+                inconsistent.push(object_id);
+            }
+        }
+
+        Ok(inconsistent)
+    }
+
+    fn file_paths(&self) -> HashSet<PathBuf> {
+        self.file_map
+            .fams
+            .iter()
+            .filter_map(|(_, fam)| fam.path().cloned())
+            .collect()
+    }
+}
+
+/// Checks the record header at `location`, and the stored
+/// bytes that follow it if `check_data` is set.
+fn verify_object(
+    fam: &FileAndMetadata,
+    object_id: ObjectId,
+    location: DiskLocation,
+    check_data: bool,
+) -> io::Result<Check> {
+    let metadata = if let Some(metadata) = fam.metadata() {
+        metadata
+    } else {
+        // the file is still being written
+        return Ok(Check::Ok);
+    };
+
+    let file_offset = location.lsn() - fam.location.lsn();
+
+    if file_offset + HEADER_LEN as u64 > metadata.trailer_offset {
+        return Ok(Check::Dangling);
+    }
+
+    let file = fam.file()?;
+    let mut header_buf = [0_u8; HEADER_LEN];
+    fallible!(file.read_exact_at(&mut header_buf, file_offset));
+
+    let crc_expected: [u8; 4] = header_buf[0..4].try_into().unwrap();
+    let pid_buf: [u8; 8] = header_buf[4..12].try_into().unwrap();
+    let len_buf: [u8; 8] = header_buf[12..].try_into().unwrap();
+
+    let object_offset = file_offset + HEADER_LEN as u64;
+    // an unknown record version is reported as an impossible
+    // length below
+    let len = decode_record_len(len_buf).unwrap_or(u64::MAX);
+
+    if len > metadata.trailer_offset - object_offset {
+        log::warn!(
+            "object {object_id} in file {:?} has an impossible length {len}",
+            fam.path()
+        );
+        return Ok(Check::Corrupt);
+    }
+
+    let read_pid = u64::from_le_bytes(pid_buf);
+
+    if object_id != read_pid {
+        log::warn!(
+            "object {object_id} in file {:?} is stored with object ID {read_pid}",
+            fam.path()
+        );
+        return Ok(Check::Corrupt);
+    }
+
+    if !check_data {
+        return Ok(Check::Ok);
+    }
+
+    let mut compressed_buf = vec![0; usize::try_from(len).unwrap()];
+    fallible!(file.read_exact_at(&mut compressed_buf, object_offset));
+
+    let crc_actual = hash(len_buf, pid_buf, &compressed_buf);
+
+    if crc_expected != crc_actual {
+        log::warn!(
+            "crc mismatch for object {object_id} in file {:?}",
+            fam.path()
+        );
+        return Ok(Check::Corrupt);
+    }
+
+    Ok(Check::Ok)
+}
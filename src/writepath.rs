@@ -1,16 +1,24 @@
+use std::collections::hash_map::Entry;
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufWriter, Write};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 
 use fault_injection::{fallible, maybe};
 
+use crate::ref_count::RefCounts;
 use crate::{
-    hash, write_trailer, DiskLocation, Map, Marble, Metadata, ObjectId, RelativeDiskLocation,
-    ZstdDict, HEADER_LEN,
+    encode_record_len, hash, retry_io, sync_directory, write_trailer, Backpressure, DiskLocation,
+    Map, Marble, MarbleError, Metadata, ObjectId, ObjectMetadata, RelativeDiskLocation, ZstdDict,
+    HEADER_LEN, METADATA_RECORD_VERSION, RECORD_VERSION, RESERVED_OBJECT_ID,
 };
 
 const HEAP_DIR_SUFFIX: &str = "heap";
-const NEW_WRITE_GENERATION: u8 = 0;
+pub(crate) const NEW_WRITE_GENERATION: u8 = 0;
+
+/// The objects of a batch that are written into one file.
+type FileObjects<B> = Map<ObjectId, Option<BatchData<B>>>;
 
 impl Marble {
     /// Write a batch of objects to disk. This function is
@@ -24,41 +32,377 @@ impl Marble {
     /// before calling this function occasionally in the
     /// background, then deleting corresponding logs after
     /// this function returns.
+    ///
+    /// Any `IntoIterator` over `(ObjectId, Option<B>)` pairs may
+    /// be passed, including a `Vec`, `HashMap`, `BTreeMap` or
+    /// [`crate::Batch`], where `None` represents a deletion. Objects are laid
+    /// out on disk in object ID order regardless of the order
+    /// that the batch iterates in, unless
+    /// `Config::sort_batches` is disabled, in which case they
+    /// are laid out in the order that they first appear in
+    /// the batch. If an object ID is present more than once
+    /// in the batch, only the last value for it is written.
+    /// Objects may be empty, and are then read back as an
+    /// empty slice, which is distinct from `None`. An empty
+    /// batch is a no-op that creates no file.
+    ///
+    /// Returns `MarbleError::ReservedObjectId` without writing
+    /// anything if the batch contains an object ID that is
+    /// greater than [`crate::MAX_OBJECT_ID`], and an `Io`
+    /// error of kind `Unsupported` without writing anything
+    /// if it contains an object that is larger than
+    /// `Config::max_object_size`.
+    ///
+    /// If writing a batch fails after it was partially
+    /// applied, for instance because the disk is full or an
+    /// fsync fails, the store becomes poisoned, and this
+    /// method, `maintenance`, `maintenance_shard`,
+    /// `compact_files` and `repair` return an `Io` error of
+    /// kind `Other` until it is reopened. Reads are still
+    /// served.
     #[doc(alias = "insert")]
     #[doc(alias = "set")]
     #[doc(alias = "put")]
-    pub fn write_batch<B, I>(&self, write_batch: I) -> io::Result<()>
+    pub fn write_batch<B, I>(&self, write_batch: I) -> Result<(), MarbleError>
     where
         B: AsRef<[u8]>,
         I: IntoIterator<Item = (ObjectId, Option<B>)>,
     {
+        self.check_writable()?;
+
+        let mut write_batch = write_batch.into_iter().peekable();
+        if write_batch.peek().is_none() {
+            return Ok(());
+        }
+
+        self.apply_backpressure()?;
+
+        let write_batch =
+            write_batch.map(|(object_id, data)| (object_id, data.map(BatchData::Object)));
+
         let old_locations = Map::default();
-        self.shard_batch(write_batch, NEW_WRITE_GENERATION, &old_locations)
+        self.shard_batch(
+            write_batch,
+            NEW_WRITE_GENERATION,
+            &old_locations,
+            None,
+            None,
+        )?;
+
+        Ok(())
+    }
+
+    /// Deletes every object in `object_ids` as a single write
+    /// batch, so that all of the deletions are written to one
+    /// storage file with at most one fsync, and are atomically
+    /// either all applied or none of them. This is equivalent
+    /// to calling `write_batch` with `None` for each object.
+    /// Deleting an object that does not exist is not an error.
+    #[doc(alias = "remove_batch")]
+    pub fn delete_batch(&self, object_ids: &[ObjectId]) -> Result<(), MarbleError> {
+        self.write_batch(
+            object_ids
+                .iter()
+                .map(|object_id| (*object_id, None::<&[u8]>)),
+        )
     }
 
+    /// Like `write_batch`, but stores a fixed-size
+    /// `ObjectMetadata` along with each object, for instance
+    /// a type tag or version, which is returned by
+    /// `read_with_metadata` without a separate lookup. The
+    /// metadata is covered by the object's crc, and is
+    /// compressed along with its data. Objects written
+    /// without metadata have metadata of all zeroes.
+    #[doc(alias = "write_batch_with_meta")]
+    pub fn write_batch_with_metadata<B, I>(&self, write_batch: I) -> Result<(), MarbleError>
+    where
+        B: AsRef<[u8]>,
+        I: IntoIterator<Item = (ObjectId, Option<(ObjectMetadata, B)>)>,
+    {
+        self.check_writable()?;
+
+        let mut write_batch = write_batch.into_iter().peekable();
+        if write_batch.peek().is_none() {
+            return Ok(());
+        }
+
+        self.apply_backpressure()?;
+
+        let write_batch = write_batch.map(|(object_id, data_opt)| {
+            let data_opt = data_opt.map(|(metadata, data)| {
+                let data = data.as_ref();
+                let mut record: Vec<u8> = Vec::with_capacity(metadata.len() + data.len());
+                record.extend_from_slice(&metadata);
+                record.extend_from_slice(data);
+                BatchData::ObjectWithMetadata(record)
+            });
+            (object_id, data_opt)
+        });
+
+        let old_locations = Map::default();
+        self.shard_batch(
+            write_batch,
+            NEW_WRITE_GENERATION,
+            &old_locations,
+            None,
+            None,
+        )?;
+
+        Ok(())
+    }
+
+    /// Like `write_batch`, but skips the batch if a batch
+    /// with an ID of at least `batch_id` was already written
+    /// this way, returning `false` instead of `true`. This
+    /// allows a batch to be safely submitted again after a
+    /// crash or error left it unclear whether it was
+    /// written, for instance when flushing a write-ahead log
+    /// whose sequence numbers are used as batch IDs. Batch
+    /// IDs must therefore increase with each batch.
+    ///
+    /// The ID is recorded in the same storage file as the
+    /// batch, so a crash either leaves both or neither. It is
+    /// stored as an extra object that is counted by
+    /// `Stats::live_objects`, but is not visible otherwise.
+    /// Unlike in `write_batch`, the batch may be empty, and
+    /// its ID is still recorded. Calls to this method are
+    /// executed one at a time, while calls to `write_batch`
+    /// may still run concurrently.
+    pub fn write_batch_once<B, I>(&self, batch_id: u64, write_batch: I) -> Result<bool, MarbleError>
+    where
+        B: AsRef<[u8]>,
+        I: IntoIterator<Item = (ObjectId, Option<B>)>,
+    {
+        self.check_writable()?;
+
+        let mut last_batch_id = self.last_batch_id.lock().unwrap();
+
+        let last = if let Some(last) = *last_batch_id {
+            last
+        } else {
+            let last = self.read_last_batch_id()?;
+            *last_batch_id = Some(last);
+            last
+        };
+
+        if last.is_some_and(|last| batch_id <= last) {
+            log::debug!("skipping write batch {batch_id}, as batch {last:?} was already written");
+            return Ok(false);
+        }
+
+        self.apply_backpressure()?;
+
+        let write_batch = write_batch
+            .into_iter()
+            .map(|(object_id, data)| (object_id, data.map(BatchData::Object)));
+
+        let old_locations = Map::default();
+        let res = self.shard_batch(
+            write_batch,
+            NEW_WRITE_GENERATION,
+            &old_locations,
+            Some(Some(batch_id)),
+            None,
+        );
+
+        // an error may be returned after the batch was already
+        // written, so the next call reads the last ID again
+        *last_batch_id = match res {
+            Ok(_) => Some(Some(batch_id)),
+            Err(_) => None,
+        };
+        res?;
+
+        Ok(true)
+    }
+
+    /// Writes a large number of objects, for instance when
+    /// initially loading a store. Objects are packed into
+    /// files of about `Config::target_file_size`, rather than
+    /// one file per group of objects passed to `write_batch`.
+    /// Each file is synced before it is renamed into place,
+    /// like the files of `write_batch`, but the directories
+    /// holding them are only synced once all of them have
+    /// been written, regardless of `Config::fsync_each_batch`.
+    ///
+    /// Unlike a single call to `write_batch`, loading is not
+    /// atomic. If it fails or is interrupted by a crash, some
+    /// of the objects may be stored and others not, but the
+    /// store is never corrupted, so the load may simply be
+    /// repeated. If an object ID is present more than once,
+    /// the last value for it is stored.
+    #[doc(alias = "import")]
+    pub fn bulk_load<B, I>(&self, objects: I) -> Result<(), MarbleError>
+    where
+        B: AsRef<[u8]>,
+        I: IntoIterator<Item = (ObjectId, B)>,
+    {
+        self.check_writable()?;
+
+        self.apply_backpressure()?;
+
+        // the directories are synced once all files are
+        // written
+        let mut unsynced = self.clone();
+        unsynced.config.fsync_each_batch = false;
+
+        let old_locations = Map::default();
+        let mut batch = vec![];
+        let mut batch_size = 0;
+
+        for (object_id, data) in objects {
+            batch_size += data.as_ref().len() + HEADER_LEN;
+            batch.push((object_id, Some(BatchData::Object(data))));
+
+            if batch_size >= self.config.target_file_size {
+                let batch = std::mem::take(&mut batch);
+                unsynced.shard_batch(batch, NEW_WRITE_GENERATION, &old_locations, None, None)?;
+                batch_size = 0;
+            }
+        }
+
+        if !batch.is_empty() {
+            unsynced.shard_batch(batch, NEW_WRITE_GENERATION, &old_locations, None, None)?;
+        }
+
+        self.sync_all()
+    }
+
+    fn read_last_batch_id(&self) -> Result<Option<u64>, MarbleError> {
+        let data = if let Some(data) = self.read_reserved()? {
+            data
+        } else {
+            return Ok(None);
+        };
+
+        if let Ok(batch_id) = <[u8; 8]>::try_from(&*data) {
+            Ok(Some(u64::from_le_bytes(batch_id)))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the stored ID of the last write batch has a length of {} bytes instead of 8",
+                    data.len()
+                ),
+            )
+            .into())
+        }
+    }
+
+    pub(crate) fn apply_backpressure(&self) -> Result<(), MarbleError> {
+        let max_space_amplification = if let Some(max) = self.config.max_space_amplification {
+            max
+        } else {
+            return Ok(());
+        };
+
+        match self.config.backpressure {
+            Backpressure::Reject => {
+                let space_amplification = self.space_amplification();
+                if space_amplification > max_space_amplification {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        format!(
+                            "space amplification of {space_amplification} exceeds the configured \
+                             `max_space_amplification` of {max_space_amplification}, call \
+                             `maintenance` before writing more data"
+                        ),
+                    )
+                    .into());
+                }
+            }
+            Backpressure::Block => {
+                if self.space_amplification() <= max_space_amplification {
+                    return Ok(());
+                }
+
+                log::debug!(
+                    "blocking write batch until space amplification drops to \
+                     {max_space_amplification}"
+                );
+
+                let (mu, cv) = &*self.space_reclaimed;
+                let mut guard = mu.lock().unwrap();
+                while self.space_amplification() > max_space_amplification {
+                    guard = cv.wait(guard).unwrap();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the batch into one new storage file per shard,
+    /// returning the total size of the files written. If
+    /// `batch_id` is `Some`, the stored ID of the last batch
+    /// written by `write_batch_once` is replaced as part of
+    /// the batch, or removed if it is `Some(None)`. Callers
+    /// that already hold the lock of the reference counts
+    /// pass them as `held_ref_counts`.
     pub(crate) fn shard_batch<B, I>(
         &self,
         write_batch: I,
         gen: u8,
         old_locations: &Map<ObjectId, DiskLocation>,
-    ) -> io::Result<()>
+        batch_id: Option<Option<u64>>,
+        held_ref_counts: Option<&mut Option<RefCounts>>,
+    ) -> Result<u64, MarbleError>
     where
         B: AsRef<[u8]>,
-        I: IntoIterator<Item = (ObjectId, Option<B>)>,
+        I: IntoIterator<Item = (ObjectId, Option<BatchData<B>>)>,
     {
+        let start = Instant::now();
+
         // maps from shard -> (shard size, map of object
         // id's to object data)
-        let mut shards: Map<u8, (usize, Map<ObjectId, Option<B>>)> = Map::default();
+        let mut shards: Map<u8, (usize, FileObjects<B>)> = Map::default();
 
         let mut fragmented_shards = vec![];
 
+        // the position that each object first appeared at in
+        // the batch, if it should be written in that order
+        // rather than in object ID order.
+        let mut write_order: Option<Map<ObjectId, usize>> =
+            if gen == NEW_WRITE_GENERATION && !self.config.sort_batches {
+                Some(Map::default())
+            } else {
+                None
+            };
+
         let mut high_level_user_bytes_written = 0;
         let mut max_oid = 0;
+
         for (object_id, data_opt) in write_batch {
-            max_oid = max_oid.max(object_id);
+            if object_id == RESERVED_OBJECT_ID {
+                if gen == NEW_WRITE_GENERATION {
+                    return Err(MarbleError::ReservedObjectId(object_id));
+                }
+            } else {
+                max_oid = max_oid.max(object_id);
+            }
+
+            if let Some(write_order) = &mut write_order {
+                let position = write_order.len();
+                write_order.entry(object_id).or_insert(position);
+            }
+
             let (object_size, shard_id) = if let Some(ref data) = data_opt {
                 let len = data.as_ref().len();
 
+                if len > self.config.max_object_size {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!(
+                            "{:?} in write batch has a size of {}, which is larger than the \
+                             configured `max_object_size` of {}. If this is intentional, please \
+                             increase the configured `max_object_size`.",
+                            object_id, len, self.config.max_object_size,
+                        ),
+                    )
+                    .into());
+                }
+
                 if old_locations.is_empty() {
                     high_level_user_bytes_written += len as u64;
                 }
@@ -69,7 +413,7 @@ impl Marble {
                     // writebatch atomicity
                     0
                 } else {
-                    (self.config.partition_function)(object_id, len)
+                    (self.config.partition_function)(object_id, len, gen)
                 };
                 (len + HEADER_LEN, shard)
             } else {
@@ -90,42 +434,121 @@ impl Marble {
 
             shard.0 += object_size;
             if let Some(Some(replaced)) = shard.1.insert(object_id, data_opt) {
-                shard.0 -= replaced.as_ref().len();
+                // the batch contained this object ID more
+                // than once, and only the last value will
+                // be written.
+                let replaced_len = replaced.as_ref().len();
+                shard.0 -= replaced_len + HEADER_LEN;
+                if old_locations.is_empty() {
+                    high_level_user_bytes_written -= replaced_len as u64;
+                }
+            }
+        }
+
+        if let Some(batch_id) = batch_id {
+            if let Some(write_order) = &mut write_order {
+                let position = write_order.len();
+                write_order.entry(RESERVED_OBJECT_ID).or_insert(position);
             }
+
+            // like the rest of a new batch, this is not
+            // partitioned, so that it is written atomically
+            // with it
+            let data = batch_id.map(|batch_id| BatchData::BatchId(batch_id.to_le_bytes()));
+            let shard = shards.entry(0).or_default();
+            shard.0 += data
+                .as_ref()
+                .map_or(0, |data| data.as_ref().len() + HEADER_LEN);
+            shard.1.insert(RESERVED_OBJECT_ID, data);
         }
 
+        // the IDs of objects deleted by a new batch, whose
+        // reference counts are discarded, and which may be
+        // reused by `allocate_object_id`. only the last value
+        // of an object in the batch counts, so this is taken
+        // from the deduplicated shards. new batches are never
+        // fragmented, so every object is in `shards`.
+        let deleted: Vec<ObjectId> = if gen == NEW_WRITE_GENERATION {
+            shards
+                .values()
+                .flat_map(|(_sz, objects)| objects.iter())
+                .filter(|(object_id, data_opt)| {
+                    data_opt.is_none() && **object_id != RESERVED_OBJECT_ID
+                })
+                .map(|(object_id, _data_opt)| *object_id)
+                .collect()
+        } else {
+            vec![]
+        };
+
         self.high_level_user_bytes_written
             .fetch_add(high_level_user_bytes_written, Ordering::Relaxed);
 
         self.max_object_id.fetch_max(max_oid, Ordering::Release);
 
+        let objects_written: usize = shards
+            .values()
+            .map(|(_sz, objects)| objects.len())
+            .sum::<usize>()
+            - usize::from(batch_id.is_some());
+
         let iter = shards
             .into_iter()
-            .map(|(_shard, (_sz, objects))| objects)
-            .chain(
-                fragmented_shards
-                    .into_iter()
-                    .map(|(_shard, objects)| objects),
-            );
+            .map(|(shard, (_sz, objects))| (shard, objects))
+            .chain(fragmented_shards);
 
-        for objects in iter {
-            self.write_batch_inner(objects, gen, &old_locations)?;
+        let mut written_file_bytes = 0;
+        for (shard, objects) in iter {
+            written_file_bytes +=
+                self.write_batch_inner(objects, gen, shard, &old_locations, write_order.as_ref())?;
         }
 
         // fsync directory to ensure new file is present
         if self.config.fsync_each_batch {
-            fallible!(self.directory_lock.sync_all());
+            self.sync_directory()?;
         }
 
-        Ok(())
+        if !deleted.is_empty() {
+            // the batch is already written, so this does not
+            // fail it. reference counts left behind are pruned
+            // when they are loaded again.
+            let res = if let Some(ref_counts_opt) = held_ref_counts {
+                self.discard_ref_counts_locked(ref_counts_opt, &deleted)
+            } else {
+                self.discard_ref_counts(&deleted)
+            };
+            if let Err(e) = res {
+                log::error!("failed to discard reference counts of deleted objects: {e:?}");
+            }
+
+            if self.config.reuse_object_ids {
+                self.free_object_ids_for_reuse(deleted);
+            }
+        }
+
+        if gen == NEW_WRITE_GENERATION {
+            let nanos = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+            self.objects_written
+                .fetch_add(objects_written as u64, Ordering::Relaxed);
+            self.write_batches.fetch_add(1, Ordering::Relaxed);
+            self.last_write_batch_nanos.store(nanos, Ordering::Relaxed);
+
+            if let Some(metrics) = &self.config.metrics {
+                metrics.on_write_batch(high_level_user_bytes_written, objects_written);
+            }
+        }
+
+        Ok(written_file_bytes)
     }
 
     fn write_batch_inner<B>(
         &self,
-        objects: Map<ObjectId, Option<B>>,
+        objects: FileObjects<B>,
         generation: u8,
+        partition: u8,
         old_locations: &Map<ObjectId, DiskLocation>,
-    ) -> io::Result<()>
+        write_order: Option<&Map<ObjectId, usize>>,
+    ) -> io::Result<u64>
     where
         B: AsRef<[u8]>,
     {
@@ -151,14 +574,23 @@ impl Marble {
         // 6. update replaced / contention-related failures
 
         // 1. write data to tmp
-        let tmp_file_name = format!("{}-tmp", TMP_COUNTER.fetch_add(1, Ordering::SeqCst));
-        let tmp_path = self.config.path.join(HEAP_DIR_SUFFIX).join(tmp_file_name);
-
+        let shard_dir = self.shard_directory(partition)?;
         let mut file_options = OpenOptions::new();
-        file_options.read(true).write(true).create(true);
+        file_options.read(true).write(true).create_new(true);
+
+        let create_tmp_file = || loop {
+            let tmp_file_name = format!("{}-tmp", TMP_COUNTER.fetch_add(1, Ordering::SeqCst));
+            let tmp_path = shard_dir.join(tmp_file_name);
+            match file_options.open(&tmp_path) {
+                // the name is taken by a temporary file kept
+                // from before a crash by `Config::keep_tmp_files`
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                res => return res.map(|file| (tmp_path, file)),
+            }
+        };
 
-        let file = fallible!(file_options.open(&tmp_path));
-        let mut buf_writer = BufWriter::with_capacity(8 * 1024 * 1024, file);
+        let (tmp_path, file) = fallible!(retry_io(self.config.io_retries, create_tmp_file));
+        let mut buf_writer = BufWriter::with_capacity(self.config.write_buffer_size, file);
 
         let (dict_bytes_opt, mut compressor_and_level_opt, decompressor) =
             if let Some(compression_level) = self.config.zstd_compression_level {
@@ -188,27 +620,31 @@ impl Marble {
         let mut written_bytes: u64 = 0;
         let mut compressed_bytes: i64 = 0;
 
-        for (object_id, raw_object_opt) in &objects {
-            let raw_object = if let Some(raw_object) = raw_object_opt {
-                raw_object.as_ref()
+        // objects are laid out in object ID order so that the
+        // contents of a file only depends on the batch that
+        // it was written for, and so that objects with nearby
+        // IDs are stored near each other.
+        let mut sorted_objects: Vec<(&ObjectId, &Option<BatchData<B>>)> = objects.iter().collect();
+        if let Some(write_order) = write_order {
+            sorted_objects.sort_unstable_by_key(|(object_id, _)| write_order[*object_id]);
+        } else {
+            sorted_objects.sort_unstable_by_key(|(object_id, _)| **object_id);
+        }
+
+        for (object_id, raw_object_opt) in sorted_objects {
+            let (raw_object, version) = if let Some(raw_object) = raw_object_opt {
+                (raw_object.as_ref(), raw_object.record_version())
             } else {
                 let is_delete = true;
                 new_relative_locations.insert(*object_id, RelativeDiskLocation::new(0, is_delete));
                 continue;
             };
 
-            if raw_object.len() > self.config.max_object_size {
-                return Err(io::Error::new(
-                    io::ErrorKind::Unsupported,
-                    format!(
-                        "{:?} in write batch has a size of {}, which is larger than the \
-                         configured `max_object_size` of {}. If this is intentional, please \
-                         increase the configured `max_object_size`.",
-                        object_id,
-                        raw_object.len(),
-                        self.config.max_object_size,
-                    ),
-                ));
+            if let Some(record_alignment) = self.config.record_alignment {
+                let padding =
+                    written_bytes.next_multiple_of(record_alignment as u64) - written_bytes;
+                fallible!(io::copy(&mut io::repeat(0).take(padding), &mut buf_writer));
+                written_bytes += padding;
             }
 
             let relative_address = written_bytes;
@@ -242,7 +678,7 @@ impl Marble {
                 .map(AsRef::as_ref)
                 .unwrap_or(raw_object);
 
-            let len_buf: [u8; 8] = (output_object.len() as u64).to_le_bytes();
+            let len_buf: [u8; 8] = encode_record_len(output_object.len(), version);
             let pid_buf: [u8; 8] = object_id.to_le_bytes();
 
             let crc = hash(len_buf, pid_buf, &output_object);
@@ -273,7 +709,8 @@ impl Marble {
         let mut file_2: File = fallible!(file.try_clone());
 
         if self.config.fsync_each_batch {
-            fallible!(file.sync_all());
+            let sync = || self.config.sync_mode.sync(&file);
+            fallible!(retry_io(self.config.io_retries, sync));
         }
 
         self.compressed_bytes_written
@@ -291,12 +728,15 @@ impl Marble {
             written_bytes,
             initial_capacity,
             generation,
+            partition,
             is_gc,
             &self.config,
             decompressor,
         );
 
         // 3. attempt installation into pagetable
+        let snapshot_guard = self.snapshot_lock.read().unwrap();
+
         let mut replaced_locations: Vec<(ObjectId, DiskLocation)> = vec![];
         let mut failed_gc_locations = vec![];
         let mut subtract_from_len = 0;
@@ -375,6 +815,12 @@ impl Marble {
             };
         }
 
+        drop(snapshot_guard);
+
+        for object_id in new_relative_locations.keys() {
+            self.cache.invalidate(*object_id);
+        }
+
         for failed_gc_location in &failed_gc_locations {
             new_relative_locations.remove(failed_gc_location).unwrap();
         }
@@ -385,7 +831,7 @@ impl Marble {
             self.file_map
                 .delete_partially_installed_fam(base_location, tmp_path);
 
-            return Ok(());
+            return Ok(0);
         }
 
         // 5. write trailer then rename file
@@ -407,11 +853,12 @@ impl Marble {
             trailer_offset: written_bytes,
             present_objects: objects.len() as u64,
             generation,
+            partition,
             file_size: expected_file_len,
         };
 
         let file_name = metadata.to_file_name();
-        let new_path = self.config.path.join(HEAP_DIR_SUFFIX).join(file_name);
+        let new_path = shard_dir.join(file_name);
 
         log::trace!(
             "writing trailer for {} at offset {}, trailer items {trailer_items}",
@@ -425,8 +872,14 @@ impl Marble {
             &new_relative_locations,
             &dict_bytes_opt,
         )
-        .and_then(|_| maybe!(file_2.sync_all()))
-        .and_then(|_| maybe!(fs::rename(&tmp_path, &new_path)));
+        .and_then(|_| {
+            let sync = || self.config.sync_mode.sync(&file_2);
+            maybe!(retry_io(self.config.io_retries, sync))
+        })
+        .and_then(|_| {
+            let rename = || install_storage_file(&tmp_path, &new_path);
+            maybe!(retry_io(self.config.io_retries, rename))
+        });
 
         assert_eq!(trailer_items, new_relative_locations.len());
 
@@ -447,13 +900,10 @@ impl Marble {
             self.file_map
                 .delete_partially_installed_fam(base_location, tmp_path);
             log::error!("failed to write new file: {:?}", e);
+            self.poison(&e);
             return Err(e);
         };
 
-        let file_len = fallible!(file_2.metadata()).len();
-
-        assert_eq!(file_len, expected_file_len);
-
         log::trace!("renamed file to {:?}", new_path);
 
         // 6. update replaced / contention-related failures
@@ -464,6 +914,135 @@ impl Marble {
 
         drop(fam_claim);
 
-        Ok(())
+        // checked after the fam is finalized, so that a failure
+        // here does not leave it without a path
+        let file_len = fallible!(file_2.metadata()).len();
+
+        assert_eq!(file_len, expected_file_len);
+
+        Ok(file_len)
+    }
+
+    /// Returns the sub-directory of the heap directory that
+    /// storage files of the given shard are written into,
+    /// creating it first if necessary. Spreading files over
+    /// one directory per shard keeps the number of entries
+    /// in each directory bounded for large stores.
+    fn shard_directory(&self, shard: u8) -> io::Result<PathBuf> {
+        let path = self
+            .config
+            .path
+            .join(HEAP_DIR_SUFFIX)
+            .join(shard_directory_name(shard));
+
+        let mut shard_directories = self.sync_on_drop.shard_directories.lock().unwrap();
+        if let Entry::Vacant(entry) = shard_directories.entry(shard) {
+            fallible!(fs::create_dir_all(&path));
+            let directory = fallible!(File::open(&path));
+
+            // make the new directory itself durable before any
+            // storage file is renamed into it
+            sync_directory(&self.sync_on_drop.directory_lock)?;
+
+            entry.insert(directory);
+        }
+
+        Ok(path)
+    }
+}
+
+/// Moves a completed storage file from `tmp_path` to
+/// `new_path`. `fs::rename` would silently replace a file
+/// that already exists at `new_path`, for instance one left
+/// behind by an earlier attempt at the same write before a
+/// crash, so the file is hard linked instead, which
+/// atomically fails if `new_path` exists, and the existing
+/// file is kept. If it has the same contents, the write is
+/// already complete and the temporary file is removed,
+/// otherwise an `AlreadyExists` error is returned without
+/// touching either file.
+fn install_storage_file(tmp_path: &Path, new_path: &Path) -> io::Result<()> {
+    match maybe!(fs::hard_link(tmp_path, new_path)) {
+        Ok(()) => {
+            fallible!(fs::remove_file(tmp_path));
+            return Ok(());
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+
+    if !same_contents(tmp_path, new_path)? {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "refusing to replace the existing storage file {new_path:?} with {tmp_path:?}, \
+                 which has different contents"
+            ),
+        ));
+    }
+
+    log::warn!("storage file {new_path:?} was already written, removing {tmp_path:?}");
+    fallible!(fs::remove_file(tmp_path));
+    Ok(())
+}
+
+/// Compares the contents of two files, reading them in
+/// chunks rather than loading either into memory.
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    const CHUNK_LEN: usize = 64 * 1024;
+
+    let mut a_file = fallible!(File::open(a));
+    let mut b_file = fallible!(File::open(b));
+
+    if fallible!(a_file.metadata()).len() != fallible!(b_file.metadata()).len() {
+        return Ok(false);
+    }
+
+    let mut a_buf = vec![0; CHUNK_LEN];
+    let mut b_buf = vec![0; CHUNK_LEN];
+    loop {
+        let read = fallible!(a_file.read(&mut a_buf));
+        if read == 0 {
+            return Ok(true);
+        }
+
+        fallible!(b_file.read_exact(&mut b_buf[..read]));
+        if a_buf[..read] != b_buf[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+/// The name of the sub-directory of the heap directory that
+/// holds the storage files of a shard.
+pub(crate) fn shard_directory_name(shard: u8) -> String {
+    format!("{shard:02x}")
+}
+
+/// The data of an object in a batch passed to
+/// `shard_batch`, or the ID of the batch, which must be
+/// stored alongside objects of the caller's type.
+pub(crate) enum BatchData<B> {
+    Object(B),
+    /// An object whose data begins with its `ObjectMetadata`.
+    ObjectWithMetadata(B),
+    BatchId([u8; 8]),
+}
+
+impl<B> BatchData<B> {
+    fn record_version(&self) -> u8 {
+        match self {
+            BatchData::ObjectWithMetadata(_) => METADATA_RECORD_VERSION,
+            BatchData::Object(_) | BatchData::BatchId(_) => RECORD_VERSION,
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> AsRef<[u8]> for BatchData<B> {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            BatchData::Object(data) | BatchData::ObjectWithMetadata(data) => data.as_ref(),
+            BatchData::BatchId(batch_id) => batch_id,
+        }
     }
 }
@@ -11,6 +11,18 @@ pub(crate) fn zstd_error(errno: usize) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::Other, name.to_string())
 }
 
+/// The largest size of the header of a zstd frame, which
+/// holds the decompressed size of the frame.
+pub(crate) const FRAME_HEADER_MAX_LEN: usize = 18;
+
+/// Reads the decompressed size of a compressed object from
+/// the start of its frame, without decompressing it.
+pub(crate) fn frame_content_size(frame_header: &[u8]) -> Option<u64> {
+    zstd_safe::get_frame_content_size(frame_header)
+        .ok()
+        .flatten()
+}
+
 #[derive(Default)]
 pub(crate) struct ZstdDict {
     decompressor: Option<ThreadLocalDict>,
@@ -38,11 +50,17 @@ impl ZstdDict {
 
     pub(crate) fn decompress(&self, buf: Box<[u8]>) -> Box<[u8]> {
         if let Some(decompressor) = &self.decompressor {
-            decompressor.decompress(&buf)
+            decompressor.decompress_into(&buf, uninit_boxed_slice)
         } else {
             buf
         }
     }
+
+    /// The decompressor for the objects of this file, or
+    /// `None` if they are stored uncompressed.
+    pub(crate) fn decompressor(&self) -> Option<&ThreadLocalDict> {
+        self.decompressor.as_ref()
+    }
 }
 
 struct DictInABox(*mut DCtx<'static>);
@@ -55,14 +73,14 @@ unsafe impl Sync for DictInABox {}
 unsafe impl Send for DictInABox {}
 
 impl DictInABox {
-    fn decompress(&self, buf: &[u8]) -> Box<[u8]> {
+    fn decompress_into<B: AsMut<[u8]>>(&self, buf: &[u8], alloc: impl FnOnce(usize) -> B) -> B {
         let exact_size = zstd_safe::find_decompressed_size(&buf).unwrap().unwrap();
 
-        let mut out = uninit_boxed_slice(exact_size as usize);
+        let mut out = alloc(exact_size as usize);
 
         let dctx: &mut DCtx<'static> = unsafe { &mut *self.0 };
 
-        dctx.decompress(&mut *out, &buf)
+        dctx.decompress(out.as_mut(), &buf)
             .map_err(zstd_error)
             .unwrap();
 
@@ -89,12 +107,18 @@ impl Drop for ThreadLocalDict {
 }
 
 impl ThreadLocalDict {
-    fn decompress(&self, buf: &[u8]) -> Box<[u8]> {
+    /// Decompresses `buf` into a buffer that `alloc` returns
+    /// for the decompressed size.
+    pub(crate) fn decompress_into<B: AsMut<[u8]>>(
+        &self,
+        buf: &[u8],
+        alloc: impl FnOnce(usize) -> B,
+    ) -> B {
         let tid = std::thread::current().id();
         let r_tls = self.tls.read().unwrap();
 
         if let Some(dict) = r_tls.get(&tid) {
-            return dict.decompress(buf);
+            return dict.decompress_into(buf, alloc);
         }
 
         drop(r_tls);
@@ -106,7 +130,7 @@ impl ThreadLocalDict {
 
         let diab = DictInABox(Box::into_raw(Box::new(dict)));
 
-        let ret = diab.decompress(buf);
+        let ret = diab.decompress_into(buf, alloc);
 
         self.tls.write().unwrap().insert(tid, diab);
 
@@ -0,0 +1,4231 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+
+use marble::*;
+
+mod common;
+
+const TEST_DIR: &str = "testing_data_directories";
+
+static TEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn test_path() -> std::path::PathBuf {
+    let subdir = format!("api_{}", TEST_COUNTER.fetch_add(1, SeqCst));
+    std::path::Path::new(TEST_DIR).join(subdir)
+}
+
+fn with_instance<F: FnOnce(&Config, Marble)>(config: Config, f: F) {
+    let _ = common::setup_logger();
+
+    let _ = std::fs::remove_dir_all(&config.path);
+
+    let marble = config.open().unwrap();
+
+    f(&config, marble);
+
+    std::fs::remove_dir_all(&config.path).unwrap();
+}
+
+fn with_default_instance<F: FnOnce(&Config, Marble)>(f: F) {
+    let config = Config {
+        path: test_path(),
+        ..Default::default()
+    };
+
+    with_instance(config, f)
+}
+
+/// Returns the paths of all completed storage files for the
+/// store at `path`, sorted by name.
+fn heap_files(path: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut files: Vec<std::path::PathBuf> = std::fs::read_dir(path.join("heap"))
+        .unwrap()
+        .map(|entry_res| entry_res.unwrap().path())
+        .flat_map(|path| {
+            if path.is_dir() {
+                std::fs::read_dir(path)
+                    .unwrap()
+                    .map(|entry_res| entry_res.unwrap().path())
+                    .collect()
+            } else {
+                vec![path]
+            }
+        })
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            !name.starts_with(' ') && !name.ends_with("tmp")
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+fn restart(config: &Config, marble: Marble) -> Marble {
+    drop(marble);
+    config.open().unwrap()
+}
+
+#[test]
+fn max_object_id() {
+    with_default_instance(|config, mut marble| {
+        let object_id: ObjectId = MAX_OBJECT_ID;
+        marble
+            .write_batch([(object_id, Some(vec![1_u8, 2, 3]))])
+            .unwrap();
+        assert_eq!(&*marble.read(object_id).unwrap().unwrap(), &[1, 2, 3]);
+
+        marble = restart(config, marble);
+        assert_eq!(&*marble.read(object_id).unwrap().unwrap(), &[1, 2, 3]);
+
+        let (next, _free) = marble.free_object_ids();
+        assert_eq!(next, u64::MAX);
+
+        let err = marble
+            .write_batch([(0, Some(vec![0_u8])), (u64::MAX, Some(vec![0_u8]))])
+            .unwrap_err();
+        assert!(matches!(err, MarbleError::ReservedObjectId(u64::MAX)));
+
+        // nothing from the rejected batch was written
+        assert_eq!(marble.read(0).unwrap(), None);
+        assert_eq!(marble.read(u64::MAX).unwrap(), None);
+    });
+}
+
+#[test]
+fn duplicate_object_ids_in_batch() {
+    with_default_instance(|config, mut marble| {
+        marble
+            .write_batch([
+                (1, Some(vec![1_u8; 64])),
+                (2, Some(vec![2_u8; 64])),
+                (1, Some(vec![3_u8; 64])),
+                (2, None),
+            ])
+            .unwrap();
+
+        assert_eq!(&*marble.read(1).unwrap().unwrap(), &[3_u8; 64]);
+        assert_eq!(marble.read(2).unwrap(), None);
+
+        let stats = marble.stats();
+        assert_eq!(stats.high_level_user_bytes_written, 64);
+        assert_eq!(stats.stored_objects, 2);
+        let deduplicated_size = stats.total_file_size;
+
+        marble = restart(config, marble);
+        assert_eq!(&*marble.read(1).unwrap().unwrap(), &[3_u8; 64]);
+        assert_eq!(marble.read(2).unwrap(), None);
+
+        with_default_instance(|_config, unique| {
+            unique
+                .write_batch([(1, Some(vec![3_u8; 64])), (2, None)])
+                .unwrap();
+            assert_eq!(unique.stats().total_file_size, deduplicated_size);
+        });
+    });
+}
+
+#[test]
+fn map_and_vec_batches() {
+    let objects: Vec<(ObjectId, Option<Vec<u8>>)> = vec![
+        (9, Some(vec![9; 9])),
+        (3, Some(vec![3; 3])),
+        (7, None),
+        (1, Some(vec![1; 1])),
+        (5, Some(vec![5; 5])),
+    ];
+
+    let mut file_contents = vec![];
+
+    let hash_map: HashMap<ObjectId, Option<Vec<u8>>> = objects.iter().cloned().collect();
+    with_default_instance(|config, marble| {
+        marble.write_batch(hash_map).unwrap();
+        let files = heap_files(&config.path);
+        assert_eq!(files.len(), 1);
+        file_contents.push(std::fs::read(&files[0]).unwrap());
+    });
+
+    let btree_map: BTreeMap<ObjectId, Option<Vec<u8>>> = objects.iter().cloned().collect();
+    with_default_instance(|config, marble| {
+        marble.write_batch(btree_map).unwrap();
+        let files = heap_files(&config.path);
+        assert_eq!(files.len(), 1);
+        file_contents.push(std::fs::read(&files[0]).unwrap());
+    });
+
+    with_default_instance(|config, mut marble| {
+        marble.write_batch(objects.clone()).unwrap();
+        let files = heap_files(&config.path);
+        assert_eq!(files.len(), 1);
+        file_contents.push(std::fs::read(&files[0]).unwrap());
+
+        marble = restart(config, marble);
+        for (object_id, expected) in &objects {
+            assert_eq!(
+                marble.read(*object_id).unwrap().as_deref(),
+                expected.as_deref()
+            );
+        }
+    });
+
+    // the on-disk layout does not depend on batch iteration order
+    assert_eq!(file_contents[0], file_contents[1]);
+    assert_eq!(file_contents[1], file_contents[2]);
+}
+
+#[test]
+fn read_cached() {
+    let config = Config {
+        path: test_path(),
+        cache_bytes: 1024 * 1024,
+        ..Default::default()
+    };
+
+    with_instance(config, |_config, marble| {
+        marble.write_batch([(1, Some(vec![1_u8; 64]))]).unwrap();
+
+        let first = marble.read_cached(1).unwrap().unwrap();
+        let second = marble.read_cached(1).unwrap().unwrap();
+        assert_eq!(&*first, &[1_u8; 64]);
+        assert!(std::sync::Arc::ptr_eq(&first, &second));
+
+        marble.write_batch([(1, Some(vec![2_u8; 64]))]).unwrap();
+        assert_eq!(&*marble.read_cached(1).unwrap().unwrap(), &[2_u8; 64]);
+
+        marble.write_batch([(2, Some(vec![3_u8; 64]))]).unwrap();
+        marble.maintenance().unwrap();
+        assert_eq!(&*marble.read_cached(1).unwrap().unwrap(), &[2_u8; 64]);
+
+        marble.write_batch::<Vec<u8>, _>([(1, None)]).unwrap();
+        assert_eq!(marble.read_cached(1).unwrap(), None);
+    });
+}
+
+#[test]
+fn verify() {
+    with_default_instance(|config, mut marble| {
+        marble.write_batch([(1, Some(vec![1_u8; 64]))]).unwrap();
+        marble
+            .write_batch([(2, Some(vec![2_u8; 64])), (3, Some(vec![3_u8; 64]))])
+            .unwrap();
+
+        let report = marble.verify().unwrap();
+        assert!(report.is_ok(), "{report:?}");
+        assert_eq!(report.objects_checked, 3);
+
+        drop(marble);
+
+        let files = heap_files(&config.path);
+        assert_eq!(files.len(), 2);
+
+        // flip a bit in the stored bytes of object 1
+        let mut file_1 = std::fs::read(&files[0]).unwrap();
+        file_1[30] ^= 1;
+        std::fs::write(&files[0], file_1).unwrap();
+
+        // point the trailer entry of object 2, which is sorted
+        // first, at the trailer itself, fixing up the trailer crc so that the file
+        // is still recovered
+        let name = files[1].file_name().unwrap().to_str().unwrap();
+        let trailer_offset = u64::from_str_radix(name.split('-').nth(1).unwrap(), 16).unwrap();
+        let trailer_start = usize::try_from(trailer_offset).unwrap();
+        let mut file_2 = std::fs::read(&files[1]).unwrap();
+        let entry_location = trailer_start + 20 + 8;
+        file_2[entry_location..entry_location + 8]
+            .copy_from_slice(&(trailer_offset << 1).to_le_bytes());
+        let crc = crc32fast::hash(&file_2[trailer_start + 4..]);
+        file_2[trailer_start..trailer_start + 4].copy_from_slice(&crc.to_le_bytes());
+        std::fs::write(&files[1], file_2).unwrap();
+
+        marble = config.open().unwrap();
+
+        let report = marble.verify().unwrap();
+        assert_eq!(
+            report,
+            VerifyReport {
+                objects_checked: 3,
+                corrupt_objects: vec![1],
+                dangling_objects: vec![2],
+                orphan_files: vec![],
+            }
+        );
+
+        assert_eq!(&*marble.read(3).unwrap().unwrap(), &[3_u8; 64]);
+        assert!(matches!(
+            marble.read(2).unwrap_err(),
+            MarbleError::Corruption(_)
+        ));
+    });
+}
+
+#[test]
+fn repair() {
+    with_default_instance(|config, mut marble| {
+        marble.write_batch([(1, Some(vec![1_u8; 64]))]).unwrap();
+        marble
+            .write_batch([(2, Some(vec![2_u8; 64])), (3, Some(vec![3_u8; 64]))])
+            .unwrap();
+        marble.write_batch([(4, Some(vec![4_u8; 64]))]).unwrap();
+
+        drop(marble);
+
+        let files = heap_files(&config.path);
+        assert_eq!(files.len(), 3);
+
+        // corrupt object 1, which is alone in its file, and
+        // object 2, which shares its file with object 3
+        for (file, offset) in [(&files[0], 30), (&files[1], 30)] {
+            let mut buf = std::fs::read(file).unwrap();
+            buf[offset] ^= 1;
+            std::fs::write(file, buf).unwrap();
+        }
+
+        marble = config.open().unwrap();
+
+        let report = marble.repair().unwrap();
+        assert_eq!(
+            report,
+            RepairReport {
+                quarantined_objects: vec![1, 2],
+                removed_files: vec![files[0].clone()],
+            }
+        );
+        assert!(marble.verify().unwrap().is_ok());
+
+        for _ in 0..2 {
+            assert_eq!(marble.read(1).unwrap(), None);
+            assert_eq!(marble.read(2).unwrap(), None);
+            assert_eq!(&*marble.read(3).unwrap().unwrap(), &[3_u8; 64]);
+            assert_eq!(&*marble.read(4).unwrap().unwrap(), &[4_u8; 64]);
+
+            marble = restart(config, marble);
+        }
+
+        assert!(!files[0].exists());
+
+        // repairing a healthy store is a no-op
+        assert_eq!(marble.repair().unwrap(), RepairReport::default());
+    });
+}
+
+#[test]
+fn maintenance_shard() {
+    fn shard_by_object_id(object_id: u64, _object_size: usize, _generation: u8) -> u8 {
+        if object_id < 100 {
+            0
+        } else {
+            1
+        }
+    }
+
+    // returns the names of files that belong to the given shard
+    fn shard_files(config: &Config, shard: u8) -> Vec<String> {
+        heap_files(&config.path)
+            .into_iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_owned())
+            .filter(|name| name.ends_with(&format!("-{shard:02x}")))
+            .collect()
+    }
+
+    let config = Config {
+        path: test_path(),
+        partition_function: shard_by_object_id,
+        min_compaction_files: 1,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        let objects = (0..10).chain(100..110);
+        marble
+            .write_batch(objects.map(|object_id| (object_id, Some(vec![0_u8; 16]))))
+            .unwrap();
+
+        let overwrites = (0..5).chain(100..105);
+        marble
+            .write_batch(overwrites.map(|object_id| (object_id, Some(vec![1_u8; 16]))))
+            .unwrap();
+
+        // new writes all go to shard 0 until they are rewritten
+        assert!(shard_files(config, 1).is_empty());
+        assert_eq!(marble.maintenance().unwrap().objects_rewritten, 10);
+        let shard_1_files = shard_files(config, 1);
+        assert_eq!(shard_1_files.len(), 1);
+
+        // fragment the rewritten files of both shards
+        let overwrites = (5..8).chain(105..108);
+        marble
+            .write_batch(overwrites.map(|object_id| (object_id, Some(vec![2_u8; 16]))))
+            .unwrap();
+
+        let shard_0_files = shard_files(config, 0);
+
+        assert_eq!(marble.maintenance_shard(0).unwrap().objects_rewritten, 2);
+        assert_eq!(shard_files(config, 1), shard_1_files);
+        assert_ne!(shard_files(config, 0), shard_0_files);
+
+        marble = restart(config, marble);
+
+        assert_eq!(shard_files(config, 1), shard_1_files);
+        assert_eq!(marble.maintenance_shard(1).unwrap().objects_rewritten, 2);
+        assert_ne!(shard_files(config, 1), shard_1_files);
+
+        for (object_ids, expected) in [(0..5, 1), (5..8, 2), (8..10, 0)] {
+            for object_id in object_ids.clone().chain(object_ids.map(|id| id + 100)) {
+                assert_eq!(
+                    &*marble.read(object_id).unwrap().unwrap(),
+                    &[expected; 16],
+                    "object {object_id}"
+                );
+            }
+        }
+    });
+}
+
+#[test]
+fn survivors_accumulate_generations() {
+    // returns the generation of each storage file
+    fn file_generations(config: &Config) -> Vec<u8> {
+        heap_files(&config.path)
+            .into_iter()
+            .map(|path| {
+                let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+                u8::from_str_radix(name.split('-').nth(3).unwrap(), 16).unwrap()
+            })
+            .collect()
+    }
+
+    let config = Config {
+        path: test_path(),
+        file_compaction_percent: 99,
+        min_compaction_files: 1,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        let cold = 0..8;
+        let hot = 100..110;
+
+        marble
+            .write_batch(
+                cold.clone()
+                    .chain(hot.clone())
+                    .map(|id| (id, Some(vec![0_u8; 16]))),
+            )
+            .unwrap();
+
+        for cycle in 1..=5_u8 {
+            marble
+                .write_batch(hot.clone().map(|id| (id, Some(vec![cycle; 16]))))
+                .unwrap();
+
+            // remove one cold object per cycle so that the
+            // file holding the other cold objects is fragmented
+            marble
+                .write_batch::<Vec<u8>, _>([(u64::from(cycle), None)])
+                .unwrap();
+
+            marble.maintenance().unwrap();
+
+            marble = restart(config, marble);
+
+            let mut rewritten: Vec<u8> = file_generations(config)
+                .into_iter()
+                .filter(|generation| *generation > 0)
+                .collect();
+            rewritten.sort();
+
+            // the surviving cold objects are kept together in
+            // one rewritten file whose generation increases
+            // up to a maximum of 3.
+            assert_eq!(rewritten, vec![cycle.min(3)]);
+
+            for id in cold.clone() {
+                let expected = if id == 0 || id > u64::from(cycle) {
+                    Some(vec![0_u8; 16].into_boxed_slice())
+                } else {
+                    None
+                };
+                assert_eq!(marble.read(id).unwrap(), expected);
+            }
+        }
+    });
+}
+
+#[test]
+fn incremental_backup() {
+    // copies the files created since `lsn` into the heap
+    // directory of `backup`, returning the highest copied
+    // sequence number.
+    fn backup_since(marble: &Marble, lsn: u64, backup: &std::path::Path) -> u64 {
+        let heap = backup.join("heap");
+        std::fs::create_dir_all(&heap).unwrap();
+
+        let mut max_lsn = lsn;
+        for (file_lsn, path) in marble.files_since(lsn) {
+            assert!(file_lsn > lsn);
+            max_lsn = max_lsn.max(file_lsn);
+            std::fs::copy(&path, heap.join(path.file_name().unwrap())).unwrap();
+        }
+        max_lsn
+    }
+
+    let backup_config = Config {
+        path: test_path(),
+        ..Default::default()
+    };
+    let _ = std::fs::remove_dir_all(&backup_config.path);
+
+    let config = Config {
+        path: test_path(),
+        min_compaction_files: 1,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        marble
+            .write_batch((0..10).map(|id| (id, Some(vec![0_u8; 16]))))
+            .unwrap();
+        marble
+            .write_batch((10..20).map(|id| (id, Some(vec![0_u8; 16]))))
+            .unwrap();
+
+        let lsn = backup_since(&marble, 0, &backup_config.path);
+        assert!(marble.files_since(lsn).is_empty());
+
+        marble
+            .write_batch((0..8).map(|id| (id, Some(vec![1_u8; 16]))))
+            .unwrap();
+        marble
+            .write_batch::<Vec<u8>, _>((15..20).map(|id| (id, None)))
+            .unwrap();
+        marble.maintenance().unwrap();
+
+        let new_files = marble.files_since(lsn);
+        assert!(!new_files.is_empty());
+
+        backup_since(&marble, lsn, &backup_config.path);
+
+        marble = restart(config, marble);
+        let restored = backup_config.open().unwrap();
+
+        for id in 0..20 {
+            assert_eq!(marble.read(id).unwrap(), restored.read(id).unwrap());
+        }
+        assert_eq!(&*restored.read(0).unwrap().unwrap(), &[1_u8; 16]);
+        assert_eq!(&*restored.read(10).unwrap().unwrap(), &[0_u8; 16]);
+        assert_eq!(restored.read(15).unwrap(), None);
+
+        // files that were removed by maintenance after being
+        // backed up are cleaned up in the restored store
+        restored.maintenance().unwrap();
+        assert_eq!(
+            restored.stats().files,
+            marble.stats().files,
+            "{:?}",
+            restored.stats()
+        );
+    });
+
+    std::fs::remove_dir_all(&backup_config.path).unwrap();
+}
+
+#[test]
+fn export_and_import() {
+    with_default_instance(|_config, marble| {
+        marble
+            .write_batch((0..100).map(|id| (id, Some(vec![id as u8; id as usize]))))
+            .unwrap();
+        marble
+            .write_batch::<Vec<u8>, _>((50..60).map(|id| (id, None)))
+            .unwrap();
+        marble
+            .write_batch_with_metadata(
+                (90..100).map(|id| (id, Some(([id as u8; 8], vec![1_u8; 4])))),
+            )
+            .unwrap();
+
+        let mut export = vec![];
+        marble.export(&mut export).unwrap();
+
+        // corrupt and truncated exports are rejected
+        let mut corrupt = export.clone();
+        corrupt[100] ^= 1;
+        let truncated = &export[..export.len() - 1];
+
+        for bad_export in [&corrupt[..], truncated] {
+            let path = test_path();
+            let err = import(&path, &mut &bad_export[..]).unwrap_err();
+            assert!(matches!(err, MarbleError::Corruption(_)));
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+
+        let config = Config {
+            path: test_path(),
+            ..Default::default()
+        };
+        let _ = std::fs::remove_dir_all(&config.path);
+
+        let imported = config.import(&mut &export[..]).unwrap();
+
+        // metadata is exported along with each object
+        for id in 0..100 {
+            assert_eq!(
+                marble.read_with_metadata(id).unwrap(),
+                imported.read_with_metadata(id).unwrap()
+            );
+        }
+        assert_eq!(
+            imported.read_with_metadata(95).unwrap().unwrap().1,
+            [95_u8; 8]
+        );
+
+        // deleted objects are not exported
+        assert_eq!(imported.stats().stored_objects, 90);
+
+        drop(imported);
+
+        // importing into a non-empty store is rejected
+        let err = config.import(&mut &export[..]).unwrap_err();
+        assert!(
+            matches!(err, MarbleError::Io(ref e) if e.kind() == std::io::ErrorKind::InvalidInput)
+        );
+
+        std::fs::remove_dir_all(&config.path).unwrap();
+
+        // exports written before objects had metadata are
+        // imported with metadata of all zeroes
+        let mut old_export = b"marblex1".to_vec();
+        old_export.extend_from_slice(&3_u64.to_le_bytes());
+        old_export.extend_from_slice(&2_u64.to_le_bytes());
+        old_export.extend_from_slice(&[7, 7]);
+        old_export.extend_from_slice(&u64::MAX.to_le_bytes());
+        old_export.extend_from_slice(&1_u64.to_le_bytes());
+        let crc = crc32fast::hash(&old_export);
+        old_export.extend_from_slice(&crc.to_le_bytes());
+
+        let imported = config.import(&mut &old_export[..]).unwrap();
+        let (data, metadata) = imported.read_with_metadata(3).unwrap().unwrap();
+        assert_eq!((&*data, metadata), (&[7_u8, 7][..], [0_u8; 8]));
+        drop(imported);
+
+        std::fs::remove_dir_all(&config.path).unwrap();
+    });
+}
+
+#[test]
+fn len() {
+    with_default_instance(|config, mut marble| {
+        assert_eq!(marble.len(), 0);
+        assert!(marble.is_empty());
+
+        marble
+            .write_batch((0..10).map(|id| (id, Some(vec![0_u8; 16]))))
+            .unwrap();
+        assert_eq!(marble.len(), 10);
+
+        marble
+            .write_batch((5..10).map(|id| (id, Some(vec![1_u8; 16]))))
+            .unwrap();
+        assert_eq!(marble.len(), 10);
+
+        marble
+            .write_batch::<Vec<u8>, _>((0..3).map(|id| (id, None)))
+            .unwrap();
+        assert_eq!(marble.len(), 7);
+
+        marble.maintenance().unwrap();
+        marble = restart(config, marble);
+        assert_eq!(marble.len(), 7);
+
+        marble
+            .write_batch::<Vec<u8>, _>((0..10).map(|id| (id, None)))
+            .unwrap();
+        assert_eq!(marble.len(), 0);
+        assert!(marble.is_empty());
+    });
+}
+
+#[test]
+fn removed_files_in_the_middle_of_the_lsn_space() {
+    with_default_instance(|config, mut marble| {
+        for batch in 0..5_u64 {
+            marble
+                .write_batch((0..10).map(|id| (batch * 10 + id, Some(vec![batch as u8; 16]))))
+                .unwrap();
+        }
+
+        // empty the second and fourth files so that they are
+        // removed, leaving gaps between the remaining files
+        marble
+            .write_batch::<Vec<u8>, _>((10..20).chain(30..40).map(|id| (id, None)))
+            .unwrap();
+        let files_before = heap_files(&config.path);
+        marble.maintenance().unwrap();
+        let files_after = heap_files(&config.path);
+        assert_eq!(files_after.len(), files_before.len() - 2);
+        assert_eq!(files_after[0], files_before[0]);
+        assert_eq!(files_after[1], files_before[2]);
+        assert_eq!(files_after[2], files_before[4]);
+
+        for _ in 0..2 {
+            for batch in 0..5_u64 {
+                for id in batch * 10..batch * 10 + 10 {
+                    let expected = if batch == 1 || batch == 3 {
+                        None
+                    } else {
+                        Some(vec![batch as u8; 16].into_boxed_slice())
+                    };
+                    assert_eq!(marble.read(id).unwrap(), expected);
+                }
+            }
+
+            marble = restart(config, marble);
+        }
+    });
+}
+
+#[test]
+fn file_fragmentation() {
+    with_default_instance(|config, mut marble| {
+        marble
+            .write_batch((0..10).map(|id| (id, Some(vec![0_u8; 16]))))
+            .unwrap();
+        marble
+            .write_batch((0..3).map(|id| (id, Some(vec![1_u8; 16]))))
+            .unwrap();
+
+        for _ in 0..2 {
+            let files = marble.file_fragmentation();
+            assert_eq!(files.len(), 2);
+            assert!(files[0].location < files[1].location);
+            assert_eq!(
+                files.iter().map(|ff| ff.path.clone()).collect::<Vec<_>>(),
+                heap_files(&config.path)
+            );
+
+            assert_eq!(files[0].stored_objects, 10);
+            assert_eq!(files[0].live_objects, 7);
+            assert_eq!(files[0].live_percent, 70);
+            assert_eq!(files[1].stored_objects, 3);
+            assert_eq!(files[1].live_objects, 3);
+            assert_eq!(files[1].live_percent, 100);
+
+            for ff in &files {
+                assert_eq!(ff.shard, 0);
+                assert_eq!(ff.generation, 0);
+                assert_eq!(ff.file_size, std::fs::metadata(&ff.path).unwrap().len());
+            }
+
+            marble = restart(config, marble);
+        }
+    });
+}
+
+#[test]
+fn compact_files() {
+    with_default_instance(|config, mut marble| {
+        for batch in 0..3_u64 {
+            marble
+                .write_batch((0..10).map(|id| (batch * 10 + id, Some(vec![batch as u8; 16]))))
+                .unwrap();
+        }
+
+        // fragment all three files
+        marble
+            .write_batch([0_u64, 10, 20].map(|id| (id, Some(vec![9_u8; 16]))))
+            .unwrap();
+
+        let files_before = marble.file_fragmentation();
+        assert_eq!(files_before.len(), 4);
+        let target = files_before[1].clone();
+        assert_eq!(target.live_objects, 9);
+
+        assert_eq!(
+            marble
+                .compact_files(&[target.location])
+                .unwrap()
+                .objects_rewritten,
+            9
+        );
+
+        let files_after = marble.file_fragmentation();
+        assert_eq!(files_after.len(), 4);
+        for ff in [&files_before[0], &files_before[2], &files_before[3]] {
+            assert!(files_after.contains(ff));
+        }
+        assert!(!target.path.exists());
+        let rewritten = files_after.iter().find(|ff| ff.generation == 1).unwrap();
+        assert_eq!(rewritten.live_objects, 9);
+
+        // compacting a file that no longer exists is refused
+        let err = marble
+            .compact_files(&[files_before[0].location, target.location])
+            .unwrap_err();
+        assert!(matches!(err, MarbleError::NotFound(_)));
+        assert_eq!(marble.file_fragmentation(), files_after);
+
+        marble = restart(config, marble);
+
+        assert_eq!(marble.file_fragmentation(), files_after);
+        for id in 0..30_u64 {
+            let expected = if id % 10 == 0 { 9 } else { (id / 10) as u8 };
+            assert_eq!(&*marble.read(id).unwrap().unwrap(), &[expected; 16]);
+        }
+    });
+}
+
+#[test]
+fn snapshot() {
+    let config = Config {
+        path: test_path(),
+        min_compaction_files: 1,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, marble| {
+        marble
+            .write_batch((0..10).map(|id| (id, Some(vec![1_u8; 16]))))
+            .unwrap();
+        let first_file = heap_files(&config.path).remove(0);
+
+        let snapshot = marble.snapshot();
+
+        marble
+            .write_batch((0..8).map(|id| (id, Some(vec![2_u8; 16]))))
+            .unwrap();
+        marble.write_batch::<Vec<u8>, _>([(8, None)]).unwrap();
+        marble.write_batch([(10, Some(vec![2_u8; 16]))]).unwrap();
+
+        // the first file is rewritten and removed from the
+        // store, but the snapshot can still read from it
+        marble.maintenance().unwrap();
+        assert!(marble
+            .file_fragmentation()
+            .iter()
+            .all(|ff| ff.path != first_file));
+
+        for id in 0..10 {
+            assert_eq!(&*snapshot.read(id).unwrap().unwrap(), &[1_u8; 16]);
+        }
+        assert_eq!(snapshot.read(10).unwrap(), None);
+
+        for id in 0..8 {
+            assert_eq!(&*marble.read(id).unwrap().unwrap(), &[2_u8; 16]);
+        }
+        assert_eq!(marble.read(8).unwrap(), None);
+        assert_eq!(&*marble.read(9).unwrap().unwrap(), &[1_u8; 16]);
+        assert_eq!(&*marble.read(10).unwrap().unwrap(), &[2_u8; 16]);
+
+        assert!(first_file.exists());
+        drop(snapshot);
+        assert!(!first_file.exists());
+    });
+}
+
+#[test]
+fn concurrent_reads_and_maintenance() {
+    const OBJECTS: u64 = 64;
+
+    let config = Config {
+        path: test_path(),
+        min_compaction_files: 1,
+        ..Default::default()
+    };
+
+    with_instance(config, |_config, marble| {
+        marble
+            .write_batch((0..OBJECTS).map(|id| (id, Some(vec![0_u8; 16]))))
+            .unwrap();
+
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !done.load(SeqCst) {
+                        for id in 0..OBJECTS {
+                            let read = marble.read(id).unwrap();
+                            assert_eq!(read.unwrap().len(), 16);
+                        }
+                    }
+                });
+            }
+
+            for round in 1..=200_u64 {
+                // overwrite a different half of the objects
+                // each round, fragmenting every file so that
+                // maintenance rewrites and removes them
+                marble
+                    .write_batch(
+                        (0..OBJECTS)
+                            .filter(|id| (id + round) % 2 == 0)
+                            .map(|id| (id, Some(vec![round as u8; 16]))),
+                    )
+                    .unwrap();
+                marble.maintenance().unwrap();
+            }
+
+            done.store(true, SeqCst);
+        });
+    });
+}
+
+#[test]
+fn error_variants() {
+    with_default_instance(|config, mut marble| {
+        let err = marble
+            .write_batch([(u64::MAX, Some(vec![0_u8]))])
+            .unwrap_err();
+        assert!(matches!(err, MarbleError::ReservedObjectId(u64::MAX)));
+        let io_err: std::io::Error = err.into();
+        assert_eq!(io_err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // the store is locked while it is open
+        let err = config.open().unwrap_err();
+        assert!(matches!(err, MarbleError::Io(_)));
+
+        let invalid = Config {
+            target_file_size: 0,
+            ..config.clone()
+        };
+        let err = invalid.open().unwrap_err();
+        assert!(matches!(err, MarbleError::InvalidConfig(_)));
+        let io_err: std::io::Error = err.into();
+        assert_eq!(io_err.kind(), std::io::ErrorKind::Unsupported);
+
+        marble.write_batch([(1_u64, Some(vec![1_u8; 64]))]).unwrap();
+
+        let location = marble.file_fragmentation()[0].location;
+        marble.compact_files(&[location]).unwrap();
+        let err = marble.compact_files(&[location]).unwrap_err();
+        assert!(matches!(err, MarbleError::NotFound(_)));
+        let io_err: std::io::Error = err.into();
+        assert_eq!(io_err.kind(), std::io::ErrorKind::NotFound);
+
+        drop(marble);
+
+        let files = heap_files(&config.path);
+        assert_eq!(files.len(), 1);
+        let mut file = std::fs::read(&files[0]).unwrap();
+        file[30] ^= 1;
+        std::fs::write(&files[0], file).unwrap();
+
+        marble = config.open().unwrap();
+
+        let err = marble.read(1).unwrap_err();
+        assert!(matches!(err, MarbleError::Corruption(_)));
+        let io_err: std::io::Error = err.into();
+        assert_eq!(io_err.kind(), std::io::ErrorKind::InvalidData);
+    });
+}
+
+#[test]
+fn drop_without_sync_all() {
+    let config = Config {
+        path: test_path(),
+        fsync_each_batch: false,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        marble.write_batch([(1_u64, Some(vec![1_u8]))]).unwrap();
+
+        // dropping a clone leaves the store open
+        let clone = marble.clone();
+        drop(clone);
+        marble.write_batch([(2_u64, Some(vec![2_u8]))]).unwrap();
+
+        marble = restart(config, marble);
+
+        assert_eq!(&*marble.read(1).unwrap().unwrap(), &[1_u8]);
+        assert_eq!(&*marble.read(2).unwrap().unwrap(), &[2_u8]);
+    });
+}
+
+#[test]
+fn recover_many_files() {
+    with_default_instance(|config, mut marble| {
+        // every batch is written to its own file, and later
+        // batches overwrite or delete objects from earlier ones
+        for i in 0_u64..256 {
+            let object_id = i % 64;
+            let value = if i % 5 == 0 {
+                None
+            } else {
+                Some(i.to_le_bytes().to_vec())
+            };
+            marble
+                .write_batch([(object_id, value), (i + 64, Some(vec![1_u8]))])
+                .unwrap();
+        }
+
+        let files_before = marble.file_fragmentation();
+        assert_eq!(files_before.len(), 256);
+        let objects_before: Vec<_> = (0..320).map(|i| marble.read(i).unwrap()).collect();
+        let stats_before = marble.stats();
+
+        marble = restart(config, marble);
+
+        assert_eq!(marble.file_fragmentation(), files_before);
+        let objects_after: Vec<_> = (0..320).map(|i| marble.read(i).unwrap()).collect();
+        assert_eq!(objects_after, objects_before);
+        assert_eq!(marble.stats().live_objects, stats_before.live_objects);
+
+        // new files are written after every recovered file
+        marble.write_batch([(0_u64, Some(vec![2_u8]))]).unwrap();
+        let last = marble.file_fragmentation().pop().unwrap();
+        assert!(last.location > files_before.last().unwrap().location);
+
+        marble = restart(config, marble);
+        assert_eq!(&*marble.read(0).unwrap().unwrap(), &[2_u8]);
+    });
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn config_serde_round_trip() {
+    fn shard_by_object_id(object_id: u64, _object_size: usize, _generation: u8) -> u8 {
+        (object_id % 4) as u8
+    }
+
+    let config = Config {
+        path: test_path(),
+        zstd_compression_level: Some(3),
+        fsync_each_batch: true,
+        target_file_size: 1024 * 1024,
+        file_compaction_percent: 40,
+        max_object_size: 1024,
+        small_file_cleanup_threshold: 16,
+        partition_function: shard_by_object_id,
+        min_compaction_files: 3,
+        compaction_threads: 4,
+        cache_bytes: 4096,
+        write_buffer_size: 1024,
+        sort_batches: false,
+        max_space_amplification: Some(3.0),
+        backpressure: Backpressure::Block,
+        read_only: true,
+        sync_mode: SyncMode::Data,
+        compaction_dead_objects_threshold: Some(100),
+        min_compaction_age: Some(std::time::Duration::from_secs(60)),
+        reuse_object_ids: true,
+        temporary: false,
+        record_alignment: Some(4096),
+        io_retries: 5,
+        recovery_policy: RecoveryPolicy::QuarantineAhead,
+        keep_tmp_files: true,
+        max_open_files: Some(128),
+        metrics: None,
+    };
+
+    let json = serde_json::to_string(&config).unwrap();
+    let deserialized: Config = serde_json::from_str(&json).unwrap();
+
+    // the partition function is not serialized
+    assert_eq!(
+        deserialized.partition_function as usize,
+        default_partition_function as usize
+    );
+
+    let expected = Config {
+        partition_function: default_partition_function,
+        ..config
+    };
+    assert_eq!(format!("{deserialized:?}"), format!("{expected:?}"));
+
+    // missing fields take their default values
+    let partial: Config = serde_json::from_str(r#"{"path": "some_path"}"#).unwrap();
+    let expected = Config {
+        path: "some_path".into(),
+        ..Config::default()
+    };
+    assert_eq!(format!("{partial:?}"), format!("{expected:?}"));
+}
+
+#[test]
+fn metrics() {
+    #[derive(Debug, Default)]
+    struct RecordingMetrics {
+        write_batches: AtomicU64,
+        written_bytes: AtomicU64,
+        written_objects: AtomicU64,
+        read_bytes: AtomicU64,
+        compactions: AtomicU64,
+        compacted_files: AtomicU64,
+        rewritten_bytes: AtomicU64,
+        removed_files: AtomicU64,
+    }
+
+    impl MarbleMetrics for RecordingMetrics {
+        fn on_write_batch(&self, bytes: u64, objects: usize) {
+            self.write_batches.fetch_add(1, SeqCst);
+            self.written_bytes.fetch_add(bytes, SeqCst);
+            self.written_objects.fetch_add(objects as u64, SeqCst);
+        }
+
+        fn on_read(&self, bytes: u64) {
+            self.read_bytes.fetch_add(bytes, SeqCst);
+        }
+
+        fn on_compaction(&self, files: usize, rewritten_bytes: u64) {
+            self.compactions.fetch_add(1, SeqCst);
+            self.compacted_files.fetch_add(files as u64, SeqCst);
+            self.rewritten_bytes.fetch_add(rewritten_bytes, SeqCst);
+        }
+
+        fn on_file_removed(&self) {
+            self.removed_files.fetch_add(1, SeqCst);
+        }
+    }
+
+    let recorder = std::sync::Arc::new(RecordingMetrics::default());
+
+    let config = Config {
+        path: test_path(),
+        metrics: Some(recorder.clone()),
+        ..Default::default()
+    };
+
+    with_instance(config, |_config, marble| {
+        marble
+            .write_batch([(1_u64, Some(vec![1_u8; 100])), (2, Some(vec![2_u8; 50]))])
+            .unwrap();
+        marble
+            .write_batch::<Vec<u8>, _>([(1_u64, None), (3, Some(vec![3_u8; 10]))])
+            .unwrap();
+
+        assert_eq!(recorder.write_batches.load(SeqCst), 2);
+        assert_eq!(recorder.written_bytes.load(SeqCst), 160);
+        assert_eq!(recorder.written_objects.load(SeqCst), 4);
+
+        marble.read(2).unwrap().unwrap();
+        marble.read(1).unwrap();
+        assert_eq!(recorder.read_bytes.load(SeqCst), 50);
+
+        let locations: Vec<_> = marble
+            .file_fragmentation()
+            .into_iter()
+            .map(|file| file.location)
+            .collect();
+        marble.compact_files(&locations).unwrap();
+        assert_eq!(recorder.compactions.load(SeqCst), 1);
+        assert_eq!(recorder.compacted_files.load(SeqCst), 2);
+        assert_eq!(recorder.rewritten_bytes.load(SeqCst), 60);
+        assert_eq!(recorder.removed_files.load(SeqCst), 2);
+
+        // maintenance does not count as user writes
+        assert_eq!(recorder.write_batches.load(SeqCst), 2);
+    });
+}
+
+#[test]
+fn file_empty_hook() {
+    #[derive(Debug, Default)]
+    struct EmptyFiles(std::sync::Mutex<Vec<(DiskLocation, std::path::PathBuf)>>);
+
+    impl MarbleMetrics for EmptyFiles {
+        fn on_file_empty(&self, location: DiskLocation, path: &std::path::Path) {
+            self.0.lock().unwrap().push((location, path.to_owned()));
+        }
+    }
+
+    let recorder = std::sync::Arc::new(EmptyFiles::default());
+
+    let config = Config {
+        path: test_path(),
+        metrics: Some(recorder.clone()),
+        ..Default::default()
+    };
+
+    with_instance(config, |config, marble| {
+        marble
+            .write_batch((0..10_u64).map(|object_id| (object_id, Some(vec![1_u8; 64]))))
+            .unwrap();
+        let files = heap_files(&config.path);
+        assert_eq!(files.len(), 1);
+        let location = marble.file_fragmentation()[0].location;
+
+        marble
+            .write_batch((0..10_u64).map(|object_id| (object_id, Some(vec![2_u8; 64]))))
+            .unwrap();
+        assert!(recorder.0.lock().unwrap().is_empty());
+
+        marble.maintenance().unwrap();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![(location, files[0].clone())]
+        );
+        assert!(!files[0].exists());
+    });
+}
+
+/// Returns the files in `dir` that this process has open,
+/// sorted by path.
+#[cfg(target_os = "linux")]
+fn open_files(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let dir = dir.canonicalize().unwrap();
+    let mut files: Vec<_> = std::fs::read_dir("/proc/self/fd")
+        .unwrap()
+        .filter_map(|entry_res| std::fs::read_link(entry_res.unwrap().path()).ok())
+        .filter(|target| target.starts_with(&dir) && !target.is_dir())
+        .collect();
+    files.sort();
+    files
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn file_handles_are_closed_after_compaction() {
+    let config = Config {
+        path: test_path(),
+        file_compaction_percent: 99,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, marble| {
+        let heap_dir = config.path.join("heap");
+
+        for round in 0..64_u8 {
+            marble
+                .write_batch((0..16_u64).map(|object_id| (object_id, Some(vec![round; 256]))))
+                .unwrap();
+            marble
+                .write_batch([(u64::from(round % 16), Some(vec![round; 64]))])
+                .unwrap();
+            marble.maintenance().unwrap();
+
+            // exactly one handle is open for each storage file,
+            // and none for removed ones
+            let live: Vec<_> = heap_files(&config.path)
+                .iter()
+                .map(|path| path.canonicalize().unwrap())
+                .collect();
+            assert_eq!(open_files(&heap_dir), live);
+            assert!(live.len() <= 4);
+        }
+    });
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn max_open_files() {
+    let config = Config {
+        path: test_path(),
+        max_open_files: Some(4),
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        let heap_dir = config.path.join("heap");
+
+        for object_id in 0..32_u64 {
+            marble
+                .write_batch([(object_id, Some(object_id.to_le_bytes()))])
+                .unwrap();
+            assert!(open_files(&heap_dir).len() <= 4);
+        }
+        assert_eq!(heap_files(&config.path).len(), 32);
+
+        // files are reopened as they are read
+        for _ in 0..2 {
+            for object_id in (0..32_u64).rev() {
+                let read = marble.read(object_id).unwrap().unwrap();
+                assert_eq!(&*read, &object_id.to_le_bytes());
+                assert!(open_files(&heap_dir).len() <= 4);
+            }
+        }
+
+        marble = restart(config, marble);
+        assert!(open_files(&heap_dir).len() <= 4);
+        assert!(marble.verify().unwrap().is_ok());
+
+        marble.write_batch([(0_u64, Some(vec![1_u8]))]).unwrap();
+        marble.maintenance().unwrap();
+        assert!(open_files(&heap_dir).len() <= 4);
+        for object_id in 1..32_u64 {
+            let read = marble.read(object_id).unwrap().unwrap();
+            assert_eq!(&*read, &object_id.to_le_bytes());
+        }
+        assert_eq!(&*marble.read(0).unwrap().unwrap(), &[1_u8]);
+    });
+
+    let config = Config {
+        path: test_path(),
+        max_open_files: Some(0),
+        ..Default::default()
+    };
+    assert!(matches!(config.open(), Err(MarbleError::InvalidConfig(_))));
+}
+
+#[test]
+fn oversized_objects() {
+    let config = Config {
+        path: test_path(),
+        max_object_size: 1024,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        marble
+            .write_batch([(1_u64, Some(vec![1_u8; 1024]))])
+            .unwrap();
+
+        let err = marble
+            .write_batch([(2_u64, Some(vec![2_u8; 16])), (3, Some(vec![3_u8; 1025]))])
+            .unwrap_err();
+        assert!(
+            matches!(err, MarbleError::Io(ref e) if e.kind() == std::io::ErrorKind::Unsupported)
+        );
+
+        // nothing from the rejected batch was written, not even
+        // a temporary file
+        assert_eq!(marble.read(2).unwrap(), None);
+        assert_eq!(
+            std::fs::read_dir(config.path.join("heap").join("00"))
+                .unwrap()
+                .count(),
+            1
+        );
+
+        marble = restart(config, marble);
+        assert_eq!(&*marble.read(1).unwrap().unwrap(), &[1_u8; 1024]);
+        assert_eq!(marble.read(2).unwrap(), None);
+    });
+}
+
+#[test]
+fn shared_across_threads() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Marble>();
+    assert_send_sync::<std::sync::Arc<Marble>>();
+    assert_send_sync::<Snapshot>();
+
+    const THREADS: u64 = 4;
+    const BATCHES: u64 = 32;
+
+    let config = Config {
+        path: test_path(),
+        target_file_size: 1024,
+        ..Default::default()
+    };
+
+    with_instance(config, |_config, marble| {
+        let marble = std::sync::Arc::new(marble);
+
+        let mut threads = vec![];
+
+        for thread in 0..THREADS {
+            let writer = marble.clone();
+            threads.push(std::thread::spawn(move || {
+                for batch in 0..BATCHES {
+                    let object_id = thread * BATCHES + batch;
+                    writer
+                        .write_batch([(object_id, Some(object_id.to_le_bytes()))])
+                        .unwrap();
+                }
+            }));
+
+            let reader = marble.clone();
+            threads.push(std::thread::spawn(move || {
+                for object_id in 0..THREADS * BATCHES {
+                    if let Some(data) = reader.read(object_id).unwrap() {
+                        assert_eq!(&*data, &object_id.to_le_bytes());
+                    }
+                }
+            }));
+        }
+
+        while threads.iter().any(|thread| !thread.is_finished()) {
+            marble.maintenance().unwrap();
+        }
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        for object_id in 0..THREADS * BATCHES {
+            assert_eq!(
+                &*marble.read(object_id).unwrap().unwrap(),
+                &object_id.to_le_bytes()
+            );
+        }
+    });
+}
+
+#[test]
+fn torn_records() {
+    with_default_instance(|config, mut marble| {
+        marble
+            .write_batch([(1_u64, Some(vec![1_u8; 64])), (2, Some(vec![2_u8; 64]))])
+            .unwrap();
+        marble.write_batch([(3_u64, Some(vec![3_u8; 64]))]).unwrap();
+
+        drop(marble);
+
+        let files = heap_files(&config.path);
+
+        // a corrupt length is detected before the object is read
+        let mut file_1 = std::fs::read(&files[0]).unwrap();
+        file_1[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&files[0], file_1).unwrap();
+
+        marble = config.open().unwrap();
+        assert!(matches!(
+            marble.read(1).unwrap_err(),
+            MarbleError::Corruption(_)
+        ));
+        assert_eq!(&*marble.read(2).unwrap().unwrap(), &[2_u8; 64]);
+        drop(marble);
+
+        // a file that was truncated in the middle of a record
+        // fails recovery instead of being read past its end
+        let file_2 = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&files[1])
+            .unwrap();
+        file_2.set_len(40).unwrap();
+        drop(file_2);
+
+        assert!(matches!(
+            config.open().unwrap_err(),
+            MarbleError::Corruption(_)
+        ));
+
+        // once the torn file is removed, the remaining objects
+        // may be read
+        std::fs::remove_file(&files[1]).unwrap();
+        marble = config.open().unwrap();
+        assert_eq!(&*marble.read(2).unwrap().unwrap(), &[2_u8; 64]);
+        assert_eq!(marble.read(3).unwrap(), None);
+    });
+}
+
+/// Returns the object IDs of the records in a storage file,
+/// in the order that they are stored.
+// the length of the object in a record with the given
+// header, whose most significant byte holds the record
+// format version
+fn record_len(header: &[u8]) -> u64 {
+    let mut len_buf: [u8; 8] = header[12..20].try_into().unwrap();
+    len_buf[7] = 0;
+    u64::from_le_bytes(len_buf)
+}
+
+fn stored_object_ids(path: &std::path::Path) -> Vec<ObjectId> {
+    let name = path.file_name().unwrap().to_str().unwrap();
+    let trailer_offset = u64::from_str_radix(name.split('-').nth(1).unwrap(), 16).unwrap();
+    let contents = std::fs::read(path).unwrap();
+
+    let mut object_ids = vec![];
+    let mut offset = 0;
+    while offset < usize::try_from(trailer_offset).unwrap() {
+        let header = &contents[offset..offset + 20];
+        object_ids.push(u64::from_le_bytes(header[4..12].try_into().unwrap()));
+        let len = record_len(header);
+        offset += 20 + usize::try_from(len).unwrap();
+    }
+    object_ids
+}
+
+#[test]
+fn batch_layout() {
+    let shuffled: Vec<(ObjectId, Option<Vec<u8>>)> = [7_u64, 3, 9, 1, 3, 5]
+        .into_iter()
+        .map(|object_id| (object_id, Some(vec![object_id as u8; 16])))
+        .collect();
+
+    with_default_instance(|config, marble| {
+        marble.write_batch(shuffled.clone()).unwrap();
+
+        let files = heap_files(&config.path);
+        assert_eq!(stored_object_ids(&files[0]), vec![1, 3, 5, 7, 9]);
+    });
+
+    let config = Config {
+        path: test_path(),
+        sort_batches: false,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        marble.write_batch(shuffled.clone()).unwrap();
+
+        let files = heap_files(&config.path);
+        assert_eq!(stored_object_ids(&files[0]), vec![7, 3, 9, 1, 5]);
+
+        marble = restart(config, marble);
+        for object_id in [1_u64, 3, 5, 7, 9] {
+            assert_eq!(
+                &*marble.read(object_id).unwrap().unwrap(),
+                &[object_id as u8; 16]
+            );
+        }
+    });
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_marble() {
+    with_default_instance(|_config, marble| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        let marble = AsyncMarble::from(marble);
+
+        runtime.block_on(async {
+            let writers: Vec<_> = (0_u64..8)
+                .map(|object_id| {
+                    let marble = marble.clone();
+                    tokio::spawn(async move {
+                        marble
+                            .write_batch([(object_id, Some(vec![object_id as u8; 8]))])
+                            .await
+                    })
+                })
+                .collect();
+
+            for writer in writers {
+                writer.await.unwrap().unwrap();
+            }
+
+            for object_id in 0_u64..8 {
+                assert_eq!(
+                    &*marble.read(object_id).await.unwrap().unwrap(),
+                    &[object_id as u8; 8]
+                );
+            }
+
+            marble
+                .write_batch::<Vec<u8>, _>([(0_u64, None)])
+                .await
+                .unwrap();
+            assert_eq!(marble.read(0).await.unwrap(), None);
+
+            marble.maintenance().await.unwrap();
+            marble.sync_all().await.unwrap();
+
+            assert_eq!(marble.stats().live_objects, 7);
+        });
+    });
+}
+
+#[test]
+fn space_amplification() {
+    with_default_instance(|config, marble| {
+        assert_eq!(marble.space_amplification(), 1.0);
+
+        marble
+            .write_batch((0_u64..4).map(|object_id| (object_id, Some(vec![0_u8; 64]))))
+            .unwrap();
+        assert_eq!(marble.space_amplification(), 1.0);
+
+        // half of the objects of the first file become dead
+        marble
+            .write_batch((0_u64..2).map(|object_id| (object_id, Some(vec![1_u8; 64]))))
+            .unwrap();
+
+        let sizes: Vec<f64> = heap_files(&config.path)
+            .iter()
+            .map(|path| std::fs::metadata(path).unwrap().len() as f64)
+            .collect();
+        let expected = (sizes[0] + sizes[1]) / (sizes[0] / 2.0 + sizes[1]);
+        assert!((marble.space_amplification() - expected).abs() < 1e-9);
+        assert!(marble.space_amplification() > 1.0);
+
+        let first_file = marble.file_fragmentation()[0].location;
+        marble.compact_files(&[first_file]).unwrap();
+        assert_eq!(marble.space_amplification(), 1.0);
+    });
+}
+
+#[test]
+fn backpressure() {
+    fn overwrite_all(marble: &Marble) -> Result<(), MarbleError> {
+        marble.write_batch((0_u64..4).map(|object_id| (object_id, Some(vec![0_u8; 64]))))
+    }
+
+    let config = Config {
+        path: test_path(),
+        max_space_amplification: Some(1.5),
+        ..Default::default()
+    };
+
+    with_instance(config.clone(), |_config, marble| {
+        overwrite_all(&marble).unwrap();
+        // this write makes the first file entirely dead
+        overwrite_all(&marble).unwrap();
+        assert!(marble.space_amplification() > 1.5);
+
+        let err = overwrite_all(&marble).unwrap_err();
+        assert!(
+            matches!(err, MarbleError::Io(ref e) if e.kind() == std::io::ErrorKind::WouldBlock)
+        );
+
+        marble.maintenance().unwrap();
+        overwrite_all(&marble).unwrap();
+    });
+
+    let config = Config {
+        path: test_path(),
+        backpressure: Backpressure::Block,
+        ..config
+    };
+
+    with_instance(config, |_config, marble| {
+        overwrite_all(&marble).unwrap();
+        overwrite_all(&marble).unwrap();
+
+        let writer = {
+            let marble = marble.clone();
+            std::thread::spawn(move || overwrite_all(&marble))
+        };
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!writer.is_finished());
+
+        marble.maintenance().unwrap();
+        writer.join().unwrap().unwrap();
+        assert_eq!(marble.stats().files, 2);
+    });
+
+    let invalid = Config {
+        path: test_path(),
+        max_space_amplification: Some(1.0),
+        ..Default::default()
+    };
+    assert!(matches!(
+        invalid.open().unwrap_err(),
+        MarbleError::InvalidConfig(_)
+    ));
+}
+
+#[test]
+fn backpressure_blocks_until_max_space_amplification() {
+    let config = Config {
+        path: test_path(),
+        max_space_amplification: Some(1.3),
+        backpressure: Backpressure::Block,
+        ..Default::default()
+    };
+
+    with_instance(config, |_config, marble| {
+        let write = |object_ids: std::ops::Range<u64>| {
+            marble.write_batch(object_ids.map(|object_id| (object_id, Some(vec![0_u8; 64]))))
+        };
+
+        write(0..20).unwrap();
+        // leaves the first file 75% live, so maintenance does
+        // not rewrite it
+        write(0..5).unwrap();
+        write(100..105).unwrap();
+        // makes the previous file entirely dead
+        write(100..105).unwrap();
+        assert!(marble.space_amplification() > 1.3);
+
+        let writer = {
+            let marble = marble.clone();
+            std::thread::spawn(move || marble.write_batch([(200_u64, Some(vec![0_u8; 64]))]))
+        };
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!writer.is_finished());
+
+        // removing the dead file cannot bring space
+        // amplification anywhere near 1.0, but the writer
+        // continues once it is at most the maximum
+        marble.maintenance().unwrap();
+        let space_amplification = marble.space_amplification();
+        assert!(
+            space_amplification > 1.15 && space_amplification <= 1.3,
+            "{space_amplification}"
+        );
+        writer.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn locate() {
+    with_default_instance(|config, marble| {
+        assert_eq!(marble.locate(1), None);
+
+        marble
+            .write_batch([(1_u64, Some(vec![1_u8; 16])), (2, Some(vec![2_u8; 32]))])
+            .unwrap();
+        marble
+            .write_batch::<Vec<u8>, _>([(1_u64, None), (3, Some(vec![3_u8; 8]))])
+            .unwrap();
+
+        assert_eq!(marble.locate(1), None);
+
+        let files = heap_files(&config.path);
+
+        for (object_id, file) in [(2_u64, &files[0]), (3, &files[1])] {
+            let (path, offset) = marble.locate(object_id).unwrap();
+            assert_eq!(&path, file);
+
+            let offset = usize::try_from(offset).unwrap();
+            let contents = std::fs::read(&path).unwrap();
+            let header = &contents[offset..offset + 20];
+            assert_eq!(
+                u64::from_le_bytes(header[4..12].try_into().unwrap()),
+                object_id
+            );
+            let len = usize::try_from(record_len(header)).unwrap();
+
+            let data = marble.read(object_id).unwrap().unwrap();
+            assert_eq!(&contents[offset + 20..offset + 20 + len], &*data);
+        }
+
+        // maintenance moves objects into new files
+        marble.maintenance().unwrap();
+        let (path, _offset) = marble.locate(2).unwrap();
+        assert!(path.exists());
+    });
+}
+
+#[test]
+fn read_only() {
+    with_default_instance(|config, marble| {
+        marble.write_batch([(1_u64, Some(vec![1_u8]))]).unwrap();
+        let first_file = heap_files(&config.path).pop().unwrap();
+        let first_file_contents = std::fs::read(&first_file).unwrap();
+
+        marble.write_batch([(1_u64, Some(vec![2_u8]))]).unwrap();
+        drop(marble);
+
+        // put back the first file, as if a crash happened
+        // before it could be removed, so that it is recovered
+        // without any live objects
+        std::fs::write(&first_file, first_file_contents).unwrap();
+
+        let files = heap_files(&config.path);
+        assert_eq!(files.len(), 2);
+        let stray_tmp = config.path.join("heap").join("7-tmp");
+        std::fs::write(&stray_tmp, b"torn").unwrap();
+
+        let read_only_config = Config {
+            read_only: true,
+            ..config.clone()
+        };
+        let read_only = read_only_config.open().unwrap();
+        let other_read_only = read_only_config.open().unwrap();
+
+        assert_eq!(&*read_only.read(1).unwrap().unwrap(), &[2_u8]);
+        assert_eq!(read_only.verify().unwrap().orphan_files.len(), 1);
+
+        let is_permission_denied = |err: MarbleError| matches!(err, MarbleError::Io(ref e) if e.kind() == std::io::ErrorKind::PermissionDenied);
+        assert!(is_permission_denied(
+            read_only
+                .write_batch([(2_u64, Some(vec![2_u8]))])
+                .unwrap_err()
+        ));
+        assert!(is_permission_denied(read_only.maintenance().unwrap_err()));
+        assert!(is_permission_denied(
+            read_only.compact_files(&[]).unwrap_err()
+        ));
+        assert!(is_permission_denied(read_only.repair().unwrap_err()));
+
+        // the store may not be opened for writing concurrently
+        assert!(matches!(config.open().unwrap_err(), MarbleError::Io(_)));
+
+        drop(read_only);
+        drop(other_read_only);
+
+        assert!(stray_tmp.exists());
+        assert_eq!(heap_files(&config.path), files);
+
+        // opening for writing cleans up as usual
+        let marble = config.open().unwrap();
+        assert!(!stray_tmp.exists());
+        marble.maintenance().unwrap();
+        assert_eq!(&*marble.read(1).unwrap().unwrap(), &[2_u8]);
+    });
+}
+
+#[test]
+fn maintenance_races_overwrites() {
+    const OBJECTS: u64 = 8;
+    const ROUNDS: u64 = 256;
+
+    let config = Config {
+        path: test_path(),
+        min_compaction_files: 1,
+        ..Default::default()
+    };
+
+    with_instance(config, |_config, marble| {
+        // each round writes half of the objects and deletes
+        // the other half in one batch, and then writes the
+        // other half in a second batch, so that files are left
+        // partially live and maintenance keeps rewriting
+        // objects while they are being overwritten
+        let writer = {
+            let marble = marble.clone();
+            std::thread::spawn(move || {
+                for round in 1..=ROUNDS {
+                    let batch = (0..OBJECTS).map(|object_id| {
+                        let value = if object_id % 2 == round % 2 {
+                            Some(round.to_le_bytes())
+                        } else {
+                            None
+                        };
+                        (object_id, value)
+                    });
+                    marble.write_batch(batch).unwrap();
+
+                    let other_half = (0..OBJECTS)
+                        .filter(|object_id| object_id % 2 != round % 2)
+                        .map(|object_id| (object_id, Some(round.to_le_bytes())));
+                    marble.write_batch(other_half).unwrap();
+                }
+            })
+        };
+
+        let mut rewritten = 0;
+        while !writer.is_finished() {
+            rewritten += marble.maintenance().unwrap().objects_rewritten;
+        }
+        writer.join().unwrap();
+        assert!(rewritten > 0);
+
+        // a rewrite of an older value never replaced a newer one
+        for object_id in 0..OBJECTS {
+            assert_eq!(
+                &*marble.read(object_id).unwrap().unwrap(),
+                &ROUNDS.to_le_bytes()
+            );
+        }
+
+        while marble.maintenance().unwrap().objects_rewritten > 0 {}
+
+        for object_id in 0..OBJECTS {
+            assert_eq!(
+                &*marble.read(object_id).unwrap().unwrap(),
+                &ROUNDS.to_le_bytes()
+            );
+        }
+    });
+}
+
+#[test]
+fn range() {
+    with_default_instance(|_config, marble| {
+        assert_eq!(marble.range(0, MAX_OBJECT_ID).count(), 0);
+
+        let scattered = [3_u64, 17, 4, 1000, 42, 9, 100];
+        marble
+            .write_batch(
+                scattered
+                    .iter()
+                    .map(|object_id| (*object_id, Some(vec![0_u8]))),
+            )
+            .unwrap();
+        marble.write_batch::<Vec<u8>, _>([(9_u64, None)]).unwrap();
+
+        let in_range: Vec<ObjectId> = marble.range(4, 100).collect();
+        assert_eq!(in_range, vec![4, 17, 42]);
+
+        let all: Vec<ObjectId> = marble.range(0, u64::MAX).collect();
+        assert_eq!(all, vec![3, 4, 17, 42, 100, 1000]);
+
+        assert_eq!(marble.range(1001, u64::MAX).count(), 0);
+        assert_eq!(marble.range(50, 10).count(), 0);
+    });
+}
+
+#[test]
+fn written_files_are_read_through_their_original_handle() {
+    with_default_instance(|config, marble| {
+        marble.write_batch([(1_u64, Some(vec![1_u8; 32]))]).unwrap();
+
+        // the file that a batch was written to is read using
+        // the handle that it was written with, and not by
+        // opening it again after it was renamed
+        let files = heap_files(&config.path);
+        assert_eq!(files.len(), 1);
+        let renamed = files[0].with_extension("moved");
+        std::fs::rename(&files[0], &renamed).unwrap();
+
+        assert_eq!(&*marble.read(1).unwrap().unwrap(), &[1_u8; 32]);
+
+        std::fs::rename(&renamed, &files[0]).unwrap();
+    });
+}
+
+#[test]
+fn directory_lock() {
+    let path = test_path();
+    let config = Config {
+        path: path.clone(),
+        ..Default::default()
+    };
+
+    let marble = config.open().unwrap();
+    marble.write_batch([(1_u64, Some(vec![1_u8]))]).unwrap();
+    let snapshot = marble.snapshot();
+
+    let err = config.open().unwrap_err();
+    assert!(matches!(err, MarbleError::Io(ref e) if e.kind() == std::io::ErrorKind::WouldBlock));
+
+    // the lock is held until every handle to the store is dropped
+    drop(marble);
+    assert!(config.open().is_err());
+    drop(snapshot);
+
+    let marble = config.open().unwrap();
+    assert_eq!(&*marble.read(1).unwrap().unwrap(), &[1_u8]);
+    drop(marble);
+
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn shard_directories() {
+    fn shard_by_object_id(object_id: u64, _object_size: usize, _generation: u8) -> u8 {
+        (object_id % 3) as u8
+    }
+
+    let config = Config {
+        path: test_path(),
+        partition_function: shard_by_object_id,
+        min_compaction_files: 1,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        marble
+            .write_batch((0..30_u64).map(|object_id| (object_id, Some(vec![object_id as u8; 16]))))
+            .unwrap();
+        marble
+            .write_batch((0..15_u64).map(|object_id| (object_id, None::<Vec<u8>>)))
+            .unwrap();
+        marble.maintenance().unwrap();
+
+        // storage files are kept in one directory per shard
+        for shard in ["00", "01", "02"] {
+            let shard_dir = config.path.join("heap").join(shard);
+            assert!(std::fs::read_dir(shard_dir).unwrap().count() > 0);
+        }
+        for path in heap_files(&config.path) {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let shard = path
+                .parent()
+                .unwrap()
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap();
+            assert!(name.ends_with(&format!("-{shard}")));
+        }
+
+        marble = restart(config, marble);
+
+        for object_id in 0..30_u64 {
+            let expected = (object_id >= 15).then(|| vec![object_id as u8; 16].into_boxed_slice());
+            assert_eq!(marble.read(object_id).unwrap(), expected);
+        }
+    });
+}
+
+#[test]
+fn maintenance_report() {
+    let config = Config {
+        path: test_path(),
+        min_compaction_files: 1,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, marble| {
+        marble
+            .write_batch((0..10_u64).map(|object_id| (object_id, Some(vec![0_u8; 64]))))
+            .unwrap();
+        marble
+            .write_batch((10..20_u64).map(|object_id| (object_id, Some(vec![1_u8; 64]))))
+            .unwrap();
+
+        // leave 2 of 10 objects live in the first file, and none
+        // in the second
+        marble
+            .write_batch((0..8_u64).map(|object_id| (object_id, Some(vec![2_u8; 64]))))
+            .unwrap();
+        marble
+            .write_batch((10..20_u64).map(|object_id| (object_id, None::<Vec<u8>>)))
+            .unwrap();
+
+        let file_sizes = || -> Vec<(std::path::PathBuf, u64)> {
+            heap_files(&config.path)
+                .into_iter()
+                .map(|path| {
+                    let len = std::fs::metadata(&path).unwrap().len();
+                    (path, len)
+                })
+                .collect()
+        };
+        let files_before = file_sizes();
+        let report = marble.maintenance().unwrap();
+        let files_after = file_sizes();
+
+        let removed: Vec<u64> = files_before
+            .iter()
+            .filter(|file| !files_after.contains(file))
+            .map(|(_path, len)| *len)
+            .collect();
+        let added: Vec<u64> = files_after
+            .iter()
+            .filter(|file| !files_before.contains(file))
+            .map(|(_path, len)| *len)
+            .collect();
+        assert_eq!(removed.len(), 2);
+        assert_eq!(added.len(), 1);
+
+        assert_eq!(
+            report,
+            MaintenanceReport {
+                files_rewritten: 1,
+                objects_rewritten: 2,
+                files_removed: 2,
+                bytes_reclaimed: removed.iter().sum::<u64>() - added[0],
+            }
+        );
+
+        // nothing is left to do once the store is defragmented
+        assert_eq!(marble.maintenance().unwrap(), MaintenanceReport::default());
+    });
+}
+
+#[test]
+fn empty_batches_and_objects() {
+    with_default_instance(|config, mut marble| {
+        marble
+            .write_batch(Vec::<(ObjectId, Option<Vec<u8>>)>::new())
+            .unwrap();
+        assert!(heap_files(&config.path).is_empty());
+        assert_eq!(marble.stats().high_level_user_bytes_written, 0);
+
+        marble
+            .write_batch([(1_u64, Some(vec![])), (2, Some(vec![2_u8]))])
+            .unwrap();
+        assert_eq!(heap_files(&config.path).len(), 1);
+        assert_eq!(marble.read(1).unwrap(), Some(vec![].into_boxed_slice()));
+
+        // an empty object survives recovery and maintenance, and
+        // can be deleted like any other
+        marble = restart(config, marble);
+        assert_eq!(marble.read(1).unwrap(), Some(vec![].into_boxed_slice()));
+        let location = marble.file_fragmentation()[0].location;
+        marble.compact_files(&[location]).unwrap();
+        assert_eq!(marble.read(1).unwrap(), Some(vec![].into_boxed_slice()));
+
+        marble.write_batch([(1_u64, None::<Vec<u8>>)]).unwrap();
+        assert_eq!(marble.read(1).unwrap(), None);
+    });
+}
+
+#[test]
+fn sync_mode_data() {
+    let config = Config {
+        path: test_path(),
+        sync_mode: SyncMode::Data,
+        min_compaction_files: 1,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        marble
+            .write_batch((0..10_u64).map(|object_id| (object_id, Some(vec![1_u8; 64]))))
+            .unwrap();
+        marble.sync_all().unwrap();
+
+        let fsync_each_batch = Config {
+            fsync_each_batch: true,
+            ..config.clone()
+        };
+        drop(marble);
+        marble = fsync_each_batch.open().unwrap();
+
+        marble
+            .write_batch((0..5_u64).map(|object_id| (object_id, Some(vec![2_u8; 64]))))
+            .unwrap();
+        marble.maintenance().unwrap();
+
+        marble = restart(config, marble);
+
+        for object_id in 0..10_u64 {
+            let expected = if object_id < 5 { 2 } else { 1 };
+            assert_eq!(&*marble.read(object_id).unwrap().unwrap(), &[expected; 64]);
+        }
+    });
+}
+
+#[test]
+fn batch_builder() {
+    with_default_instance(|config, mut marble| {
+        marble
+            .write_batch([(1_u64, Some(vec![1_u8])), (2, Some(vec![2_u8]))])
+            .unwrap();
+
+        let mut batch = Batch::new();
+        assert!(batch.is_empty());
+        batch.insert(3, vec![3_u8]).remove(2).insert(4, vec![4_u8]);
+        batch.insert(4, vec![5_u8]);
+        assert_eq!(batch.len(), 4);
+
+        marble.write_batch(batch).unwrap();
+
+        let expected = |marble: &Marble| {
+            assert_eq!(&*marble.read(1).unwrap().unwrap(), &[1_u8]);
+            assert_eq!(marble.read(2).unwrap(), None);
+            assert_eq!(&*marble.read(3).unwrap().unwrap(), &[3_u8]);
+            assert_eq!(&*marble.read(4).unwrap().unwrap(), &[5_u8]);
+        };
+        expected(&marble);
+
+        marble = restart(config, marble);
+        expected(&marble);
+
+        // batches may also borrow their objects
+        let data = [6_u8; 4];
+        let mut batch: Batch<&[u8]> = Batch::new();
+        batch.insert(1, &data);
+        marble.write_batch(batch).unwrap();
+        assert_eq!(&*marble.read(1).unwrap().unwrap(), &data);
+    });
+}
+
+#[test]
+fn compaction_dead_objects_threshold() {
+    let config = Config {
+        path: test_path(),
+        min_compaction_files: 1,
+        compaction_dead_objects_threshold: Some(2),
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        marble
+            .write_batch((0..10_u64).map(|object_id| (object_id, Some(vec![1_u8; 16]))))
+            .unwrap();
+        marble
+            .write_batch((10..20_u64).map(|object_id| (object_id, Some(vec![1_u8; 16]))))
+            .unwrap();
+
+        // both files stay above `file_compaction_percent`, but
+        // only the first has more than 2 dead objects
+        marble
+            .write_batch([0_u64, 1, 2, 10, 11].map(|object_id| (object_id, Some(vec![2_u8; 16]))))
+            .unwrap();
+
+        let files_before = heap_files(&config.path);
+        let report = marble.maintenance().unwrap();
+        assert_eq!(report.files_rewritten, 1);
+        assert_eq!(report.objects_rewritten, 7);
+
+        let files_after = heap_files(&config.path);
+        assert!(!files_after.contains(&files_before[0]));
+        assert!(files_after.contains(&files_before[1]));
+
+        marble = restart(config, marble);
+        for object_id in 0..20_u64 {
+            let expected = if [0, 1, 2, 10, 11].contains(&object_id) {
+                2
+            } else {
+                1
+            };
+            assert_eq!(&*marble.read(object_id).unwrap().unwrap(), &[expected; 16]);
+        }
+    });
+}
+
+#[test]
+fn iter() {
+    with_default_instance(|_config, marble| {
+        assert_eq!(marble.iter().count(), 0);
+
+        let mut expected = std::collections::BTreeMap::new();
+        for chunk in 0..10_u64 {
+            let batch: Vec<(ObjectId, Option<Vec<u8>>)> = (chunk * 100..(chunk + 1) * 100)
+                .map(|object_id| (object_id, Some(object_id.to_le_bytes().to_vec())))
+                .collect();
+            expected.extend(batch.iter().cloned().map(|(k, v)| (k, v.unwrap())));
+            marble.write_batch(batch).unwrap();
+        }
+
+        // deleted objects are skipped
+        marble
+            .write_batch(
+                (0..1000_u64)
+                    .step_by(7)
+                    .map(|object_id| (object_id, None::<Vec<u8>>)),
+            )
+            .unwrap();
+        expected.retain(|object_id, _| object_id % 7 != 0);
+
+        let iter = marble.iter();
+
+        // writes and maintenance after the iterator is created are
+        // not visible through it
+        marble
+            .write_batch([(1_u64, Some(vec![0_u8])), (5000, Some(vec![0_u8]))])
+            .unwrap();
+        marble.maintenance().unwrap();
+
+        let iterated: std::collections::BTreeMap<ObjectId, Vec<u8>> = iter
+            .map(|res| {
+                let (object_id, data) = res.unwrap();
+                (object_id, data.into_vec())
+            })
+            .collect();
+        assert_eq!(iterated.len(), 1000 - 143);
+        assert_eq!(iterated, expected);
+    });
+}
+
+#[test]
+fn record_versions() {
+    with_default_instance(|config, mut marble| {
+        marble
+            .write_batch([(1_u64, Some(vec![1_u8; 64])), (2, Some(vec![2_u8; 64]))])
+            .unwrap();
+        drop(marble);
+
+        let files = heap_files(&config.path);
+        let mut contents = std::fs::read(&files[0]).unwrap();
+        assert_eq!(contents[19], 1);
+
+        // rewrite the first record as it was written before
+        // records were versioned
+        contents[19] = 0;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&contents[12..20]);
+        hasher.update(&contents[4..12]);
+        hasher.update(&contents[20..84]);
+        contents[0..4].copy_from_slice(&hasher.finalize().to_le_bytes());
+        std::fs::write(&files[0], &contents).unwrap();
+
+        marble = config.open().unwrap();
+        assert_eq!(&*marble.read(1).unwrap().unwrap(), &[1_u8; 64]);
+        assert_eq!(&*marble.read(2).unwrap().unwrap(), &[2_u8; 64]);
+        assert!(marble.verify().unwrap().is_ok());
+
+        let location = marble.file_fragmentation()[0].location;
+        assert_eq!(
+            marble.compact_files(&[location]).unwrap().objects_rewritten,
+            2
+        );
+        assert_eq!(&*marble.read(1).unwrap().unwrap(), &[1_u8; 64]);
+        drop(marble);
+
+        // records of an unknown version are treated as corrupt
+        let files = heap_files(&config.path);
+        let mut contents = std::fs::read(&files[0]).unwrap();
+        assert_eq!(contents[19], 1);
+        contents[19] = 2;
+        std::fs::write(&files[0], &contents).unwrap();
+
+        marble = config.open().unwrap();
+        assert!(matches!(
+            marble.read(1).unwrap_err(),
+            MarbleError::Corruption(_)
+        ));
+        assert_eq!(&*marble.read(2).unwrap().unwrap(), &[2_u8; 64]);
+    });
+}
+
+#[test]
+fn read_at_location() {
+    let config = Config {
+        path: test_path(),
+        min_compaction_files: 1,
+        ..Default::default()
+    };
+
+    with_instance(config, |_config, marble| {
+        assert_eq!(marble.location(1), None);
+
+        marble
+            .write_batch([(1_u64, Some(vec![1_u8; 16])), (2, Some(vec![2_u8; 16]))])
+            .unwrap();
+        let old_location = marble.location(1).unwrap();
+
+        marble.write_batch([(1_u64, Some(vec![3_u8; 16]))]).unwrap();
+        let new_location = marble.location(1).unwrap();
+        assert_ne!(old_location, new_location);
+
+        // the earlier version is still in its storage file
+        let (object_id, data) = marble.read_at_location(old_location).unwrap();
+        assert_eq!(object_id, 1);
+        assert_eq!(&*data, &[1_u8; 16]);
+        let (object_id, data) = marble.read_at_location(new_location).unwrap();
+        assert_eq!(object_id, 1);
+        assert_eq!(&*data, &[3_u8; 16]);
+
+        marble.write_batch([(1_u64, None::<Vec<u8>>)]).unwrap();
+        assert_eq!(marble.location(1), None);
+        assert_eq!(
+            &*marble.read_at_location(new_location).unwrap().1,
+            &[3_u8; 16]
+        );
+
+        // until maintenance removes the file
+        marble.write_batch([(2_u64, None::<Vec<u8>>)]).unwrap();
+        marble.maintenance().unwrap();
+        assert!(matches!(
+            marble.read_at_location(old_location).unwrap_err(),
+            MarbleError::NotFound(_)
+        ));
+    });
+}
+
+#[test]
+fn total_trailer_size() {
+    let config = Config {
+        path: test_path(),
+        min_compaction_files: 1,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        assert_eq!(marble.stats().total_trailer_size, 0);
+
+        // every batch replaces the same objects, and keeps its
+        // file alive with one object of its own
+        for file in 0..20_u64 {
+            marble
+                .write_batch(
+                    (0..100_u64)
+                        .chain([100 + file])
+                        .map(|object_id| (object_id, Some(vec![1_u8; 16]))),
+                )
+                .unwrap();
+        }
+
+        let before = marble.stats();
+        assert_eq!(before.files, 20);
+        assert!(before.total_trailer_size > 20 * 100 * 16);
+        assert!(before.total_trailer_size < before.total_file_size);
+
+        marble.maintenance().unwrap();
+
+        let after = marble.stats();
+        assert!(
+            after.total_trailer_size < before.total_trailer_size / 4,
+            "maintenance only shrank trailers from {} to {} bytes",
+            before.total_trailer_size,
+            after.total_trailer_size,
+        );
+
+        marble = restart(config, marble);
+        assert_eq!(marble.stats().total_trailer_size, after.total_trailer_size);
+        assert_eq!(marble.len(), 120);
+    });
+}
+
+#[test]
+fn maintenance_rewrites_into_fresh_files() {
+    let config = Config {
+        path: test_path(),
+        min_compaction_files: 1,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        for file in 0..8_u64 {
+            marble
+                .write_batch(
+                    (file * 10..file * 10 + 10).map(|object_id| (object_id, Some(vec![1_u8; 16]))),
+                )
+                .unwrap();
+        }
+
+        // leave a single live object in every file
+        marble
+            .write_batch(
+                (0..80_u64)
+                    .filter(|object_id| object_id % 10 != 0)
+                    .map(|object_id| (object_id, None::<Vec<u8>>)),
+            )
+            .unwrap();
+
+        let files_before = heap_files(&config.path);
+        let report = marble.maintenance().unwrap();
+        // the file holding the deletions stays
+        assert_eq!(report.files_rewritten, 8);
+
+        // the rewritten objects were written into files that
+        // did not exist before the pass, and that the pass
+        // did not remove again
+        let files_after = heap_files(&config.path);
+        let new_files: Vec<_> = files_after
+            .iter()
+            .filter(|file| !files_before.contains(file))
+            .collect();
+        assert!(!new_files.is_empty());
+        assert_eq!(files_after.len(), new_files.len() + 1);
+
+        marble = restart(config, marble);
+        assert_eq!(heap_files(&config.path), files_after);
+        for object_id in 0..80_u64 {
+            let expected = (object_id % 10 == 0).then(|| vec![1_u8; 16].into_boxed_slice());
+            assert_eq!(marble.read(object_id).unwrap(), expected);
+        }
+    });
+}
+
+#[test]
+fn clear() {
+    with_default_instance(|config, mut marble| {
+        // clearing an empty store does nothing
+        marble.clear().unwrap();
+        assert!(heap_files(&config.path).is_empty());
+
+        for file in 0..4_u64 {
+            marble
+                .write_batch(
+                    (file * 10..file * 10 + 10).map(|object_id| (object_id, Some(vec![1_u8; 16]))),
+                )
+                .unwrap();
+        }
+        assert_eq!(marble.len(), 40);
+
+        marble.clear().unwrap();
+
+        assert_eq!(marble.len(), 0);
+        assert!(marble.is_empty());
+        for object_id in 0..40_u64 {
+            assert!(marble.read(object_id).unwrap().is_none());
+        }
+
+        // only the file recording the removals remains
+        assert_eq!(heap_files(&config.path).len(), 1);
+
+        marble = restart(config, marble);
+        assert!(marble.is_empty());
+        for object_id in 0..40_u64 {
+            assert!(marble.read(object_id).unwrap().is_none());
+        }
+
+        marble.write_batch([(7_u64, Some(vec![2_u8]))]).unwrap();
+        assert_eq!(marble.len(), 1);
+        assert_eq!(&*marble.read(7).unwrap().unwrap(), &[2_u8]);
+    });
+}
+
+#[test]
+fn checkpoint_restore() {
+    with_default_instance(|config, marble| {
+        marble
+            .write_batch((0..10_u64).map(|object_id| (object_id, Some(vec![1_u8; 16]))))
+            .unwrap();
+        marble
+            .write_batch((10..20_u64).map(|object_id| (object_id, Some(vec![1_u8; 16]))))
+            .unwrap();
+
+        let checkpoint = marble.checkpoint().unwrap();
+        assert_eq!(checkpoint.files, 2);
+        assert_eq!(marble.checkpoint().unwrap(), checkpoint);
+
+        marble
+            .write_batch((15..30_u64).map(|object_id| (object_id, Some(vec![2_u8; 16]))))
+            .unwrap();
+        marble.write_batch([(0_u64, None::<Vec<u8>>)]).unwrap();
+        assert_eq!(marble.len(), 29);
+        assert_eq!(marble.checkpoint().unwrap().files, 4);
+
+        // the store is locked while it is open
+        assert!(config.restore(checkpoint).is_err());
+        drop(marble);
+
+        let read_only = Config {
+            read_only: true,
+            ..config.clone()
+        };
+        match read_only.restore(checkpoint) {
+            Err(MarbleError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied),
+            other => panic!("expected a PermissionDenied error, got {other:?}"),
+        }
+
+        let mut marble = config.restore(checkpoint).unwrap();
+        assert_eq!(marble.len(), 20);
+        for object_id in 0..20_u64 {
+            assert_eq!(&*marble.read(object_id).unwrap().unwrap(), &[1_u8; 16]);
+        }
+        assert!(marble.read(25).unwrap().is_none());
+
+        marble = restart(config, marble);
+        assert_eq!(marble.len(), 20);
+        assert_eq!(heap_files(&config.path).len(), 2);
+
+        // once a file included in the checkpoint is removed
+        // because all of its objects were replaced, it can no
+        // longer be restored
+        marble
+            .write_batch((0..10_u64).map(|object_id| (object_id, Some(vec![3_u8; 16]))))
+            .unwrap();
+        drop(marble);
+
+        match config.restore(checkpoint) {
+            Err(MarbleError::NotFound(_)) => {}
+            other => panic!("expected a NotFound error, got {other:?}"),
+        }
+
+        let marble = config.open().unwrap();
+        assert_eq!(&*marble.read(0).unwrap().unwrap(), &[3_u8; 16]);
+        assert_eq!(&*marble.read(10).unwrap().unwrap(), &[1_u8; 16]);
+    });
+}
+
+#[test]
+fn restore_quarantines_files_after_checkpoint() {
+    let config = Config {
+        path: test_path(),
+        recovery_policy: RecoveryPolicy::QuarantineAhead,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, marble| {
+        marble
+            .write_batch((0..10_u64).map(|object_id| (object_id, Some(vec![1_u8; 16]))))
+            .unwrap();
+        let checkpoint = marble.checkpoint().unwrap();
+
+        marble
+            .write_batch((5..20_u64).map(|object_id| (object_id, Some(vec![2_u8; 16]))))
+            .unwrap();
+        marble
+            .write_batch((20..30_u64).map(|object_id| (object_id, Some(vec![3_u8; 16]))))
+            .unwrap();
+        let files = heap_files(&config.path);
+        assert_eq!(files.len(), 3);
+        drop(marble);
+
+        let marble = config.restore(checkpoint).unwrap();
+        assert_eq!(marble.len(), 10);
+        assert!(marble.read(25).unwrap().is_none());
+        assert_eq!(heap_files(&config.path), files[..1]);
+
+        // the newer files are moved rather than removed
+        let quarantine = config.path.join("quarantine");
+        let mut quarantined: Vec<_> = std::fs::read_dir(&quarantine)
+            .unwrap()
+            .map(|entry_res| entry_res.unwrap().path())
+            .collect();
+        quarantined.sort();
+        let expected: Vec<_> = files[1..]
+            .iter()
+            .map(|path| quarantine.join(path.file_name().unwrap()))
+            .collect();
+        assert_eq!(quarantined, expected);
+
+        // and moving them back undoes the restore
+        drop(marble);
+        for (original, quarantined) in files[1..].iter().zip(&quarantined) {
+            std::fs::rename(quarantined, original).unwrap();
+        }
+
+        let marble = config.open().unwrap();
+        assert_eq!(marble.len(), 30);
+        assert_eq!(&*marble.read(5).unwrap().unwrap(), &[2_u8; 16]);
+        assert_eq!(&*marble.read(25).unwrap().unwrap(), &[3_u8; 16]);
+        assert!(marble.verify().unwrap().is_ok());
+    });
+}
+
+#[test]
+fn partition_by_generation() {
+    fn shard_by_generation(_object_id: u64, _object_size: usize, generation: u8) -> u8 {
+        generation
+    }
+
+    let config = Config {
+        path: test_path(),
+        partition_function: shard_by_generation,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        marble
+            .write_batch((0..10_u64).map(|object_id| (object_id, Some(vec![1_u8; 16]))))
+            .unwrap();
+
+        for file in marble.file_fragmentation() {
+            assert_eq!((file.shard, file.generation), (0, 0));
+        }
+
+        for pass in 1..=4_u8 {
+            let locations: Vec<_> = marble
+                .file_fragmentation()
+                .into_iter()
+                .map(|file| file.location)
+                .collect();
+            marble.compact_files(&locations).unwrap();
+
+            let files = marble.file_fragmentation();
+            assert_eq!(files.len(), 1);
+            let expected = pass.min(3);
+            assert_eq!((files[0].shard, files[0].generation), (expected, expected));
+        }
+
+        marble = restart(config, marble);
+        let files = marble.file_fragmentation();
+        assert_eq!((files[0].shard, files[0].generation), (3, 3));
+        for object_id in 0..10_u64 {
+            assert_eq!(&*marble.read(object_id).unwrap().unwrap(), &[1_u8; 16]);
+        }
+    });
+}
+
+#[test]
+fn write_batch_once() {
+    with_default_instance(|config, mut marble| {
+        assert!(marble
+            .write_batch_once(5, [(0_u64, Some(vec![1_u8]))])
+            .unwrap());
+
+        // the same ID, or an older one, is skipped
+        assert!(!marble
+            .write_batch_once(5, [(0_u64, Some(vec![2_u8]))])
+            .unwrap());
+        assert!(!marble
+            .write_batch_once(4, [(1_u64, Some(vec![2_u8]))])
+            .unwrap());
+        assert_eq!(&*marble.read(0).unwrap().unwrap(), &[1_u8]);
+        assert!(marble.read(1).unwrap().is_none());
+
+        // the batch ID is not visible as an object
+        assert_eq!(marble.len(), 1);
+        assert_eq!(marble.range(0, u64::MAX).collect::<Vec<_>>(), vec![0]);
+        assert_eq!(marble.stats().live_objects, 2);
+
+        marble = restart(config, marble);
+        assert!(!marble
+            .write_batch_once(5, [(0_u64, Some(vec![2_u8]))])
+            .unwrap());
+
+        // an empty batch still records its ID
+        assert!(marble
+            .write_batch_once(6, std::iter::empty::<(u64, Option<Vec<u8>>)>())
+            .unwrap());
+
+        // the ID survives rewriting and removing the files
+        // that it was written into
+        marble.write_batch([(0_u64, Some(vec![3_u8]))]).unwrap();
+        let locations: Vec<_> = marble
+            .file_fragmentation()
+            .into_iter()
+            .map(|file| file.location)
+            .collect();
+        marble.compact_files(&locations).unwrap();
+        assert!(marble.verify().unwrap().is_ok());
+
+        marble = restart(config, marble);
+        assert!(!marble
+            .write_batch_once(6, [(0_u64, Some(vec![4_u8]))])
+            .unwrap());
+        assert!(marble
+            .write_batch_once(7, [(0_u64, Some(vec![4_u8]))])
+            .unwrap());
+        assert_eq!(&*marble.read(0).unwrap().unwrap(), &[4_u8]);
+        assert_eq!(marble.len(), 1);
+
+        assert!(matches!(
+            marble.write_batch_once(8, [(u64::MAX, Some(vec![1_u8]))]),
+            Err(MarbleError::ReservedObjectId(u64::MAX))
+        ));
+    });
+}
+
+#[test]
+fn read_cancellable() {
+    use std::sync::atomic::AtomicBool;
+
+    with_default_instance(|_config, marble| {
+        marble.write_batch([(0_u64, Some(vec![1_u8; 16]))]).unwrap();
+
+        let cancel = AtomicBool::new(false);
+        assert_eq!(
+            &*marble.read_cancellable(0, &cancel).unwrap().unwrap(),
+            &[1_u8; 16]
+        );
+        assert!(marble.read_cancellable(1, &cancel).unwrap().is_none());
+
+        cancel.store(true, std::sync::atomic::Ordering::Release);
+        match marble.read_cancellable(0, &cancel) {
+            Err(MarbleError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::Interrupted),
+            other => panic!("expected an Interrupted error, got {other:?}"),
+        }
+
+        // objects that need no IO are still returned
+        assert!(marble.read_cancellable(1, &cancel).unwrap().is_none());
+        assert_eq!(&*marble.read(0).unwrap().unwrap(), &[1_u8; 16]);
+    });
+}
+
+#[test]
+fn allocate_object_id() {
+    with_default_instance(|config, mut marble| {
+        marble.write_batch([(10_u64, Some(vec![1_u8]))]).unwrap();
+
+        let mut allocated = vec![];
+        for _ in 0..3000 {
+            allocated.push(marble.allocate_object_id().unwrap());
+        }
+        assert!(allocated[0] > 10);
+
+        // allocated IDs remain above user-chosen ones
+        marble
+            .write_batch([(100_000_u64, Some(vec![1_u8]))])
+            .unwrap();
+        allocated.push(marble.allocate_object_id().unwrap());
+        assert!(*allocated.last().unwrap() > 100_000);
+
+        // IDs that were allocated but never written are not
+        // reused after a restart
+        marble = restart(config, marble);
+        for _ in 0..10 {
+            allocated.push(marble.allocate_object_id().unwrap());
+        }
+
+        for pair in allocated.windows(2) {
+            assert!(pair[0] < pair[1]);
+        }
+
+        let read_only = Config {
+            read_only: true,
+            ..config.clone()
+        };
+        drop(marble);
+        let marble = read_only.open().unwrap();
+        assert!(marble.allocate_object_id().is_err());
+    });
+}
+
+#[test]
+fn reserve_object_ids() {
+    with_default_instance(|config, mut marble| {
+        marble.write_batch([(10_u64, Some(vec![1_u8]))]).unwrap();
+
+        let mut ranges = vec![marble.reserve_object_ids(100).unwrap()];
+        assert!(ranges[0].start > 10);
+        assert_eq!(ranges[0].end - ranges[0].start, 100);
+
+        let object_id = marble.allocate_object_id().unwrap();
+        ranges.push(object_id..object_id + 1);
+        ranges.push(marble.reserve_object_ids(5000).unwrap());
+        assert!(marble.reserve_object_ids(0).unwrap().is_empty());
+
+        // reserved IDs are not issued again after a restart,
+        // even if no object was written with them
+        marble = restart(config, marble);
+        ranges.push(marble.reserve_object_ids(100).unwrap());
+        let object_id = marble.allocate_object_id().unwrap();
+        ranges.push(object_id..object_id + 1);
+
+        for pair in ranges.windows(2) {
+            assert!(pair[0].end <= pair[1].start);
+        }
+
+        assert!(marble.reserve_object_ids(MAX_OBJECT_ID).is_err());
+        assert!(marble.reserve_object_ids(u64::MAX).is_err());
+    });
+}
+
+#[test]
+fn reuse_object_ids() {
+    let config = Config {
+        path: test_path(),
+        reuse_object_ids: true,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        marble
+            .write_batch((0..10_u64).map(|object_id| (object_id, Some(vec![1_u8; 16]))))
+            .unwrap();
+        marble
+            .write_batch([3_u64, 7].map(|object_id| (object_id, None::<Vec<u8>>)))
+            .unwrap();
+
+        marble = restart(config, marble);
+        assert_eq!(marble.allocate_object_id().unwrap(), 3);
+
+        // deletions made after allocation started are reused
+        // as well
+        marble.write_batch([(5_u64, None::<Vec<u8>>)]).unwrap();
+        assert_eq!(marble.allocate_object_id().unwrap(), 5);
+
+        // objects that were written again are not reused
+        marble.write_batch([(7_u64, Some(vec![2_u8]))]).unwrap();
+        assert_eq!(marble.allocate_object_id().unwrap(), 10);
+
+        // reused objects only return their new data
+        assert!(marble.read(3).unwrap().is_none());
+        marble.write_batch([(3_u64, Some(vec![3_u8]))]).unwrap();
+        assert_eq!(&*marble.read(3).unwrap().unwrap(), &[3_u8]);
+        assert_eq!(&*marble.read(7).unwrap().unwrap(), &[2_u8]);
+
+        marble = restart(config, marble);
+        assert_eq!(&*marble.read(3).unwrap().unwrap(), &[3_u8]);
+        // 5 was never written after it was reused
+        assert_eq!(marble.allocate_object_id().unwrap(), 5);
+        assert!(marble.allocate_object_id().unwrap() > 10);
+    });
+}
+
+#[test]
+fn bulk_load() {
+    let config = Config {
+        path: test_path(),
+        target_file_size: 1 << 20,
+        fsync_each_batch: true,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        let objects = (0..100_000_u64).map(|object_id| (object_id, object_id.to_le_bytes()));
+        marble.bulk_load(objects).unwrap();
+
+        // objects are packed into files of the target size
+        let files = heap_files(&config.path).len();
+        assert!((2..=4).contains(&files), "{files} files were written");
+
+        let check = |marble: &Marble| {
+            assert_eq!(marble.len(), 100_000);
+            for object_id in (0..100_000_u64).step_by(997) {
+                assert_eq!(
+                    &*marble.read(object_id).unwrap().unwrap(),
+                    &object_id.to_le_bytes()
+                );
+            }
+        };
+        check(&marble);
+
+        marble = restart(config, marble);
+        check(&marble);
+
+        // small objects are written into a single file,
+        // rather than one per object
+        marble
+            .bulk_load((0..200_u64).map(|object_id| (object_id, [2_u8])))
+            .unwrap();
+        assert_eq!(heap_files(&config.path).len(), files + 1);
+        assert_eq!(&*marble.read(199).unwrap().unwrap(), &[2_u8]);
+    });
+}
+
+#[test]
+fn read_with_crc() {
+    with_default_instance(|config, mut marble| {
+        marble
+            .write_batch([(7_u64, Some(vec![1_u8; 100]))])
+            .unwrap();
+
+        let check = |marble: &Marble| {
+            let (data, crc) = marble.read_with_crc(7).unwrap().unwrap();
+            assert_eq!(&*data, &[1_u8; 100]);
+
+            // the crc covers the length and object ID in the
+            // record header, followed by the data
+            let (path, offset) = marble.locate(7).unwrap();
+            let file = std::fs::read(path).unwrap();
+            let offset = usize::try_from(offset).unwrap();
+            let record = &file[offset..offset + 20 + data.len()];
+            assert_eq!(crc.to_le_bytes(), record[..4]);
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&record[12..20]);
+            hasher.update(&record[4..12]);
+            hasher.update(&record[20..]);
+            assert_eq!(crc, hasher.finalize());
+        };
+
+        check(&marble);
+        marble = restart(config, marble);
+        check(&marble);
+
+        assert!(marble.read_with_crc(8).unwrap().is_none());
+    });
+}
+
+#[test]
+fn size_on_disk() {
+    fn walk(path: &std::path::Path) -> u64 {
+        std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry_res| {
+                let path = entry_res.unwrap().path();
+                if path.is_dir() {
+                    walk(&path)
+                } else {
+                    std::fs::metadata(path).unwrap().len()
+                }
+            })
+            .sum()
+    }
+
+    with_default_instance(|config, mut marble| {
+        assert_eq!(marble.size_on_disk().unwrap(), walk(&config.path));
+
+        for object_id in 0..8_u64 {
+            marble
+                .write_batch([(object_id, Some(vec![1_u8; 1024]))])
+                .unwrap();
+        }
+        let size = marble.size_on_disk().unwrap();
+        assert_eq!(size, walk(&config.path));
+        assert!(size >= marble.stats().total_file_size);
+
+        // after some files are emptied and others compacted
+        marble
+            .write_batch((0..6_u64).map(|object_id| (object_id, None::<Vec<u8>>)))
+            .unwrap();
+        marble.maintenance().unwrap();
+        assert_eq!(marble.size_on_disk().unwrap(), walk(&config.path));
+        assert!(marble.size_on_disk().unwrap() < size);
+
+        marble = restart(config, marble);
+        assert_eq!(marble.size_on_disk().unwrap(), walk(&config.path));
+    });
+}
+
+#[test]
+fn write_batch_with_metadata() {
+    with_default_instance(|config, mut marble| {
+        marble
+            .write_batch_with_metadata([
+                (0_u64, Some((*b"typetag1", vec![1_u8; 64]))),
+                (1, Some(([2; OBJECT_METADATA_LEN], vec![]))),
+            ])
+            .unwrap();
+        marble.write_batch([(2_u64, Some(vec![3_u8; 64]))]).unwrap();
+
+        let check = |marble: &Marble| {
+            let (data, metadata) = marble.read_with_metadata(0).unwrap().unwrap();
+            assert_eq!((&*data, metadata), (&[1_u8; 64][..], *b"typetag1"));
+            assert_eq!(&*marble.read(0).unwrap().unwrap(), &[1_u8; 64]);
+
+            let (data, metadata) = marble.read_with_metadata(1).unwrap().unwrap();
+            assert_eq!((&*data, metadata), (&[][..], [2; OBJECT_METADATA_LEN]));
+
+            // objects written without metadata have zeroes
+            let (data, metadata) = marble.read_with_metadata(2).unwrap().unwrap();
+            assert_eq!(
+                (&*data, metadata),
+                (&[3_u8; 64][..], [0; OBJECT_METADATA_LEN])
+            );
+
+            assert!(marble.read_with_metadata(3).unwrap().is_none());
+        };
+
+        check(&marble);
+        marble = restart(config, marble);
+        check(&marble);
+
+        // metadata is kept when objects are rewritten
+        let locations: Vec<_> = marble
+            .file_fragmentation()
+            .into_iter()
+            .map(|file| file.location)
+            .collect();
+        marble.compact_files(&locations).unwrap();
+        check(&marble);
+        assert!(marble.verify().unwrap().is_ok());
+
+        // the crc covers the metadata
+        let (path, offset) = marble.locate(0).unwrap();
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[usize::try_from(offset).unwrap() + 20] ^= 1;
+        std::fs::write(&path, &contents).unwrap();
+        assert!(matches!(
+            marble.read_with_metadata(0),
+            Err(MarbleError::Corruption(_))
+        ));
+    });
+}
+
+#[test]
+fn rewrite_keeps_metadata_and_increments_generation() {
+    with_default_instance(|config, mut marble| {
+        marble
+            .write_batch_with_metadata([(0_u64, Some((*b"typetag1", vec![1_u8; 64])))])
+            .unwrap();
+
+        let compact = |marble: &Marble| {
+            let files = marble.file_fragmentation();
+            assert_eq!(files.len(), 1);
+            marble.compact_files(&[files[0].location]).unwrap();
+
+            let files = marble.file_fragmentation();
+            assert_eq!(files.len(), 1);
+            files[0].generation
+        };
+
+        assert_eq!(compact(&marble), 1);
+        assert_eq!(compact(&marble), 2);
+
+        // an object with metadata in a generation 2 file comes
+        // out of compaction in generation 3 with its metadata
+        assert_eq!(compact(&marble), 3);
+        let (data, metadata) = marble.read_with_metadata(0).unwrap().unwrap();
+        assert_eq!((&*data, metadata), (&[1_u8; 64][..], *b"typetag1"));
+
+        // generation 3 is the oldest, and survives recovery
+        assert_eq!(compact(&marble), 3);
+        marble = restart(config, marble);
+        assert_eq!(marble.file_fragmentation()[0].generation, 3);
+        let (data, metadata) = marble.read_with_metadata(0).unwrap().unwrap();
+        assert_eq!((&*data, metadata), (&[1_u8; 64][..], *b"typetag1"));
+        assert!(marble.verify().unwrap().is_ok());
+    });
+}
+
+#[test]
+fn overlapping_files() {
+    with_default_instance(|config, marble| {
+        marble.write_batch([(0_u64, Some(vec![1_u8; 64]))]).unwrap();
+        marble.write_batch([(1_u64, Some(vec![2_u8; 64]))]).unwrap();
+        drop(marble);
+
+        // move the second file into the lsn range of the first
+        let files = heap_files(&config.path);
+        assert_eq!(files.len(), 2);
+        let first_name = files[0].file_name().unwrap().to_str().unwrap();
+        let second_name = files[1].file_name().unwrap().to_str().unwrap();
+        let first_lsn = u64::from_str_radix(&first_name[..16], 16).unwrap();
+        let overlapping_name = format!("{:016x}{}", first_lsn + 1, &second_name[16..]);
+        std::fs::rename(&files[1], files[1].with_file_name(overlapping_name)).unwrap();
+
+        let err = config.open().unwrap_err();
+        assert!(matches!(err, MarbleError::Corruption(_)));
+        assert!(err.to_string().contains("overlaps"), "{err}");
+    });
+}
+
+#[test]
+fn open_in_memory() {
+    let in_memory = marble::open_in_memory().unwrap();
+
+    with_default_instance(|_config, on_disk| {
+        for marble in [&in_memory, &on_disk] {
+            for round in 0..4_u8 {
+                let batch = (0..64_u64).map(|object_id| {
+                    let data = if (object_id + u64::from(round)) % 5 == 0 {
+                        None
+                    } else {
+                        Some(vec![round; usize::try_from(object_id).unwrap()])
+                    };
+                    (object_id, data)
+                });
+                marble.write_batch(batch).unwrap();
+            }
+            marble.maintenance().unwrap();
+        }
+
+        for object_id in 0..65_u64 {
+            assert_eq!(
+                in_memory.read(object_id).unwrap(),
+                on_disk.read(object_id).unwrap()
+            );
+        }
+        assert_eq!(in_memory.len(), on_disk.len());
+    });
+
+    // the store is removed once every clone is dropped
+    let (file, _offset) = in_memory.locate(1).unwrap();
+    let store_path = file.ancestors().nth(3).unwrap().to_path_buf();
+    assert!(store_path.join("heap").is_dir());
+
+    let clone = in_memory.clone();
+    drop(in_memory);
+    assert_eq!(&*clone.read(1).unwrap().unwrap(), &[3_u8]);
+    drop(clone);
+    assert!(!store_path.exists());
+
+    let config = Config {
+        path: test_path(),
+        temporary: true,
+        read_only: true,
+        ..Default::default()
+    };
+    assert!(matches!(config.open(), Err(MarbleError::InvalidConfig(_))));
+}
+
+#[test]
+fn recovery_events() {
+    #[derive(Debug, Default)]
+    struct RecordingMetrics {
+        events: std::sync::Mutex<Vec<RecoveryEvent>>,
+    }
+
+    impl MarbleMetrics for RecordingMetrics {
+        fn on_recovery_event(&self, event: RecoveryEvent) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    let recorder = std::sync::Arc::new(RecordingMetrics::default());
+    let take_events = || std::mem::take(&mut *recorder.events.lock().unwrap());
+
+    let config = Config {
+        path: test_path(),
+        metrics: Some(recorder.clone()),
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        marble.write_batch([(0_u64, Some(vec![1_u8]))]).unwrap();
+        drop(marble);
+        assert_eq!(take_events(), vec![]);
+
+        let tmp_path = config.path.join("heap").join("00").join("7-tmp");
+        let unknown_path = config.path.join("heap").join("unknown");
+        std::fs::write(&tmp_path, [1, 2, 3]).unwrap();
+        std::fs::write(&unknown_path, [1, 2, 3]).unwrap();
+
+        let read_only = Config {
+            read_only: true,
+            ..config.clone()
+        };
+        drop(read_only.open().unwrap());
+        let mut events = take_events();
+        events.sort_by_key(|event| format!("{event:?}"));
+        assert_eq!(
+            events,
+            vec![
+                RecoveryEvent::IgnoredTemporaryFile(tmp_path.clone()),
+                RecoveryEvent::UnknownFile(unknown_path.clone()),
+            ]
+        );
+
+        marble = config.open().unwrap();
+        let mut events = take_events();
+        events.sort_by_key(|event| format!("{event:?}"));
+        assert_eq!(
+            events,
+            vec![
+                RecoveryEvent::RemovedTemporaryFile(tmp_path.clone()),
+                RecoveryEvent::UnknownFile(unknown_path.clone()),
+            ]
+        );
+        assert!(!tmp_path.exists());
+        std::fs::remove_file(&unknown_path).unwrap();
+
+        let checkpoint = marble.checkpoint().unwrap();
+        marble.write_batch([(1_u64, Some(vec![2_u8]))]).unwrap();
+        let (_, newer_file) = marble.files_since(checkpoint.lsn + 1).pop().unwrap();
+        drop(marble);
+
+        marble = config.restore(checkpoint).unwrap();
+        assert_eq!(
+            take_events(),
+            vec![RecoveryEvent::RemovedFileAfterCheckpoint(newer_file)]
+        );
+        assert!(marble.read(1).unwrap().is_none());
+    });
+}
+
+#[test]
+fn keep_tmp_files() {
+    let config = Config {
+        path: test_path(),
+        keep_tmp_files: true,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        marble.write_batch([(0_u64, Some(vec![1_u8]))]).unwrap();
+        let files = heap_files(&config.path);
+        drop(marble);
+
+        // several names that new writes may also try to use
+        let tmp_paths: Vec<_> = (0..64)
+            .map(|n| config.path.join("heap").join("00").join(format!("{n}-tmp")))
+            .collect();
+        for tmp_path in &tmp_paths {
+            std::fs::write(tmp_path, [1, 2, 3]).unwrap();
+        }
+
+        marble = config.open().unwrap();
+        for object_id in 1..8_u64 {
+            marble
+                .write_batch([(object_id, Some(vec![2_u8; 16]))])
+                .unwrap();
+        }
+        assert_eq!(marble.len(), 8);
+        assert_eq!(heap_files(&config.path)[..1], files);
+
+        marble = restart(config, marble);
+        assert_eq!(marble.len(), 8);
+        assert!(marble.verify().unwrap().is_ok());
+        for tmp_path in &tmp_paths {
+            assert_eq!(std::fs::read(tmp_path).unwrap(), [1, 2, 3]);
+        }
+
+        // without the flag they are removed as usual
+        drop(marble);
+        let removing = Config {
+            keep_tmp_files: false,
+            ..config.clone()
+        };
+        drop(removing.open().unwrap());
+        assert!(tmp_paths.iter().all(|tmp_path| !tmp_path.exists()));
+    });
+}
+
+#[test]
+fn read_unknown_object_ids() {
+    with_default_instance(|config, mut marble| {
+        let check = |marble: &Marble| {
+            for object_id in [0, 1, MAX_OBJECT_ID, u64::MAX] {
+                assert!(marble.read(object_id).unwrap().is_none());
+                assert!(marble.read_cached(object_id).unwrap().is_none());
+                assert!(marble.read_with_crc(object_id).unwrap().is_none());
+                assert!(marble.read_with_metadata(object_id).unwrap().is_none());
+                let cancel = std::sync::atomic::AtomicBool::new(false);
+                assert!(marble
+                    .read_cancellable(object_id, &cancel)
+                    .unwrap()
+                    .is_none());
+                assert!(marble.snapshot().read(object_id).unwrap().is_none());
+                assert!(marble.location(object_id).is_none());
+                assert!(marble.locate(object_id).is_none());
+            }
+        };
+
+        check(&marble);
+
+        // the ID stored by `write_batch_once` is not readable
+        marble
+            .write_batch_once(1, [(5_u64, Some(vec![5_u8; 8]))])
+            .unwrap();
+        check(&marble);
+
+        // a record of another object is reported as corrupt
+        // rather than returned or panicking
+        let (path, offset) = marble.locate(5).unwrap();
+        let offset = usize::try_from(offset).unwrap();
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[offset + 4..offset + 12].copy_from_slice(&6_u64.to_le_bytes());
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&contents[offset + 12..offset + 20]);
+        hasher.update(&contents[offset + 4..offset + 12]);
+        hasher.update(&contents[offset + 20..offset + 28]);
+        contents[offset..offset + 4].copy_from_slice(&hasher.finalize().to_le_bytes());
+        std::fs::write(&path, &contents).unwrap();
+
+        marble = restart(config, marble);
+        check(&marble);
+        assert!(matches!(marble.read(5), Err(MarbleError::Corruption(_))));
+    });
+}
+
+#[test]
+fn min_compaction_age() {
+    let config = Config {
+        path: test_path(),
+        min_compaction_age: Some(std::time::Duration::from_secs(3600)),
+        min_compaction_files: 1,
+        ..Default::default()
+    };
+
+    with_instance(config, |config, mut marble| {
+        marble
+            .write_batch((0..4_u64).map(|object_id| (object_id, Some(vec![1_u8; 64]))))
+            .unwrap();
+        marble
+            .write_batch((4..8_u64).map(|object_id| (object_id, Some(vec![1_u8; 64]))))
+            .unwrap();
+        // leave a quarter of each file live
+        marble
+            .write_batch(
+                (0..3_u64)
+                    .chain(4..7)
+                    .map(|object_id| (object_id, Some(vec![2_u8; 64]))),
+            )
+            .unwrap();
+
+        let files = heap_files(&config.path);
+        assert_eq!(files.len(), 3);
+
+        // make the first file look like it was written two
+        // hours ago
+        let two_hours_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(7200);
+        std::fs::File::options()
+            .write(true)
+            .open(&files[0])
+            .unwrap()
+            .set_modified(two_hours_ago)
+            .unwrap();
+
+        let report = marble.maintenance().unwrap();
+        assert_eq!(report.files_rewritten, 1);
+        let remaining = heap_files(&config.path);
+        assert!(!remaining.contains(&files[0]));
+        assert!(remaining.contains(&files[1]));
+
+        // the young file is compacted once the age limit no
+        // longer applies
+        let no_min_age = Config {
+            min_compaction_age: None,
+            ..config.clone()
+        };
+        drop(marble);
+        marble = no_min_age.open().unwrap();
+        let report = marble.maintenance().unwrap();
+        assert_eq!(report.files_rewritten, 1);
+        assert!(!heap_files(&config.path).contains(&files[1]));
+
+        for object_id in 0..8_u64 {
+            let expected = if object_id % 4 == 3 { 1_u8 } else { 2 };
+            assert_eq!(&*marble.read(object_id).unwrap().unwrap(), &[expected; 64]);
+        }
+    });
+}
+
+#[test]
+fn read_result_shapes() {
+    with_default_instance(|_config, marble| {
+        marble
+            .write_batch([
+                (0_u64, Some(vec![1_u8; 64])),
+                (1, Some(vec![2_u8; 64])),
+                (2, Some(vec![3_u8; 64])),
+            ])
+            .unwrap();
+        marble.write_batch([(1_u64, None::<Vec<u8>>)]).unwrap();
+
+        // present
+        assert_eq!(&*marble.read(0).unwrap().unwrap(), &[1_u8; 64]);
+        // deleted
+        assert!(matches!(marble.read(1), Ok(None)));
+        // never written
+        assert!(matches!(marble.read(3), Ok(None)));
+
+        // corrupted
+        let (path, offset) = marble.locate(2).unwrap();
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[usize::try_from(offset).unwrap() + 20] ^= 1;
+        std::fs::write(&path, &contents).unwrap();
+        assert!(matches!(marble.read(2), Err(MarbleError::Corruption(_))));
+
+        // other objects are unaffected
+        assert_eq!(&*marble.read(0).unwrap().unwrap(), &[1_u8; 64]);
+        assert!(matches!(marble.read(1), Ok(None)));
+    });
+}
+
+#[derive(Default, Clone)]
+struct BufferPool {
+    free: std::sync::Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+    allocations: std::sync::Arc<AtomicU64>,
+}
+
+struct PooledBuffer {
+    buf: Vec<u8>,
+    pool: BufferPool,
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl AsMut<[u8]> for PooledBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let buf = std::mem::take(&mut self.buf);
+        self.pool.free.lock().unwrap().push(buf);
+    }
+}
+
+impl BufferProvider for BufferPool {
+    type Buffer = PooledBuffer;
+
+    fn buffer(&self, len: usize) -> PooledBuffer {
+        let mut buf = self.free.lock().unwrap().pop().unwrap_or_else(|| {
+            self.allocations.fetch_add(1, SeqCst);
+            Vec::new()
+        });
+        buf.resize(len, 0);
+
+        PooledBuffer {
+            buf,
+            pool: self.clone(),
+        }
+    }
+}
+
+#[test]
+fn read_with() {
+    for zstd_compression_level in [None, Some(3)] {
+        let config = Config {
+            path: test_path(),
+            zstd_compression_level,
+            ..Default::default()
+        };
+
+        with_instance(config, |_config, marble| {
+            let objects: Vec<(u64, Vec<u8>)> = (0..64_u64)
+                .map(|object_id| {
+                    (
+                        object_id,
+                        vec![object_id as u8; 1 + object_id as usize * 37],
+                    )
+                })
+                .collect();
+
+            marble
+                .write_batch(
+                    objects
+                        .iter()
+                        .map(|(object_id, data)| (*object_id, Some(data))),
+                )
+                .unwrap();
+            marble
+                .write_batch_with_metadata([(64_u64, Some(([9_u8; 8], vec![7_u8; 100])))])
+                .unwrap();
+
+            let pool = BufferPool::default();
+
+            for _ in 0..10 {
+                for (object_id, data) in &objects {
+                    let buffer = marble.read_with(*object_id, &pool).unwrap().unwrap();
+                    assert_eq!(buffer.as_ref(), &**data);
+                }
+
+                // metadata is not part of the returned data
+                let buffer = marble.read_with(64, &pool).unwrap().unwrap();
+                assert_eq!(buffer.as_ref(), &[7_u8; 100]);
+
+                assert!(marble.read_with(65, &pool).unwrap().is_none());
+            }
+
+            // each buffer was returned to the pool before the
+            // next read, so only one was ever allocated
+            assert_eq!(pool.allocations.load(SeqCst), 1);
+        });
+    }
+}
+
+#[test]
+fn check_locations() {
+    with_default_instance(|config, mut marble| {
+        marble.write_batch([(1, Some(vec![1_u8; 64]))]).unwrap();
+        marble
+            .write_batch([(2, Some(vec![2_u8; 64])), (3, Some(vec![3_u8; 64]))])
+            .unwrap();
+        marble.write_batch([(4, None::<Vec<u8>>)]).unwrap();
+
+        assert_eq!(marble.check_locations().unwrap(), Vec::<u64>::new());
+
+        drop(marble);
+
+        let files = heap_files(&config.path);
+
+        // flip a bit in the stored bytes of object 1, which
+        // only a full verify detects
+        let mut file_1 = std::fs::read(&files[0]).unwrap();
+        file_1[30] ^= 1;
+        std::fs::write(&files[0], file_1).unwrap();
+
+        // point the trailer entry of object 2, which is sorted
+        // first, at the record of object 3, fixing up the
+        // trailer crc so that the file is still recovered
+        let name = files[1].file_name().unwrap().to_str().unwrap();
+        let trailer_offset = u64::from_str_radix(name.split('-').nth(1).unwrap(), 16).unwrap();
+        let trailer_start = usize::try_from(trailer_offset).unwrap();
+        let mut file_2 = std::fs::read(&files[1]).unwrap();
+        let entry_location = trailer_start + 20 + 8;
+        let object_3_offset = (20 + 64) << 1;
+        file_2[entry_location..entry_location + 8]
+            .copy_from_slice(&u64::to_le_bytes(object_3_offset));
+        let crc = crc32fast::hash(&file_2[trailer_start + 4..]);
+        file_2[trailer_start..trailer_start + 4].copy_from_slice(&crc.to_le_bytes());
+        std::fs::write(&files[1], file_2).unwrap();
+
+        marble = config.open().unwrap();
+
+        assert_eq!(marble.check_locations().unwrap(), vec![2]);
+
+        let report = marble.verify().unwrap();
+        assert_eq!(report.corrupt_objects, vec![1, 2]);
+    });
+}
+
+#[test]
+fn delete_batch() {
+    with_default_instance(|_config, marble| {
+        for chunk in 0..6_u64 {
+            marble
+                .write_batch((chunk * 200..(chunk + 1) * 200).map(|i| (i, Some(vec![1_u8; 16]))))
+                .unwrap();
+        }
+
+        let object_ids: Vec<u64> = (0..1000).collect();
+        marble.delete_batch(&object_ids).unwrap();
+
+        for object_id in 0..1200 {
+            assert_eq!(marble.read(object_id).unwrap().is_some(), object_id >= 1000);
+        }
+
+        // the deletions were written to a single new file
+        let files = marble.file_fragmentation();
+        let live_objects: Vec<u64> = files.iter().map(|file| file.live_objects).collect();
+        assert_eq!(live_objects, vec![0, 0, 0, 0, 0, 200, 1000]);
+    });
+}
+
+#[test]
+fn record_alignment() {
+    for zstd_compression_level in [None, Some(3)] {
+        let config = Config {
+            path: test_path(),
+            record_alignment: Some(512),
+            zstd_compression_level,
+            min_compaction_files: 1,
+            ..Default::default()
+        };
+
+        with_instance(config, |config, mut marble| {
+            let objects: Vec<(u64, Vec<u8>)> = (0..32_u64)
+                .map(|object_id| (object_id, vec![object_id as u8; object_id as usize * 100]))
+                .collect();
+
+            marble
+                .write_batch(
+                    objects
+                        .iter()
+                        .map(|(object_id, data)| (*object_id, Some(data))),
+                )
+                .unwrap();
+            for object_id in 0..16 {
+                marble
+                    .write_batch([(object_id, Some(&objects[object_id as usize].1))])
+                    .unwrap();
+            }
+
+            let check = |marble: &Marble| {
+                for (object_id, data) in &objects {
+                    let (_path, offset) = marble.locate(*object_id).unwrap();
+                    assert_eq!(offset % 512, 0, "object {object_id} is at offset {offset}");
+                    assert_eq!(&*marble.read(*object_id).unwrap().unwrap(), &**data);
+                }
+            };
+
+            check(&marble);
+
+            // maintenance rewrites the padded files
+            let report = marble.maintenance().unwrap();
+            assert!(report.files_rewritten > 0, "{report:?}");
+            check(&marble);
+
+            marble = restart(config, marble);
+            check(&marble);
+            assert!(marble.verify().unwrap().is_ok());
+        });
+    }
+
+    let config = Config {
+        record_alignment: Some(1000),
+        ..Default::default()
+    };
+    assert!(matches!(config.open(), Err(MarbleError::InvalidConfig(_))));
+}
+
+#[test]
+fn locations() {
+    with_default_instance(|_config, marble| {
+        marble
+            .write_batch([
+                (0_u64, Some(vec![0_u8; 8])),
+                (2, Some(vec![2_u8; 8])),
+                (3, Some(vec![3_u8; 8])),
+            ])
+            .unwrap();
+        marble
+            .write_batch([(2_u64, None), (5, Some(vec![5_u8; 8]))])
+            .unwrap();
+
+        let object_ids = [5, 1, 0, 2, 3, 100, 0, u64::MAX];
+        let locations = marble.locations(&object_ids);
+
+        let expected: Vec<Option<DiskLocation>> = object_ids
+            .iter()
+            .map(|object_id| marble.location(*object_id))
+            .collect();
+        assert_eq!(locations, expected);
+
+        let present: Vec<bool> = locations.iter().map(Option::is_some).collect();
+        assert_eq!(
+            present,
+            vec![true, false, true, false, true, false, true, false]
+        );
+    });
+}
+
+#[test]
+fn debug_does_not_block() {
+    // formats the store from within a metrics hook, which
+    // `write_batch_once` calls while it holds a lock
+    #[derive(Default)]
+    struct FormattingMetrics {
+        marble: std::sync::Mutex<Option<Marble>>,
+        formatted: std::sync::Mutex<Option<String>>,
+    }
+
+    impl std::fmt::Debug for FormattingMetrics {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "FormattingMetrics")
+        }
+    }
+
+    impl MarbleMetrics for FormattingMetrics {
+        fn on_write_batch(&self, _bytes: u64, _objects: usize) {
+            if let Some(marble) = self.marble.lock().unwrap().take() {
+                *self.formatted.lock().unwrap() = Some(format!("{marble:?}"));
+            }
+        }
+    }
+
+    let metrics = std::sync::Arc::new(FormattingMetrics::default());
+
+    let config = Config {
+        path: test_path(),
+        metrics: Some(metrics.clone()),
+        ..Default::default()
+    };
+
+    with_instance(config, |_config, marble| {
+        marble.write_batch([(0_u64, Some(vec![0_u8; 8]))]).unwrap();
+
+        *metrics.marble.lock().unwrap() = Some(marble.clone());
+        marble
+            .write_batch_once(1, [(1_u64, Some(vec![1_u8; 8]))])
+            .unwrap();
+
+        let formatted = metrics.formatted.lock().unwrap().take().unwrap();
+        assert!(
+            formatted.starts_with("Marble { config: Config {"),
+            "{formatted}"
+        );
+        assert!(formatted.contains("next_file_lsn"), "{formatted}");
+        assert!(formatted.contains("files: 2"), "{formatted}");
+    });
+}
+
+#[test]
+fn compaction_threads() {
+    let compact = |compaction_threads: usize| {
+        let config = Config {
+            path: test_path(),
+            compaction_threads,
+            ..Default::default()
+        };
+
+        let mut result = None;
+
+        with_instance(config, |_config, marble| {
+            for file in 0..64_u64 {
+                marble
+                    .write_batch((0..10).map(|i| (file * 10 + i, Some(vec![i as u8; 100]))))
+                    .unwrap();
+            }
+
+            // leave every file with less than half of its
+            // objects live, in addition to the file holding
+            // the deletions
+            marble
+                .write_batch(
+                    (0..640_u64)
+                        .filter(|i| i % 10 < 6)
+                        .map(|i| (i, None::<Vec<u8>>)),
+                )
+                .unwrap();
+
+            let files_before = marble.stats().files;
+            let report = marble.maintenance().unwrap();
+            let files_after = marble.stats().files;
+
+            for object_id in 0..640_u64 {
+                let expected = (object_id % 10 >= 6).then(|| vec![(object_id % 10) as u8; 100]);
+                assert_eq!(
+                    marble.read(object_id).unwrap().as_deref(),
+                    expected.as_deref()
+                );
+            }
+            assert!(marble.verify().unwrap().is_ok());
+
+            result = Some((
+                report,
+                files_before - files_after,
+                marble.stats().live_objects,
+            ));
+        });
+
+        result.unwrap()
+    };
+
+    let (serial, serial_files_removed, serial_live) = compact(1);
+    let (parallel, parallel_files_removed, parallel_live) = compact(4);
+
+    assert_eq!(serial.files_rewritten, 65);
+    assert_eq!(parallel.files_rewritten, serial.files_rewritten);
+    assert_eq!(parallel.objects_rewritten, serial.objects_rewritten);
+    assert_eq!(parallel.files_removed, serial.files_removed);
+    assert_eq!(parallel_live, serial_live);
+
+    // each thread writes its own files, which only differ in
+    // the size of their trailers
+    assert_eq!(serial_files_removed, 64);
+    assert_eq!(parallel_files_removed, 61);
+    assert!(parallel.bytes_reclaimed.abs_diff(serial.bytes_reclaimed) < 1024);
+}
+
+#[test]
+fn parallel_compaction_merges_small_files() {
+    let config = Config {
+        path: test_path(),
+        compaction_threads: 4,
+        small_file_cleanup_threshold: 0,
+        ..Default::default()
+    };
+
+    with_instance(config, |_config, marble| {
+        for object_id in 0..4_u64 {
+            marble
+                .write_batch([(object_id, Some(vec![object_id as u8; 100]))])
+                .unwrap();
+        }
+        assert_eq!(marble.stats().files, 4);
+
+        // every pass merges the small files into fewer ones,
+        // until a single file is left that is not rewritten
+        let mut files = marble.stats().files;
+        while files > 1 {
+            marble.maintenance().unwrap();
+            assert!(marble.stats().files < files);
+            files = marble.stats().files;
+        }
+        assert_eq!(marble.maintenance().unwrap().files_rewritten, 0);
+
+        for object_id in 0..4_u64 {
+            assert_eq!(
+                &*marble.read(object_id).unwrap().unwrap(),
+                &[object_id as u8; 100]
+            );
+        }
+    });
+}
+
+#[test]
+fn live_percent_counts_objects() {
+    with_default_instance(|config, mut marble| {
+        // one large and nine small objects, and a deletion
+        marble
+            .write_batch(
+                (0..10_u64)
+                    .map(|id| (id, Some(vec![0_u8; if id == 0 { 10_000 } else { 10 }])))
+                    .chain([(10, None)]),
+            )
+            .unwrap();
+
+        // replacing the large object leaves almost none of
+        // the file's bytes live, but most of its objects
+        marble.write_batch([(0_u64, Some(vec![1_u8; 10]))]).unwrap();
+
+        for _ in 0..2 {
+            let file = &marble.file_fragmentation()[0];
+            assert_eq!(file.stored_objects, 11);
+            assert_eq!(file.live_objects, 10);
+            assert_eq!(file.live_percent, 90);
+
+            // and so it is above `Config::file_compaction_percent`
+            assert_eq!(marble.maintenance().unwrap().files_rewritten, 0);
+
+            marble = restart(config, marble);
+        }
+    });
+}
+
+#[test]
+fn first_open() {
+    let file_names = |marble: &Marble| -> Vec<String> {
+        marble
+            .file_fragmentation()
+            .iter()
+            .map(|file| file.path.file_name().unwrap().to_str().unwrap().to_owned())
+            .collect()
+    };
+
+    let mut names = vec![];
+
+    for _ in 0..2 {
+        let config = Config {
+            path: test_path(),
+            ..Default::default()
+        };
+        assert!(!config.path.exists());
+
+        let mut marble = config.open().unwrap();
+        assert_eq!(marble.stats().files, 0);
+        assert!(heap_files(&config.path).is_empty());
+        assert_eq!(marble.read(0).unwrap(), None);
+
+        marble.write_batch([(0_u64, Some(vec![0_u8; 16]))]).unwrap();
+        marble = restart(&config, marble);
+
+        // the first file written after reopening must not
+        // overlap the first file of the new store
+        marble.write_batch([(1_u64, Some(vec![1_u8; 16]))]).unwrap();
+        marble = restart(&config, marble);
+
+        assert_eq!(&*marble.read(0).unwrap().unwrap(), &[0_u8; 16]);
+        assert_eq!(&*marble.read(1).unwrap().unwrap(), &[1_u8; 16]);
+        assert!(marble.verify().unwrap().is_ok());
+
+        names.push(file_names(&marble));
+
+        drop(marble);
+        std::fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    // new stores are laid out deterministically
+    assert_eq!(names[0].len(), 2);
+    assert_eq!(names[0], names[1]);
+}
+
+#[test]
+fn compact_until() {
+    with_default_instance(|_config, marble| {
+        for file in 0..20_u64 {
+            marble
+                .write_batch((0..100).map(|i| (file * 100 + i, Some(vec![1_u8; 64]))))
+                .unwrap();
+        }
+
+        // overwrite 80% of the objects of every file
+        marble
+            .write_batch(
+                (0..2000_u64)
+                    .filter(|i| i % 10 < 8)
+                    .map(|i| (i, Some(vec![2_u8; 64]))),
+            )
+            .unwrap();
+        assert!(marble.space_amplification() > 1.5);
+
+        let report = marble.compact_until(1.5).unwrap();
+        assert_eq!(report.files_rewritten, 20);
+        assert!(marble.space_amplification() <= 1.5);
+
+        // already at the target
+        assert_eq!(
+            marble.compact_until(1.5).unwrap(),
+            MaintenanceReport::default()
+        );
+
+        for object_id in 0..2000_u64 {
+            let expected = if object_id % 10 < 8 { 2 } else { 1 };
+            assert_eq!(&*marble.read(object_id).unwrap().unwrap(), &[expected; 64]);
+        }
+
+        // leave the only file with objects 70% live, which
+        // is above `Config::file_compaction_percent`, so that
+        // maintenance makes no progress towards the target
+        marble
+            .write_batch((0..2000_u64).map(|i| (i, Some(vec![3_u8; 64]))))
+            .unwrap();
+        marble
+            .write_batch(
+                (0..2000_u64)
+                    .filter(|i| i % 10 < 3)
+                    .map(|i| (i, None::<Vec<u8>>)),
+            )
+            .unwrap();
+        marble.maintenance().unwrap();
+        let before = marble.space_amplification();
+        assert!(before > 1.1);
+
+        let report = marble.compact_until(1.1).unwrap();
+        assert_eq!(report.files_rewritten, 0);
+        assert_eq!(marble.space_amplification(), before);
+    });
+}
+
+#[test]
+fn maintenance_budgeted() {
+    // each storage file written below is about 10kb, so that
+    // rewritten groups hold a single file
+    let config = Config {
+        path: test_path(),
+        target_file_size: 15_000,
+        ..Default::default()
+    };
+
+    with_instance(config, |_config, marble| {
+        let write_fragmented_files = |first_object_id: u64| {
+            for file in 0..20_u64 {
+                marble
+                    .write_batch(
+                        (0..100).map(|i| (first_object_id + file * 100 + i, Some(vec![1_u8; 64]))),
+                    )
+                    .unwrap();
+            }
+
+            // overwrite 80% of the objects of every file
+            marble
+                .write_batch(
+                    (first_object_id..first_object_id + 2000)
+                        .filter(|i| i % 10 < 8)
+                        .map(|i| (i, Some(vec![2_u8; 64]))),
+                )
+                .unwrap();
+        };
+        let fragmented = || -> Vec<FileFragmentation> {
+            marble
+                .file_fragmentation()
+                .into_iter()
+                .filter(|file| file.live_objects > 0 && file.live_percent < 66)
+                .collect()
+        };
+
+        write_fragmented_files(0);
+        assert_eq!(fragmented().len(), 20);
+        let file_size = fragmented()[0].file_size;
+
+        // only part of the dead space is reclaimed per call
+        let mut space_amplification = marble.space_amplification();
+        let mut calls = 0;
+        while !fragmented().is_empty() {
+            let report = marble
+                .maintenance_budgeted(3 * file_size, std::time::Duration::MAX)
+                .unwrap();
+            assert!(report.files_rewritten > 0);
+            assert!(report.files_rewritten <= 3);
+            assert!(marble.space_amplification() < space_amplification);
+            space_amplification = marble.space_amplification();
+            calls += 1;
+        }
+        assert_eq!(calls, 7);
+
+        for object_id in 0..2000_u64 {
+            let expected = if object_id % 10 < 8 { 2 } else { 1 };
+            assert_eq!(&*marble.read(object_id).unwrap().unwrap(), &[expected; 64]);
+        }
+
+        // a call without any time left still rewrites one
+        // group of files
+        write_fragmented_files(2000);
+        assert_eq!(fragmented().len(), 20);
+        let report = marble
+            .maintenance_budgeted(u64::MAX, std::time::Duration::ZERO)
+            .unwrap();
+        assert_eq!(report.files_rewritten, 1);
+        assert_eq!(fragmented().len(), 19);
+    });
+}
+
+#[test]
+fn delete_range() {
+    with_default_instance(|config, mut marble| {
+        let object_ids = [1_u64, 5, 9, 10, 17, 30, 31, 100, 1000];
+        marble
+            .write_batch(object_ids.map(|object_id| (object_id, Some(vec![object_id as u8; 8]))))
+            .unwrap();
+        marble.write_batch([(17_u64, None::<Vec<u8>>)]).unwrap();
+        assert!(marble
+            .write_batch_once(7, [(2_u64, Some(vec![2_u8; 8]))])
+            .unwrap());
+
+        // 17 was already deleted, and is not counted
+        assert_eq!(marble.delete_range(5, 31).unwrap(), 4);
+        assert_eq!(marble.delete_range(5, 31).unwrap(), 0);
+        assert_eq!(marble.delete_range(2000, u64::MAX).unwrap(), 0);
+
+        marble = restart(config, marble);
+
+        let remaining: Vec<u64> = marble.range(0, u64::MAX).collect();
+        assert_eq!(remaining, vec![1, 2, 31, 100, 1000]);
+
+        // the ID of the last batch is kept
+        assert!(!marble
+            .write_batch_once(7, [(3_u64, Some(vec![3_u8; 8]))])
+            .unwrap());
+        assert_eq!(marble.delete_range(0, u64::MAX).unwrap(), 5);
+        assert!(!marble
+            .write_batch_once(7, [(3_u64, Some(vec![3_u8; 8]))])
+            .unwrap());
+        assert!(marble.is_empty());
+    });
+}
+
+#[test]
+fn write_stats() {
+    with_default_instance(|_config, marble| {
+        let stats = marble.stats();
+        assert_eq!(stats.objects_written, 0);
+        assert_eq!(stats.write_batches, 0);
+        assert_eq!(stats.last_write_batch_nanos, 0);
+
+        let mut previous = stats;
+
+        for batch in 1..=5_u64 {
+            marble
+                .write_batch((0..batch * 10).map(|i| (i, Some(vec![1_u8; 100]))))
+                .unwrap();
+
+            let stats = marble.stats();
+            assert_eq!(stats.write_batches, batch);
+            assert_eq!(stats.objects_written, previous.objects_written + batch * 10);
+            assert_eq!(
+                stats.high_level_user_bytes_written,
+                previous.high_level_user_bytes_written + batch * 1000
+            );
+            assert!(stats.last_write_batch_nanos > 0);
+            assert!(stats.last_write_batch_nanos < 60_000_000_000);
+
+            previous = stats;
+        }
+
+        // rewrites by maintenance are not counted
+        marble.maintenance().unwrap();
+        let stats = marble.stats();
+        assert_eq!(stats.write_batches, 5);
+        assert_eq!(stats.objects_written, 150);
+    });
+}
+
+#[test]
+fn ref_counts() {
+    with_default_instance(|config, mut marble| {
+        marble
+            .write_batch([(1_u64, Some(vec![1_u8; 8])), (2, Some(vec![2_u8; 8]))])
+            .unwrap();
+
+        assert_eq!(marble.ref_count(1).unwrap(), 1);
+        assert_eq!(marble.increment_ref(1).unwrap(), 2);
+
+        marble = restart(config, marble);
+        assert_eq!(marble.ref_count(1).unwrap(), 2);
+
+        assert_eq!(marble.decrement_ref(1).unwrap(), 1);
+        assert_eq!(&*marble.read(1).unwrap().unwrap(), &[1_u8; 8]);
+
+        marble = restart(config, marble);
+        assert_eq!(marble.ref_count(1).unwrap(), 1);
+
+        assert_eq!(marble.decrement_ref(1).unwrap(), 0);
+        assert_eq!(marble.read(1).unwrap(), None);
+        assert_eq!(marble.ref_count(1).unwrap(), 0);
+        assert!(matches!(
+            marble.decrement_ref(1),
+            Err(MarbleError::NotFound(_))
+        ));
+        assert!(matches!(
+            marble.increment_ref(3),
+            Err(MarbleError::NotFound(_))
+        ));
+
+        // deleting an object discards its reference count, so
+        // an object written again with its ID starts over
+        assert_eq!(marble.increment_ref(2).unwrap(), 2);
+        marble.write_batch([(2_u64, None::<Vec<u8>>)]).unwrap();
+        marble.write_batch([(2_u64, Some(vec![3_u8; 8]))]).unwrap();
+        assert_eq!(marble.ref_count(2).unwrap(), 1);
+
+        marble = restart(config, marble);
+        assert_eq!(marble.ref_count(2).unwrap(), 1);
+        assert_eq!(marble.decrement_ref(2).unwrap(), 0);
+        assert!(marble.is_empty());
+    });
+}
+
+#[test]
+fn deletions_are_written_despite_corrupt_ref_counts() {
+    with_default_instance(|config, mut marble| {
+        marble.write_batch([(1_u64, Some(vec![1_u8; 8]))]).unwrap();
+        assert_eq!(marble.increment_ref(1).unwrap(), 2);
+
+        // reference counts are loaded again after a restart,
+        // once the deletion tries to discard them
+        marble = restart(config, marble);
+        std::fs::write(config.path.join("object_ref_counts"), b"garbage").unwrap();
+
+        marble.write_batch([(1_u64, None::<Vec<u8>>)]).unwrap();
+        assert_eq!(marble.read(1).unwrap(), None);
+
+        marble = restart(config, marble);
+        assert_eq!(marble.read(1).unwrap(), None);
+    });
+}
+
+#[test]
+fn ref_counts_survive_deletion_overwritten_in_batch() {
+    with_default_instance(|config, mut marble| {
+        marble.write_batch([(1_u64, Some(vec![1_u8; 8]))]).unwrap();
+        assert_eq!(marble.increment_ref(1).unwrap(), 2);
+
+        // only the last value of an object in a batch counts,
+        // so the object is not deleted and keeps its count
+        marble
+            .write_batch([(1_u64, None), (1, Some(vec![2_u8; 8]))])
+            .unwrap();
+        assert_eq!(&*marble.read(1).unwrap().unwrap(), &[2_u8; 8]);
+        assert_eq!(marble.ref_count(1).unwrap(), 2);
+
+        marble = restart(config, marble);
+        assert_eq!(marble.ref_count(1).unwrap(), 2);
+        assert_eq!(marble.decrement_ref(1).unwrap(), 1);
+        assert_eq!(&*marble.read(1).unwrap().unwrap(), &[2_u8; 8]);
+    });
+}
+
+#[test]
+fn scan_file() {
+    with_default_instance(|_config, marble| {
+        marble
+            .write_batch((0..5_u64).map(|i| (i, Some(vec![i as u8; 8]))))
+            .unwrap();
+        marble
+            .write_batch([(2_u64, Some(vec![9_u8; 8])), (3, None)])
+            .unwrap();
+
+        let files = marble.file_fragmentation();
+
+        // overwritten and deleted objects are still scanned
+        let records: Vec<(u64, Box<[u8]>)> = marble
+            .scan_file(files[0].location)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let expected: Vec<(u64, Box<[u8]>)> = (0..5_u64)
+            .map(|i| (i, vec![i as u8; 8].into_boxed_slice()))
+            .collect();
+        assert_eq!(records, expected);
+
+        // deletions are not records
+        let records: Vec<(u64, Box<[u8]>)> = marble
+            .scan_file(files[1].location)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(records, vec![(2, vec![9_u8; 8].into_boxed_slice())]);
+
+        // not the location of a file
+        let object_location = marble.location(1).unwrap();
+        assert!(matches!(
+            marble.scan_file(object_location),
+            Err(MarbleError::NotFound(_))
+        ));
+
+        // make a record in the middle of the first file
+        // extend past the end of its object data
+        let record_len = 20 + 8;
+        let len_offset = 2 * record_len + 12;
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&files[0].path)
+            .unwrap();
+        std::os::unix::fs::FileExt::write_at(&file, &(1_u64 << 20).to_le_bytes(), len_offset)
+            .unwrap();
+
+        let results: Vec<Result<(u64, Box<[u8]>), MarbleError>> =
+            marble.scan_file(files[0].location).unwrap().collect();
+        assert_eq!(results.len(), 3);
+        assert!(results[..2].iter().all(Result::is_ok));
+        assert!(matches!(results[2], Err(MarbleError::Corruption(_))));
+    });
+}
+
+#[test]
+fn prefetch() {
+    for zstd_compression_level in [None, Some(3)] {
+        let config = Config {
+            path: test_path(),
+            zstd_compression_level,
+            ..Default::default()
+        };
+
+        with_instance(config, |_config, marble| {
+            for object_id in 0..16_u64 {
+                marble
+                    .write_batch([(object_id, Some(vec![object_id as u8; 4096]))])
+                    .unwrap();
+            }
+            marble.delete_batch(&[3]).unwrap();
+
+            // unknown, removed and repeated objects are ignored
+            let object_ids: Vec<u64> = (0..20).chain([0, 5, u64::MAX]).collect();
+            marble.prefetch(&object_ids).unwrap();
+            marble.prefetch(&[]).unwrap();
+
+            for object_id in 0..20_u64 {
+                let read = marble.read(object_id).unwrap();
+                if object_id == 3 || object_id >= 16 {
+                    assert!(read.is_none());
+                } else {
+                    assert_eq!(&*read.unwrap(), &[object_id as u8; 4096][..]);
+                }
+            }
+        });
+    }
+}
+
+#[test]
+fn object_len() {
+    for zstd_compression_level in [None, Some(3)] {
+        let config = Config {
+            path: test_path(),
+            zstd_compression_level,
+            ..Default::default()
+        };
+
+        with_instance(config, |config, mut marble| {
+            marble
+                .write_batch([
+                    (0_u64, Some(vec![1_u8; 1000])),
+                    (1, Some(vec![])),
+                    (2, Some(vec![2_u8; 10])),
+                ])
+                .unwrap();
+            marble
+                .write_batch_with_metadata([(3_u64, Some((*b"typetag1", vec![3_u8; 100])))])
+                .unwrap();
+            marble.delete_batch(&[2]).unwrap();
+
+            let check = |marble: &Marble| {
+                assert_eq!(marble.object_len(0).unwrap(), Some(1000));
+                assert_eq!(marble.object_len(1).unwrap(), Some(0));
+                assert_eq!(marble.object_len(2).unwrap(), None);
+                assert_eq!(marble.object_len(3).unwrap(), Some(100));
+                assert_eq!(marble.object_len(4).unwrap(), None);
+                assert_eq!(marble.object_len(u64::MAX).unwrap(), None);
+            };
+
+            check(&marble);
+            marble = restart(config, marble);
+            check(&marble);
+        });
+    }
+
+    // the object ID in the header is checked
+    with_default_instance(|_config, marble| {
+        marble
+            .write_batch([(0_u64, Some(vec![1_u8; 8])), (1, Some(vec![2_u8; 8]))])
+            .unwrap();
+
+        let (path, offset) = marble.locate(0).unwrap();
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[usize::try_from(offset).unwrap() + 4] ^= 1;
+        std::fs::write(&path, &contents).unwrap();
+
+        assert!(matches!(
+            marble.object_len(0),
+            Err(MarbleError::Corruption(_))
+        ));
+        assert_eq!(marble.object_len(1).unwrap(), Some(8));
+    });
+}
@@ -26,7 +26,7 @@ fn run(marble: Marble) {
         marble.write_batch(batch).unwrap();
 
         if i % 16 == 0 {
-            let cleaned_up = marble.maintenance().unwrap();
+            let cleaned_up = marble.maintenance().unwrap().objects_rewritten;
             if cleaned_up != 0 {
                 log::info!("defragmented {} objects", cleaned_up);
             }
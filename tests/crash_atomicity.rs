@@ -6,7 +6,7 @@ use std::time::Duration;
 
 use rand::Rng;
 
-use marble::{Config, Marble};
+use marble::{Config, Marble, MAX_OBJECT_ID};
 
 mod common;
 
@@ -119,7 +119,7 @@ fn write_batches_inner(start: u32, m: Marble) {
         };
 
         let mut batch = vec![];
-        for key in (0..BATCH_SIZE as u64).chain(once(u64::MAX)) {
+        for key in (0..BATCH_SIZE as u64).chain(once(MAX_OBJECT_ID)) {
             batch.push((key, value.clone()));
         }
         m.write_batch(batch).unwrap();
@@ -130,7 +130,7 @@ fn write_batches_inner(start: u32, m: Marble) {
 /// recovered (i.e., equal). Panics if they don't match up.
 fn verify_batches(m: &Marble) {
     let values: Vec<Option<Box<[u8]>>> = (0..BATCH_SIZE as u64)
-        .chain(once(u64::MAX))
+        .chain(once(MAX_OBJECT_ID))
         .map(|i| {
             let object_id = i;
             m.read(object_id).unwrap()
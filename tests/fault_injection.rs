@@ -0,0 +1,404 @@
+use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+use std::sync::Mutex;
+
+use fault_injection::FAULT_INJECT_COUNTER;
+use marble::*;
+
+mod common;
+
+const TEST_DIR: &str = "testing_data_directories";
+
+static TEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// `FAULT_INJECT_COUNTER` is global, so tests in this file
+// must not run concurrently with each other.
+static FAULT_LOCK: Mutex<()> = Mutex::new(());
+
+fn with_instance<F: FnOnce(&Config, Marble)>(config: Config, f: F) {
+    let _guard = FAULT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let _ = common::setup_logger();
+
+    let _ = std::fs::remove_dir_all(&config.path);
+
+    let marble = config.open().unwrap();
+
+    f(&config, marble);
+
+    FAULT_INJECT_COUNTER.store(u64::MAX, SeqCst);
+
+    std::fs::remove_dir_all(&config.path).unwrap();
+}
+
+fn test_config() -> Config {
+    let subdir = format!("fault_injection_{}", TEST_COUNTER.fetch_add(1, SeqCst));
+
+    Config {
+        path: std::path::Path::new(TEST_DIR).join(subdir),
+        fsync_each_batch: true,
+        // injected faults are not retried, so that each one
+        // fails the operation that hits it
+        io_retries: 0,
+        ..Default::default()
+    }
+}
+
+/// Runs `f` with a fault injected at the `n`th fallible IO
+/// operation.
+fn inject_fault<T, F: FnOnce() -> T>(n: u64, f: F) -> T {
+    FAULT_INJECT_COUNTER.store(n, SeqCst);
+    let ret = f();
+    FAULT_INJECT_COUNTER.store(u64::MAX, SeqCst);
+    ret
+}
+
+/// Simulates a crash by dropping the instance and recovering
+/// from whatever made it to disk.
+fn crash(config: &Config, marble: Marble) -> Marble {
+    drop(marble);
+    config.open().unwrap()
+}
+
+/// Returns the paths of the completed storage files of the
+/// store at `path`, sorted by name.
+fn heap_files(path: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut files: Vec<_> = std::fs::read_dir(path.join("heap"))
+        .unwrap()
+        .map(|entry_res| entry_res.unwrap().path())
+        .filter(|path| path.is_dir())
+        .flat_map(|shard_dir| std::fs::read_dir(shard_dir).unwrap())
+        .map(|entry_res| entry_res.unwrap().path())
+        .filter(|path| !path.to_str().unwrap().ends_with("tmp"))
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn crash_during_write_batch() {
+    let old: &[u8] = &[1; 16];
+    let new: &[u8] = &[2; 16];
+
+    with_instance(test_config(), |config, mut marble| {
+        marble.write_batch([(0_u64, Some(old))]).unwrap();
+
+        for n in 1.. {
+            let res = inject_fault(n, || marble.write_batch([(0_u64, Some(new))]));
+
+            marble = crash(config, marble);
+
+            let read = marble.read(0).unwrap().unwrap();
+
+            if res.is_ok() {
+                // once the write completes without hitting the
+                // injected fault, its rename must be durable.
+                assert_eq!(&*read, new);
+                break;
+            }
+
+            assert!(&*read == old || &*read == new);
+
+            marble.write_batch([(0_u64, Some(old))]).unwrap();
+        }
+    });
+}
+
+#[test]
+fn crash_during_write_batch_is_all_or_nothing() {
+    let old: &[u8] = &[1; 16];
+    let new: &[u8] = &[2; 16];
+    let object_ids = 0_u64..8;
+
+    with_instance(test_config(), |config, mut marble| {
+        marble
+            .write_batch(object_ids.clone().map(|object_id| (object_id, Some(old))))
+            .unwrap();
+
+        for n in 1.. {
+            let res = inject_fault(n, || {
+                marble.write_batch(object_ids.clone().map(|object_id| (object_id, Some(new))))
+            });
+
+            marble = crash(config, marble);
+
+            let reads: Vec<_> = object_ids
+                .clone()
+                .map(|object_id| marble.read(object_id).unwrap().unwrap())
+                .collect();
+
+            // recovery either sees every location written by
+            // the batch or none of them
+            let expected = if &*reads[0] == new { new } else { old };
+            assert!(reads.iter().all(|read| &**read == expected));
+
+            if res.is_ok() {
+                assert_eq!(expected, new);
+                break;
+            }
+
+            marble
+                .write_batch(object_ids.clone().map(|object_id| (object_id, Some(old))))
+                .unwrap();
+        }
+    });
+}
+
+#[test]
+fn failed_writes_poison_the_store() {
+    let is_poisoned = |res: Result<(), MarbleError>| matches!(res, Err(MarbleError::Io(ref e)) if e.to_string().contains("poisoned"));
+
+    with_instance(test_config(), |config, mut marble| {
+        marble.write_batch([(0_u64, Some(vec![1_u8]))]).unwrap();
+
+        let mut poisoned = 0;
+        for n in 1.. {
+            // each injected fault stands in for an error like
+            // running out of disk space
+            let res = inject_fault(n, || marble.write_batch([(0_u64, Some(vec![2_u8]))]));
+            if res.is_ok() {
+                break;
+            }
+
+            let retry = marble.write_batch([(0_u64, Some(vec![3_u8]))]);
+            if is_poisoned(retry) {
+                poisoned += 1;
+
+                assert!(is_poisoned(marble.maintenance().map(drop)));
+                assert!(marble.read(0).unwrap().is_some());
+
+                // reopening recovers from whatever made it to
+                // disk, and accepts writes again
+                marble = crash(config, marble);
+                marble.write_batch([(0_u64, Some(vec![3_u8]))]).unwrap();
+            }
+            assert_eq!(&*marble.read(0).unwrap().unwrap(), &[3_u8]);
+        }
+
+        assert!(poisoned > 0);
+    });
+}
+
+#[test]
+fn write_batch_once_after_crash() {
+    let old: &[u8] = &[1; 16];
+    let new: &[u8] = &[2; 16];
+
+    with_instance(test_config(), |config, mut marble| {
+        assert!(marble.write_batch_once(1, [(0_u64, Some(old))]).unwrap());
+
+        for n in 1.. {
+            let batch_id = n + 1;
+            let res = inject_fault(n, || {
+                marble.write_batch_once(batch_id, [(0_u64, Some(new))])
+            });
+
+            marble = crash(config, marble);
+
+            // the batch is written again by the retry exactly
+            // when the crashed attempt was lost
+            let landed = &*marble.read(0).unwrap().unwrap() == new;
+            let retried = marble
+                .write_batch_once(batch_id, [(0_u64, Some(new))])
+                .unwrap();
+            assert_eq!(landed, !retried);
+
+            if res.is_ok() {
+                assert!(landed);
+                break;
+            }
+
+            marble.write_batch([(0_u64, Some(old))]).unwrap();
+        }
+    });
+}
+
+#[test]
+fn write_batch_once_after_error() {
+    let old: &[u8] = &[1; 16];
+    let new: &[u8] = &[2; 16];
+
+    with_instance(test_config(), |config, mut marble| {
+        assert!(marble.write_batch_once(1, [(0_u64, Some(old))]).unwrap());
+
+        for n in 1.. {
+            let batch_id = n + 1;
+            let res = inject_fault(n, || {
+                marble.write_batch_once(batch_id, [(0_u64, Some(new))])
+            });
+
+            // an error may be returned after the batch was
+            // written, in which case retrying it without a
+            // crash must skip it
+            let landed = &*marble.read(0).unwrap().unwrap() == new;
+            match marble.write_batch_once(batch_id, [(0_u64, Some(new))]) {
+                Ok(retried) => assert_eq!(landed, !retried),
+                // the error poisoned the store, which is
+                // covered by `write_batch_once_after_crash`
+                Err(_) => marble = crash(config, marble),
+            }
+
+            if res.is_ok() {
+                assert!(landed);
+                break;
+            }
+
+            marble.write_batch([(0_u64, Some(old))]).unwrap();
+        }
+    });
+}
+
+#[test]
+fn crash_during_bulk_load() {
+    let old: &[u8] = &[1; 1024];
+    let new: &[u8] = &[2; 1024];
+    let object_ids = 0_u64..16;
+
+    let config = Config {
+        target_file_size: 4096,
+        ..test_config()
+    };
+
+    with_instance(config, |config, mut marble| {
+        marble
+            .write_batch(object_ids.clone().map(|object_id| (object_id, Some(old))))
+            .unwrap();
+
+        for n in 1.. {
+            let res = inject_fault(n, || {
+                marble.bulk_load(object_ids.clone().map(|object_id| (object_id, new)))
+            });
+
+            marble = crash(config, marble);
+
+            // some of the objects may be lost, but the store is
+            // never corrupted
+            assert!(marble.verify().unwrap().is_ok());
+            for object_id in object_ids.clone() {
+                let read = marble.read(object_id).unwrap().unwrap();
+                if res.is_ok() {
+                    assert_eq!(&*read, new);
+                } else {
+                    assert!(&*read == old || &*read == new);
+                }
+            }
+
+            if res.is_ok() {
+                break;
+            }
+
+            marble
+                .write_batch(object_ids.clone().map(|object_id| (object_id, Some(old))))
+                .unwrap();
+        }
+    });
+}
+
+#[test]
+fn transient_errors_are_retried() {
+    // reads whose retried attempts hit an injected fault, and
+    // the kinds of the returned errors
+    let read_with_faults = |marble: &Marble| {
+        let mut retried = 0;
+        let mut kinds = vec![];
+        for n in 1..=8 {
+            let res = inject_fault(n, || {
+                let res = marble.read(0);
+                (res, FAULT_INJECT_COUNTER.load(SeqCst) > n)
+            });
+            match res {
+                (Ok(read), hit_fault) => {
+                    assert_eq!(&*read.unwrap(), &[1_u8; 64]);
+                    if hit_fault {
+                        retried += 1;
+                    }
+                }
+                (Err(MarbleError::Io(e)), _) => kinds.push(e.kind()),
+                (Err(e), _) => panic!("unexpected error {e:?}"),
+            }
+        }
+        (retried, kinds)
+    };
+
+    let config = Config {
+        io_retries: 3,
+        ..test_config()
+    };
+
+    with_instance(config, |_config, marble| {
+        marble.write_batch([(0_u64, Some(vec![1_u8; 64]))]).unwrap();
+
+        // a fault injected into an attempt fails it with
+        // `Interrupted`, and the attempt is repeated
+        let (retried, kinds) = read_with_faults(&marble);
+        assert!(retried > 0);
+        assert!(!kinds.contains(&std::io::ErrorKind::Interrupted));
+    });
+
+    // without retries, the transient error is returned
+    with_instance(test_config(), |_config, marble| {
+        marble.write_batch([(0_u64, Some(vec![1_u8; 64]))]).unwrap();
+
+        let (retried, kinds) = read_with_faults(&marble);
+        assert_eq!(retried, 0);
+        assert!(kinds.contains(&std::io::ErrorKind::Interrupted));
+        assert_eq!(&*marble.read(0).unwrap().unwrap(), &[1_u8; 64]);
+    });
+}
+
+#[test]
+fn rewrite_after_crash_following_rename() {
+    let batch = |byte: u8| (0_u64..8).map(move |object_id| (object_id, Some(vec![byte; 16])));
+
+    with_instance(test_config(), |config, mut marble| {
+        // find a fault that fails the write after its file was
+        // already installed at its final path
+        let mut path = None;
+        for n in 1.. {
+            let res = inject_fault(n, || marble.write_batch(batch(1)));
+            assert!(res.is_err());
+
+            marble = crash(config, marble);
+            if let [file] = &*heap_files(&config.path) {
+                path = Some(file.clone());
+                break;
+            }
+        }
+        let path = path.unwrap();
+
+        // the installed file is recovered cleanly
+        assert!(marble.verify().unwrap().is_ok());
+        assert_eq!(&*marble.read(7).unwrap().unwrap(), &[1_u8; 16]);
+
+        // if recovery does not see the file, the same batch is
+        // written to the same path again, which is only
+        // noticed once the file reappears
+        let aside = config.path.join("aside");
+        let hide = |marble: Marble| {
+            drop(marble);
+            std::fs::rename(&path, &aside).unwrap();
+            let marble = config.open().unwrap();
+            std::fs::rename(&aside, &path).unwrap();
+            marble
+        };
+
+        marble = hide(marble);
+        marble.write_batch(batch(1)).unwrap();
+        assert_eq!(heap_files(&config.path), vec![path.clone()]);
+        marble = crash(config, marble);
+        assert!(marble.verify().unwrap().is_ok());
+        assert_eq!(&*marble.read(7).unwrap().unwrap(), &[1_u8; 16]);
+
+        // a different file is never replaced
+        marble = hide(marble);
+        let res = marble.write_batch(batch(2));
+        assert!(
+            matches!(res, Err(MarbleError::Io(ref e)) if e.kind() == std::io::ErrorKind::AlreadyExists)
+        );
+        marble = crash(config, marble);
+        assert_eq!(heap_files(&config.path), vec![path.clone()]);
+        assert_eq!(&*marble.read(7).unwrap().unwrap(), &[1_u8; 16]);
+
+        marble.write_batch(batch(2)).unwrap();
+        assert_eq!(&*marble.read(7).unwrap().unwrap(), &[2_u8; 16]);
+    });
+}
@@ -0,0 +1,94 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ret = System.alloc(layout);
+        if !ret.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), SeqCst) + layout.size();
+            PEAK.fetch_max(allocated, SeqCst);
+        }
+        ret
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+const FILES: u64 = 256;
+const OBJECTS_PER_FILE: u64 = 1024;
+// an object ID and its relative location
+const TRAILER_ITEM_SIZE: usize = 16;
+
+#[test]
+fn recovery_streams_trailers() {
+    let path = std::path::Path::new("testing_data_directories").join("recovery_memory");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let config = marble::Config {
+        path: path.clone(),
+        ..Default::default()
+    };
+
+    let marble = config.open().unwrap();
+
+    // every batch overwrites the same objects, along with
+    // one object of its own that keeps its file live, so
+    // that the trailers of every file must be recovered
+    // even though few objects are stored
+    for file in 0..FILES {
+        let batch = (0..OBJECTS_PER_FILE)
+            .chain([OBJECTS_PER_FILE + file])
+            .map(|object_id| (object_id, Some(file.to_le_bytes())));
+        marble.write_batch(batch).unwrap();
+    }
+
+    let objects = OBJECTS_PER_FILE + FILES;
+    assert_eq!(marble.len(), objects as usize);
+
+    drop(marble);
+
+    let before = ALLOCATED.load(SeqCst);
+    PEAK.store(before, SeqCst);
+
+    let marble = config.open().unwrap();
+
+    // memory that is still held after recovery, like the
+    // location table, is needed regardless of how recovery
+    // reads trailers
+    let retained = ALLOCATED.load(SeqCst) - before;
+    let transient = PEAK.load(SeqCst) - before - retained;
+    let trailer_bytes = (FILES * (OBJECTS_PER_FILE + 1)) as usize * TRAILER_ITEM_SIZE;
+
+    assert!(
+        transient < trailer_bytes / 4,
+        "recovering {trailer_bytes} bytes of trailers temporarily allocated up to {transient} \
+         bytes"
+    );
+
+    assert_eq!(marble.len(), objects as usize);
+    assert_eq!(marble.stats().live_objects, objects);
+    assert_eq!(
+        &*marble.read(0).unwrap().unwrap(),
+        &(FILES - 1).to_le_bytes()
+    );
+    for file in 0..FILES {
+        assert_eq!(
+            &*marble.read(OBJECTS_PER_FILE + file).unwrap().unwrap(),
+            &file.to_le_bytes()
+        );
+    }
+
+    drop(marble);
+    std::fs::remove_dir_all(&path).unwrap();
+}
@@ -0,0 +1,68 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ret = System.alloc(layout);
+        if !ret.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), SeqCst) + layout.size();
+            PEAK.fetch_max(allocated, SeqCst);
+        }
+        ret
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+const OBJECT_SIZE: usize = 4096;
+const OBJECTS: u64 = 16 * 1024;
+const WRITE_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[test]
+fn large_batches_are_streamed_to_disk() {
+    let path = std::path::Path::new("testing_data_directories").join("write_memory");
+    let _ = std::fs::remove_dir_all(&path);
+
+    let config = marble::Config {
+        path: path.clone(),
+        write_buffer_size: WRITE_BUFFER_SIZE,
+        ..Default::default()
+    };
+
+    let marble = config.open().unwrap();
+
+    // every object borrows the same buffer, so the batch
+    // itself only takes memory for its keys and references
+    let object = vec![7_u8; OBJECT_SIZE];
+    let batch = (0..OBJECTS).map(|object_id| (object_id, Some(&object[..])));
+
+    let before = ALLOCATED.load(SeqCst);
+    PEAK.store(before, SeqCst);
+
+    marble.write_batch(batch).unwrap();
+
+    let peak = PEAK.load(SeqCst) - before;
+    let batch_bytes = OBJECTS as usize * OBJECT_SIZE;
+
+    assert!(
+        peak < batch_bytes / 8,
+        "writing a batch of {batch_bytes} bytes allocated up to {peak} bytes"
+    );
+
+    assert!(marble.stats().total_file_size as usize > batch_bytes);
+    assert_eq!(&*marble.read(OBJECTS - 1).unwrap().unwrap(), &*object);
+
+    drop(marble);
+    std::fs::remove_dir_all(&path).unwrap();
+}